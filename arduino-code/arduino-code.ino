@@ -55,6 +55,30 @@ const uint8_t TERMINATE_ON_VECTOR = 0x10;
 const uint8_t TERMINATE_ON_BAD_WRITE = 0x20;
 const uint8_t TERMINATE_ON_UNUSED_FLAGS = 0xC0;
 
+// Reported in response to a capabilities query. Bump FIRMWARE_VERSION
+// whenever the firmware changes, and PROTOCOL_VERSION whenever CONTROL.md
+// does. (Firmware version 1 predates the capabilities query.)
+const uint16_t FIRMWARE_VERSION = 3;
+const uint8_t PROTOCOL_VERSION = 1;
+const uint32_t FEATURE_CYCLE_REPORTS = 0x0001;
+const uint32_t FEATURE_SERIAL_IN = 0x0002;
+const uint32_t FEATURE_SERIAL_OUT = 0x0004;
+const uint32_t FEATURE_NMI = 0x0008;
+const uint32_t FEATURE_IRQ = 0x0010;
+const uint32_t FEATURE_RDY = 0x0020;
+const uint32_t FEATURE_SO = 0x0040;
+const uint32_t FEATURE_RES = 0x0080;
+const uint32_t FEATURE_BAUD_RATE = 0x0100;
+const uint32_t FEATURES = FEATURE_CYCLE_REPORTS | FEATURE_SERIAL_IN
+  | FEATURE_SERIAL_OUT | FEATURE_NMI | FEATURE_IRQ | FEATURE_RDY | FEATURE_SO
+  | FEATURE_RES | FEATURE_BAUD_RATE;
+
+const uint32_t DEFAULT_BAUD_RATE = 115200;
+const uint32_t MIN_BAUD_RATE = 9600;
+const uint32_t MAX_BAUD_RATE = 2000000;
+// How long to wait for the host to confirm a new baud rate
+const int BAUD_CONFIRM_TIMEOUT = 500;
+
 uint8_t sram[65536];
 struct Range {
   uint16_t beg, end;
@@ -76,11 +100,13 @@ const int SERIAL_BUF_SIZE = 32;
 uint8_t serial_in_buf[SERIAL_BUF_SIZE];
 // serial_in_total gets set to EOF if there is no more
 int serial_in_consumed = 0, serial_in_total = 0;
+const int SERIAL_OUT_LIMIT = 131072;
 uint8_t serial_out_buf[SERIAL_BUF_SIZE];
-int serial_out_size = 0, serial_out_rem = 131072;
+int serial_out_size = 0, serial_out_rem = SERIAL_OUT_LIMIT;
 bool serial_in_enabled = false, serial_out_enabled = false, terminated = false,
   last_pc_valid = false, vector_has_been_pulled = false,
-  clear_so_next_cycle = false;
+  clear_so_next_cycle = false, capabilities_requested = false;
+uint32_t pending_baud_rate = 0;
 uint32_t max_cycles_to_report = 0, max_cycles = 10000000;
 uint8_t terminate_on = uint8_t(~TERMINATE_ON_UNUSED_FLAGS);
 uint8_t termination_cause = 0;
@@ -121,7 +147,7 @@ void setup() {
   pinMode(13, OUTPUT);
   digitalWrite(13, LOW);
   // 115200 baud, 8 data bits, 1 stop bit, no parity.
-  Serial.begin(115200, SERIAL_8N1);
+  Serial.begin(DEFAULT_BAUD_RATE, SERIAL_8N1);
   // Send the wakeup sequence
   Serial.write((const uint8_t[]){0,0,4,0,0,5,0,0,6}, 9);
   // Initialize the SRAM
@@ -225,6 +251,21 @@ bool start_state(uint8_t packet_type, size_t length, const uint8_t* data) {
     write_addr = (data[0] << 8U) | data[1];
     return false;
   }
+  case 0x0A: {
+    // query capabilities
+    if(length != 0) shutdown();
+    capabilities_requested = true;
+    return true;
+  }
+  case 0x0B: {
+    // change baud rate (after the ACK)
+    if(length != 4) shutdown();
+    pending_baud_rate = (ptr[0] << 24U) | (ptr[1] << 16U)
+      | (ptr[2] << 8U) | ptr[3];
+    if(pending_baud_rate < MIN_BAUD_RATE || pending_baud_rate > MAX_BAUD_RATE)
+      shutdown();
+    return false;
+  }
   case 0xFE: {
     // Go!
     return true;
@@ -233,6 +274,63 @@ bool start_state(uint8_t packet_type, size_t length, const uint8_t* data) {
   }
 }
 
+void change_baud_rate(uint32_t rate) {
+  // make sure the ACK went out at the old rate
+  Serial.flush();
+  Serial.end();
+  Serial.begin(rate, SERIAL_8N1);
+  // The host confirms the new rate with an Echo Request. If that doesn't
+  // arrive intact, go back to the default rate; the host will do the same
+  // once it doesn't get an Echo Response.
+  static const uint8_t ECHO_REQUEST[] = {0x02,0xFF,0x05,0xD2,0xFD,0xEF,0x8D,
+                                         0x00};
+  auto deadline = millis() + BAUD_CONFIRM_TIMEOUT;
+  size_t got = 0;
+  bool garbled = false;
+  while(millis() < deadline && got < sizeof(ECHO_REQUEST)) {
+    if(!Serial.available()) continue;
+    if(Serial.read() != ECHO_REQUEST[got]) garbled = true;
+    ++got;
+  }
+  if(got == sizeof(ECHO_REQUEST) && !garbled) {
+    Serial.write((const uint8_t[]){0,0,8}, 3);
+    return;
+  }
+  // wait out the host's attempt, so none of it is mistaken for a packet
+  while(millis() < deadline)
+    ;
+  Serial.end();
+  Serial.begin(DEFAULT_BAUD_RATE, SERIAL_8N1);
+  while(Serial.available()) Serial.read();
+}
+
+void send_capabilities() {
+  uint8_t* buf = PacketIO::getBuf();
+  buf[0] = FIRMWARE_VERSION >> 8;
+  buf[1] = FIRMWARE_VERSION;
+  buf[2] = PROTOCOL_VERSION;
+  buf[3] = MAX_RANGES;
+  buf[4] = SERIAL_BUF_SIZE >> 8;
+  buf[5] = SERIAL_BUF_SIZE;
+  buf[6] = PacketIO::MAX_PHYSICAL_PACKET_SIZE >> 8;
+  buf[7] = PacketIO::MAX_PHYSICAL_PACKET_SIZE;
+  buf[8] = PacketIO::MAX_LOGICAL_PACKET_SIZE >> 8;
+  buf[9] = PacketIO::MAX_LOGICAL_PACKET_SIZE;
+  buf[10] = MAX_FLIPS >> 8;
+  buf[11] = MAX_FLIPS;
+  buf[12] = SERIAL_OUT_LIMIT >> 24;
+  buf[13] = SERIAL_OUT_LIMIT >> 16;
+  buf[14] = SERIAL_OUT_LIMIT >> 8;
+  buf[15] = SERIAL_OUT_LIMIT;
+  buf[16] = uint8_t(~TERMINATE_ON_UNUSED_FLAGS);
+  buf[17] = FEATURES >> 24;
+  buf[18] = FEATURES >> 16;
+  buf[19] = FEATURES >> 8;
+  buf[20] = FEATURES;
+  if(!PacketIO::sendFromBuf(0x43, 21))
+    shutdown(); // must flip back
+}
+
 uint8_t* buf;
 // The current position in a partially-transmitted cycle report, if any
 uint8_t* ptr;
@@ -413,8 +511,19 @@ void loop() {
   check_bus_cycle(CPU::BUS_MASK(0xFF00), CPU::BUS_READ(0x0100), 2);
   check_bus_cycle(CPU::BUS_MASK(0xFF00), CPU::BUS_READ(0x0100), 3);
   check_bus_cycle(CPU::BUS_MASK(0xFF00), CPU::BUS_READ(0x0100), 4);
-  while(!PacketIO::recv(start_state))
-    ; // repeat until we flip
+  while(true) {
+    bool flipped = PacketIO::recv(start_state);
+    if(pending_baud_rate != 0) {
+      change_baud_rate(pending_baud_rate);
+      pending_baud_rate = 0;
+    }
+    if(!flipped)
+      continue; // repeat until we flip
+    if(!capabilities_requested)
+      break; // Go!
+    capabilities_requested = false;
+    send_capabilities();
+  }
   // now in the Running state
   buf = PacketIO::getBuf();
   ptr = buf;
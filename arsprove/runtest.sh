@@ -34,5 +34,5 @@ if [ ! -f "$JOB" -o "$SRC" -nt "$JOB" -o "$JOBTMPL" -nt "$JOB" ]; then
 fi
 
 if [ ! -f "$HWOUT" -o "$JOB" -nt "$HWOUT" ]; then
-    wget -O "$HWOUT" -q --post-file "$JOB" http://localhost/65test.cgi
+    65test run "$JOB" > "$HWOUT" || (rm -f "$HWOUT"; false)
 fi
@@ -1,447 +1,348 @@
-#![feature(try_blocks)]
-
-extern crate base64;
-extern crate serde;
 extern crate serde_json;
-#[macro_use]
-extern crate serde_derive;
+extern crate test65;
 extern crate w65c02s;
 
-use serde::de::Error as SerdeDeError;
-use serde::de;
-use serde_json::Value;
-use std::borrow::BorrowMut;
-use std::clone::Clone;
-use std::cmp::Ordering;
-use std::collections::VecDeque;
-use std::fmt;
-use std::io::BufRead;
-use std::ops::Deref;
-use w65c02s::{W65C02S, P_V};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::TcpListener;
+use test65::{trace, Profile};
+use test65::coverage::Coverage;
+use test65::profiler::{self, Profiler};
+use test65::symbols::Symbols;
+use test65::harness::{Cpu, CpuState, Emulator, Pins, Registers, RunState,
+                      Snapshot, System};
+use w65c02s::{State, W65C02S, P_V};
+
+// The harness's idea of a CPU, in terms of the w65c02s crate.
+struct Core(W65C02S);
 
-const MIN_CYCLE_COUNT: u32 = 9;
-const MAX_CYCLE_COUNT: u32 = 10000000;
-const MAX_SPECIAL_CYCLES: usize = 20;
-const CYCLES_TO_REPORT: u32 = 1000;
+// The pins of a W65C02S, for the span of one bus cycle.
+struct PinsOf<'a>(&'a mut W65C02S);
 
-#[derive(Debug)]
-enum DataType {
-    Utf8, Base64
+impl<'a> Pins for PinsOf<'a> {
+    fn set_irq(&mut self, state: bool) { self.0.set_irq(state) }
+    fn set_nmi(&mut self, state: bool) { self.0.set_nmi(state) }
+    fn set_overflow(&mut self) { self.0.set_p(self.0.get_p() | P_V) }
+    fn reset(&mut self) { self.0.reset() }
+    fn get_s(&self) -> u8 { self.0.get_s() }
 }
-impl<'de> serde::Deserialize<'de> for DataType {
-    fn deserialize<D>(deserializer: D) -> Result<DataType, D::Error>
-    where D: serde::Deserializer<'de> {
-        deserializer.deserialize_str(DataTypeVisitor)
-    }
+
+impl Pins for Core {
+    fn set_irq(&mut self, state: bool) { PinsOf(&mut self.0).set_irq(state) }
+    fn set_nmi(&mut self, state: bool) { PinsOf(&mut self.0).set_nmi(state) }
+    fn set_overflow(&mut self) { PinsOf(&mut self.0).set_overflow() }
+    fn reset(&mut self) { PinsOf(&mut self.0).reset() }
+    fn get_s(&self) -> u8 { self.0.get_s() }
 }
-struct DataTypeVisitor;
-impl<'de> de::Visitor<'de> for DataTypeVisitor {
-    type Value = DataType;
-    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("either utf8 or base64")
-    }
-    fn visit_str<E: de::Error>(self, s: &str) -> Result<DataType, E> {
-        if s == "base64" { Ok(DataType::Base64) }
-        else if s == "utf8" { Ok(DataType::Utf8) }
-        else {
-            Err(E::custom("expected either utf8 or base64"))
+
+impl Cpu for Core {
+    fn step(&mut self, system: &mut System) {
+        self.0.step(&mut Bus(system));
+    }
+    fn registers(&self) -> Option<Registers> {
+        Some(Registers {
+            cycle: 0,
+            pc: self.0.get_pc(), a: self.0.get_a(), x: self.0.get_x(),
+            y: self.0.get_y(), s: self.0.get_s(), p: self.0.get_p(),
+        })
+    }
+    fn set_registers(&mut self, regs: &Registers) -> bool {
+        self.0.set_pc(regs.pc);
+        self.0.set_a(regs.a);
+        self.0.set_x(regs.x);
+        self.0.set_y(regs.y);
+        self.0.set_s(regs.s);
+        self.0.set_p(regs.p);
+        true
+    }
+    fn save(&self) -> Option<CpuState> {
+        Some(CpuState {
+            registers: self.registers()?,
+            state: match self.0.get_state() {
+                State::AwaitingInterrupt => RunState::Waiting,
+                State::Stopped => RunState::Stopped,
+                _ => RunState::Running,
+            },
+        })
+    }
+    // A W65C02S only gets into a state the way a real one does, so a fresh
+    // one is taken through its reset sequence (and a WAI or STP, if need be)
+    // with a bus that feeds it nothing but that opcode. Then the registers
+    // are put back.
+    fn restore(&mut self, state: &CpuState) -> bool {
+        let mut cpu = W65C02S::new();
+        let (opcode, target) = match state.state {
+            RunState::Reset => (None, State::Running),
+            RunState::Running => (Some(0xEA), State::Running),
+            RunState::Waiting => (Some(0xCB), State::AwaitingInterrupt),
+            RunState::Stopped => (Some(0xDB), State::Stopped),
+        };
+        if let Some(opcode) = opcode {
+            let mut primer = Primer(opcode);
+            cpu.step(&mut primer);
+            if cpu.get_state() != target { cpu.step(&mut primer); }
+            if cpu.get_state() != target { return false }
         }
+        self.0 = cpu;
+        self.set_registers(&state.registers)
     }
 }
 
-#[derive(Deserialize, Debug)]
-struct InitRec {
-    base: u16,
-    data: Blob,
-    size: Option<u32>,
-}
+// Reads `.0` from everywhere, and ignores writes.
+struct Primer(u8);
 
-#[derive(Debug)]
-struct Range {
-    start: u16,
-    end: u16
-}
-impl<'de> serde::Deserialize<'de> for Range {
-    fn deserialize<D>(deserializer: D) -> Result<Range, D::Error>
-    where D: serde::Deserializer<'de> {
-        deserializer.deserialize_seq(RangeVisitor)
-    }
+impl w65c02s::System for Primer {
+    fn read(&mut self, _cpu: &mut W65C02S, _addr: u16) -> u8 { self.0 }
+    fn write(&mut self, _cpu: &mut W65C02S, _addr: u16, _value: u8) {}
 }
-struct RangeVisitor;
-impl<'de> de::Visitor<'de> for RangeVisitor {
-    type Value = Range;
-    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("an array [a,b] where a are valid 16-bit addresses and b is >= a")
+
+// The harness's `System`, in terms of the w65c02s crate's. The CPU passes
+// itself along with each bus cycle, which is how the harness gets at its
+// pins.
+struct Bus<'a>(&'a mut System);
+
+impl<'a> w65c02s::System for Bus<'a> {
+    fn read(&mut self, cpu: &mut W65C02S, addr: u16) -> u8 {
+        self.0.read(&mut PinsOf(cpu), addr)
     }
-    fn visit_seq<A>(self, mut seq: A) -> Result<Range, A::Error>
-    where A: de::SeqAccess<'de> {
-        let len = seq.size_hint().expect("array lengths should be known");
-        if len != 2 {
-            return Err(de::Error::custom("range must be 2-element array"));
-        }
-        let start = seq.next_element()?.unwrap();
-        let end = seq.next_element()?.unwrap();
-        if end < start {
-            return Err(de::Error::custom("end of range cannot be less than \
-                                          start of range"));
-        }
-        Ok(Range { start, end })
+    fn read_locked(&mut self, cpu: &mut W65C02S, addr: u16) -> u8 {
+        self.0.read_locked(&mut PinsOf(cpu), addr)
     }
-}
-
-#[derive(Debug)]
-struct Blob {
-    data: Vec<u8>
-}
-impl Deref for Blob {
-    type Target = [u8];
-    fn deref(&self) -> &[u8] { &self.data[..] }
-}
-impl<'de> serde::Deserialize<'de> for Blob {
-    fn deserialize<D>(deserializer: D) -> Result<Blob, D::Error>
-    where D: serde::Deserializer<'de> {
-        deserializer.deserialize_str(BlobVisitor)
+    fn read_opcode(&mut self, cpu: &mut W65C02S, addr: u16) -> u8 {
+        self.0.read_opcode(&mut PinsOf(cpu), addr)
     }
-}
-struct BlobVisitor;
-impl<'de> de::Visitor<'de> for BlobVisitor {
-    type Value = Blob;
-    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("a string starting with utf8: or base64:")
+    fn read_vector(&mut self, cpu: &mut W65C02S, addr: u16) -> u8 {
+        self.0.read_vector(&mut PinsOf(cpu), addr)
     }
-    fn visit_str<E: de::Error>(self, s: &str) -> Result<Blob, E> {
-        if s.starts_with("base64:") {
-            match base64::decode(&s[7..]) {
-                Ok(data) => Ok(Blob{data}),
-                Err(_) => Err(E::custom("invalid base64 data")),
-            }
-        }
-        else if s.starts_with("utf8:") {
-            Ok(Blob{data: s[5..].as_bytes().to_vec()})
-        }
-        else {
-            Err(E::custom("binary data must start with utf8: or base64:"))
-        }
+    fn write(&mut self, cpu: &mut W65C02S, addr: u16, value: u8) {
+        self.0.write(&mut PinsOf(cpu), addr, value)
     }
-    fn visit_byte_buf<E: de::Error>(self, data: Vec<u8>) -> Result<Blob, E> {
-        Ok(Blob{data})
+    fn write_locked(&mut self, cpu: &mut W65C02S, addr: u16, value: u8) {
+        self.0.write_locked(&mut PinsOf(cpu), addr, value)
     }
 }
 
-#[derive(Deserialize, Debug)]
-struct Job {
-    init: Vec<InitRec>,
-    rwmap: Option<Vec<Range>>,
-    serial_in_addr: Option<u16>,
-    serial_out_addr: Option<u16>,
-    serial_in_data: Option<Blob>,
-    serial_out_fmt: Option<DataType>,
-    show_cycles: Option<bool>,
-    max_cycles: Option<u32>,
-    terminate_on_brk: Option<bool>,
-    terminate_on_infinite_loop: Option<bool>,
-    terminate_on_zero_fetch: Option<bool>,
-    terminate_on_stack_fetch: Option<bool>,
-    terminate_on_vector_fetch: Option<bool>,
-    terminate_on_bad_write: Option<bool>,
-    nmi: Option<Vec<u32>>,
-    irq: Option<Vec<u32>>,
-    rdy: Option<Vec<u32>>,
-    so: Option<Vec<u32>>,
-    res: Option<Vec<u32>>,
+// Whatever is looking at every cycle of the run, not just the reported ones.
+struct Observers {
+    coverage: Option<Coverage>,
+    profiler: Option<Profiler>,
 }
 
-fn get_job(input: &mut BufRead) -> Result<Job, serde_json::Error> {
-    try {
-        let mut job: Job = serde_json::from_reader(input.borrow_mut())?;
-        for rec in &job.init {
-            let size = match rec.size {
-                None => rec.data.len() as u32,
-                Some(size) => size,
-            };
-            let endut = (rec.base as u32).saturating_add(size);
-            if endut > 65536 {
-                Err(serde_json::Error::custom("Initialization record exceeds \
-                                               size of address space"))?
-            }
-        }
-        if let Some(max_cycles) = job.max_cycles {
-            if max_cycles > MAX_CYCLE_COUNT || max_cycles < MIN_CYCLE_COUNT {
-                Err(serde_json::Error::custom("Maximum cycle count cannot \
-                                               exceed 10,000,000 or be less \
-                                               than 9"))?
-            }
-        }
-        for spec in [&mut job.nmi, &mut job.irq, &mut job.rdy,
-                     &mut job.so, &mut job.res].iter_mut() {
-            if let Some(ref mut spec) = spec {
-                if spec.len() > MAX_SPECIAL_CYCLES {
-                    Err(serde_json::Error::custom("A given special signal may \
-                                                   not toggle more than 20 \
-                                                   times"))?
+impl Observers {
+    fn any(&self) -> bool {
+        self.coverage.is_some() || self.profiler.is_some()
+    }
+    // Steps until cycle `until` (or the end of the job), feeding the cycles
+    // to the observers as it goes.
+    fn run_until(&mut self, emulator: &mut Emulator<Core>, until: u32) {
+        while emulator.is_running() && emulator.num_cycles() < until {
+            emulator.step();
+            if !self.any() { continue }
+            let log = emulator.probe().log.as_mut()
+                .expect("observers need the log");
+            for &cycle in log.iter() {
+                if let Some(coverage) = self.coverage.as_mut() {
+                    coverage.add_cycle(&trace::cycle_string(cycle));
+                }
+                if let Some(profiler) = self.profiler.as_mut() {
+                    profiler.add_cycle(cycle);
                 }
-                spec.sort_unstable();
             }
+            log.clear();
         }
-        job
-    }
-}
-
-#[derive(Clone,Copy,PartialEq,Eq)]
-enum FlipType { Overflow, Nmi, Irq }
-
-#[derive(PartialEq,Eq)]
-struct Flip {
-    typ: FlipType,
-    cycle: u32,
-    state: bool,
-}
-
-impl PartialOrd for Flip {
-    fn partial_cmp(&self, other: &Flip) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-impl Ord for Flip {
-    fn cmp(&self, other: &Flip) -> Ordering {
-        self.cycle.cmp(&other.cycle)
     }
 }
 
-struct System {
-    sram: [u8; 65536],
-    writable: [bool; 65536],
-    serial_in_addr: Option<u16>,
-    serial_out_addr: Option<u16>,
-    serial_in_data: VecDeque<u8>,
-    serial_out_data: Vec<u8>,
-    vector_has_been_pulled: bool,
-    cycles_to_report: u32,
-    cycles: Vec<String>,
-    terminate_on_brk: bool,
-    terminate_on_infinite_loop: bool,
-    terminate_on_zero_fetch: bool,
-    terminate_on_stack_fetch: bool,
-    terminate_on_vector_fetch: bool,
-    terminate_on_bad_write: bool,
-    last_pc: Option<u16>,
-    termination_cause: Option<&'static str>,
-    cycles_to_run: u32,
-    num_cycles: u32,
-    flips: VecDeque<Flip>,
+fn usage() -> ! {
+    eprintln!("Usage: rustcore [--local] [--trace-file cycles.txt | --vcd \
+               out.vcd]\n\
+               \x20                [--snapshot-at CYCLE --snapshot \
+               snap.json]\n\
+               \x20                [--coverage coverage.json] \
+               [--profile profile.json]\n\
+               \x20                [--profile-report profile.txt] \
+               [--profile-range NAME=START-END]...\n\
+               \x20                < job.json\n\
+               \x20      rustcore --resume snap.json [--trace-file \
+               cycles.txt]\n\
+               \x20      rustcore --debug [--local] job.json\n\
+               \x20      rustcore --debug --resume snap.json\n\
+               \x20      rustcore --gdb PORT [--local] job.json\n\
+               \x20      rustcore --gdb PORT --resume snap.json\n\
+               \n\
+               --local lifts the server's limits on cycle counts, reported \
+               cycles, and\n\
+               signal toggles. --trace-file writes the reported cycles \
+               there, one per\n\
+               line, instead of into the response. --snapshot saves the \
+               job's state at\n\
+               the first instruction boundary at or after CYCLE, and \
+               --resume runs the\n\
+               rest of the job from there. --coverage writes which opcodes \
+               ran, and how, for\n\
+               65test coverage. --profile writes where the cycles went, \
+               as JSON, and\n\
+               --profile-report as text, totaled per PC, opcode, and \
+               --profile-range\n\
+               (hex addresses, inclusive), with reads and writes per page.\n\
+               --debug runs the job under an interactive debugger instead; \
+               type \"help\"\n\
+               at its prompt. --gdb waits for GDB to connect to \
+               127.0.0.1:PORT, and lets\n\
+               it debug the job. Any of these can take --symbols FILE (a \
+               WLA-DX .sym, VICE\n\
+               label, or ca65 .dbg file) in place of the job's \
+               \"symbols\", to show\n\
+               addresses as label+offset.");
+    std::process::exit(1)
 }
 
-impl System {
-    fn add_flips(flips: &mut Vec<Flip>, vec: &Option<Vec<u32>>, typ: FlipType) {
-        if let Some(ref vec) = vec {
-            let mut next_state = true;
-            for cycle in vec.iter() {
-                flips.push(Flip { cycle: *cycle, typ, state: next_state });
-                next_state = !next_state;
-            }
-        }
-    }
-    pub fn new(job: &Job) -> System {
-        if job.rdy.is_some() { panic!("RDY is not supported") }
-        if job.res.is_some() { panic!("Reset is not supported") }
-        let mut sram = [0; 65536];
-        sram[0xFFFD] = 0x02;
-        for rec in job.init.iter() {
-            let base = rec.base as usize;
-            let size = rec.size.map(|x| x as usize)
-                .unwrap_or(rec.data.data.len());
-            (&mut sram[base .. base+size]).copy_from_slice(&rec.data.data[..]);
-        }
-        let mut writable = [false; 65536];
-        let rwmap = &job.rwmap;
-        let rwmap = match rwmap {
-            Some(rwmap) => &rwmap[..],
-            None => &[Range { start: 0, end: 511 }],
-        };
-        for range in rwmap.iter() {
-            for cell in (&mut writable[range.start as usize .. range.end as usize + 1]).iter_mut() {
-                *cell = true;
-            }
-        }
-        let serial_in_data = match &job.serial_in_data {
-            Some(data) => data.data.clone().into(),
-            None => VecDeque::new(),
-        };
-        let cycles_to_run = job.max_cycles.unwrap_or(MAX_CYCLE_COUNT);
-        let mut flips = Vec::new();
-        Self::add_flips(&mut flips, &job.so, FlipType::Overflow);
-        Self::add_flips(&mut flips, &job.nmi, FlipType::Nmi);
-        Self::add_flips(&mut flips, &job.irq, FlipType::Irq);
-        flips.sort_unstable();
-        System {
-            sram, writable,
-            serial_in_addr: job.serial_in_addr,
-            serial_out_addr: job.serial_out_addr,
-            serial_in_data,
-            serial_out_data: Vec::new(),
-            vector_has_been_pulled: false,
-            cycles_to_run,
-            cycles_to_report: if job.show_cycles.unwrap_or(false) { CYCLES_TO_REPORT.min(cycles_to_run) } else { 0 },
-            cycles: Vec::new(),
-            terminate_on_brk: job.terminate_on_brk.unwrap_or(true),
-            terminate_on_infinite_loop: job.terminate_on_infinite_loop.unwrap_or(true),
-            terminate_on_zero_fetch: job.terminate_on_zero_fetch.unwrap_or(true),
-            terminate_on_stack_fetch: job.terminate_on_stack_fetch.unwrap_or(true),
-            terminate_on_vector_fetch: job.terminate_on_vector_fetch.unwrap_or(true),
-            terminate_on_bad_write: job.terminate_on_bad_write.unwrap_or(true),
-            last_pc: None,
-            termination_cause: None,
-            num_cycles: 5,
-            flips: flips.into(),
-        }
-    }
-    fn report_cycle(&mut self, cpu: &mut W65C02S, typ: u32, addr: u16, data: u8) {
-        if self.num_cycles >= self.cycles_to_run || self.termination_cause.is_some() { return }
-        if self.cycles_to_report > 0 && self.termination_cause.is_none() {
-            self.cycles_to_report -= 1;
-            self.cycles.push(format!("{:1X}{:04X}{:02X}", typ, addr, data));
-        }
-        self.num_cycles += 1;
-        while !self.flips.is_empty() {
-            if self.flips.front().unwrap().cycle > self.num_cycles { break }
-            let flip = self.flips.pop_front().unwrap();
-            match flip.typ {
-                FlipType::Overflow => if flip.state {
-                    cpu.set_p(cpu.get_p() | P_V);
-                },
-                FlipType::Irq => cpu.set_irq(flip.state),
-                FlipType::Nmi => cpu.set_nmi(flip.state),
-            }
-        }
-    }
-    fn handle_read(&mut self, cpu: &mut W65C02S, addr: u16) -> u8 {
-        if let Some(serial_in_addr) = self.serial_in_addr {
-            if addr == serial_in_addr {
-                match self.serial_in_data.pop_front() {
-                    Some(x) => return x,
-                    None => {
-                        cpu.set_p(cpu.get_p() | P_V);
-                        return 0;
-                    }
-                }
-            }
-        }
-        self.sram[addr as usize]
-    }
-    fn handle_write(&mut self, _: &mut W65C02S, addr: u16, value: u8) {
-        if let Some(serial_out_addr) = self.serial_out_addr {
-            if addr == serial_out_addr {
-                return self.serial_out_data.push(value);
-            }
-        }
-        if self.writable[addr as usize] {
-            self.sram[addr as usize] = value;
-        }
-        else if self.terminate_on_bad_write && self.termination_cause.is_none() {
-            self.termination_cause = Some("bad_write");
+fn main() {
+    let mut profile = Profile::Strict;
+    let mut trace_path = None;
+    let mut vcd_path = None;
+    let mut snapshot_at = None;
+    let mut snapshot_path = None;
+    let mut resume_path = None;
+    let mut debug = false;
+    let mut gdb_port = None;
+    let mut coverage_path = None;
+    let mut profile_path = None;
+    let mut profile_report_path = None;
+    let mut profile_ranges = Vec::new();
+    let mut symbols_path = None;
+    let mut job_path = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--local" => profile = Profile::Local,
+            "--trace-file" => trace_path = Some(value()),
+            "--vcd" => vcd_path = Some(value()),
+            "--snapshot-at" => snapshot_at = Some(value().parse::<u32>()
+                                                  .unwrap_or_else(|_| usage())),
+            "--snapshot" => snapshot_path = Some(value()),
+            "--resume" => resume_path = Some(value()),
+            "--debug" => debug = true,
+            "--symbols" => symbols_path = Some(value()),
+            "--coverage" => coverage_path = Some(value()),
+            "--profile" => profile_path = Some(value()),
+            "--profile-report" => profile_report_path = Some(value()),
+            "--profile-range" => profile_ranges.push(
+                profiler::parse_range(&value()).unwrap_or_else(|| usage())),
+            "--gdb" => gdb_port = Some(value().parse::<u16>()
+                                       .unwrap_or_else(|_| usage())),
+            x if !x.starts_with("-") && job_path.is_none() =>
+                job_path = Some(x.to_owned()),
+            _ => usage(),
         }
     }
-    fn perform_read(&mut self, cpu: &mut W65C02S, typ: u32, addr: u16) -> u8 {
-        let ret = self.handle_read(cpu, addr);
-        if self.vector_has_been_pulled {
-            self.report_cycle(cpu, typ, addr, ret)
+    // the VCD is made from the cycles in the response
+    if trace_path.is_some() && vcd_path.is_some() { usage() }
+    if snapshot_at.is_some() != snapshot_path.is_some() { usage() }
+    // a snapshot brings its own limits, and there's no job to make a VCD of
+    if resume_path.is_some()
+    && (profile != Profile::Strict || vcd_path.is_some()) {
+        usage()
+    }
+    let profiling = profile_path.is_some() || profile_report_path.is_some();
+    if !profiling && !profile_ranges.is_empty() { usage() }
+    // a debugger has stdin (or doesn't need it), so the job comes from a file
+    let debugging = debug || gdb_port.is_some();
+    if job_path.is_some() != (debugging && resume_path.is_none())
+    || (debug && gdb_port.is_some())
+    || (debugging && (vcd_path.is_some() || snapshot_at.is_some()
+                      || coverage_path.is_some() || profiling)) {
+        usage()
+    }
+    let job = match (resume_path.as_ref(), job_path) {
+        (Some(_), _) => None,
+        (None, Some(path)) => {
+            let file = BufReader::new(File::open(path).unwrap());
+            Some(test65::get_job_for(file, profile).unwrap())
+        },
+        (None, None) => {
+            let stdin = std::io::stdin();
+            Some(test65::get_job_for(stdin.lock(), profile).unwrap())
+        },
+    };
+    let trace_out = trace_path.map(|path| {
+        let file = File::create(path).unwrap();
+        Box::new(BufWriter::new(file)) as Box<dyn Write>
+    });
+    let mut core = Core(W65C02S::new());
+    let mut emulator = match (&job, resume_path) {
+        (Some(job), _) => Emulator::new(job, &mut core, profile, trace_out),
+        (None, Some(path)) => {
+            let file = BufReader::new(File::open(path).unwrap());
+            let snapshot: Snapshot = serde_json::from_reader(file).unwrap();
+            Emulator::resume(&snapshot, &mut core, trace_out).unwrap()
+        },
+        (None, None) => unreachable!(),
+    };
+    if let Some(path) = symbols_path {
+        emulator.set_symbols(Some(Symbols::load(&path).unwrap()));
+    }
+    if debug {
+        let stdin = std::io::stdin();
+        let stdout = std::io::stdout();
+        test65::debugger::debug(&mut emulator, &mut stdin.lock(),
+                                &mut stdout.lock()).unwrap();
+        return
+    }
+    if let Some(port) = gdb_port {
+        let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+        eprintln!("rustcore: waiting for GDB on 127.0.0.1:{}", port);
+        let (stream, _) = listener.accept().unwrap();
+        test65::gdb::serve(&mut emulator, stream).unwrap();
+        return
+    }
+    let mut observers = Observers {
+        coverage: coverage_path.as_ref().map(|_| Coverage::new()),
+        profiler: if profiling { Some(Profiler::new()) } else { None },
+    };
+    if observers.any() { emulator.probe().log = Some(Vec::new()) }
+    if let (Some(cycle), Some(path)) = (snapshot_at, snapshot_path) {
+        observers.run_until(&mut emulator, cycle);
+        match emulator.snapshot() {
+            Ok(snapshot) => {
+                let mut file = BufWriter::new(File::create(path).unwrap());
+                serde_json::to_writer(&mut file, &snapshot).unwrap();
+                file.flush().unwrap();
+            },
+            Err(e) => eprintln!("rustcore: no snapshot at cycle {}: {}",
+                                cycle, e),
         }
-        ret
     }
-    fn perform_write(&mut self, cpu: &mut W65C02S, typ: u32, addr: u16, value: u8) {
-        if self.vector_has_been_pulled {
-            self.report_cycle(cpu, typ, addr, value)
+    if observers.any() {
+        observers.run_until(&mut emulator, u32::max_value());
+    }
+    if let (Some(mut coverage), Some(path)) = (observers.coverage,
+                                               coverage_path) {
+        coverage.finish();
+        let mut file = BufWriter::new(File::create(path).unwrap());
+        serde_json::to_writer(&mut file, &coverage).unwrap();
+        file.flush().unwrap();
+    }
+    if let Some(mut profiler) = observers.profiler {
+        profiler.finish();
+        let report = profiler.report(&profile_ranges, emulator.symbols());
+        if let Some(path) = profile_path {
+            let mut file = BufWriter::new(File::create(path).unwrap());
+            serde_json::to_writer(&mut file, &report).unwrap();
+            file.flush().unwrap();
         }
-        self.handle_write(cpu, addr, value);
-    }
-}
-
-const LOCKED_WRITE: u32 = 2;
-const LOCKED_READ: u32 = 3;
-const VECTOR_READ: u32 = 5;
-const NORMAL_WRITE: u32 = 6;
-const NORMAL_READ: u32 = 7;
-const OPCODE_READ: u32 = 15;
-
-impl w65c02s::System for System {
-    fn read(&mut self, cpu: &mut W65C02S, addr: u16) -> u8 {
-        self.perform_read(cpu, NORMAL_READ, addr)
-    }
-    fn read_locked(&mut self, cpu: &mut W65C02S, addr: u16) -> u8 {
-        self.perform_read(cpu, LOCKED_READ, addr)
-    }
-    // 0x03 = fast NOP
-    fn read_opcode(&mut self, cpu: &mut W65C02S, addr: u16) -> u8 {
-        let ret = self.perform_read(cpu, OPCODE_READ, addr);
-        if self.vector_has_been_pulled {
-            if let Some(last_pc) = self.last_pc {
-                if self.terminate_on_infinite_loop && addr == last_pc {
-                    self.termination_cause = Some("infinite_loop");
-                    return 0x03;
-                }
-            }
-            self.last_pc = Some(addr);
-            if self.terminate_on_zero_fetch && addr < 0x0100 {
-                self.termination_cause = Some("zero_fetch");
-                return 0x03;
-            }
-            if self.terminate_on_stack_fetch && addr >= 0x0100 && addr < 0x0200 {
-                self.termination_cause = Some("stack_fetch");
-                return 0x03;
-            }
-            if self.terminate_on_vector_fetch && addr >= 0xFFFA {
-                self.termination_cause = Some("vector_fetch");
-                return 0x03;
-            }
-            if self.terminate_on_brk && ret == w65c02s::op::BRK {
-                self.termination_cause = Some("brk");
-                return 0x03;
-            }
+        if let Some(path) = profile_report_path {
+            let mut file = BufWriter::new(File::create(path).unwrap());
+            report.write_report(&mut file).unwrap();
+            file.flush().unwrap();
         }
-        ret
-    }
-    fn read_vector(&mut self, cpu: &mut W65C02S, addr: u16) -> u8 {
-        self.vector_has_been_pulled = true;
-        self.perform_read(cpu, VECTOR_READ, addr)
-    }
-    fn write(&mut self, cpu: &mut W65C02S, addr: u16, value: u8) {
-        self.perform_write(cpu, NORMAL_WRITE, addr, value)
-    }
-    fn write_locked(&mut self, cpu: &mut W65C02S, addr: u16, value: u8) {
-        self.perform_write(cpu, LOCKED_WRITE, addr, value)
-    }
-}
-
-fn main() {
-    let job = {
-        let stdin = std::io::stdin();
-        let mut stdin = stdin.lock();
-        get_job(&mut stdin)
-    }.unwrap();
-    let mut system = System::new(&job);
-    let mut cpu = W65C02S::new();
-    while system.termination_cause.is_none() && system.num_cycles < system.cycles_to_run {
-        cpu.step(&mut system);
-    }
-    if system.num_cycles >= system.cycles_to_run && system.termination_cause.is_none() {
-        system.termination_cause = Some("limit");
-    }
-    let mut result = serde_json::Map::new();
-    if let Some(last_pc) = system.last_pc {
-        result.insert("last_pc".to_string(), Value::from(last_pc));
-    }
-    result.insert("num_cycles".to_string(), Value::from(system.num_cycles));
-    if let Some(termination_cause) = system.termination_cause {
-        result.insert("termination_cause".to_string(), Value::from(termination_cause));
-    }
-    if !system.cycles.is_empty() {
-        result.insert("cycles".to_string(), Value::from(system.cycles));
     }
-    match job.serial_out_fmt {
-        None => (),
-        Some(DataType::Utf8) => { result.insert("serial_out_data".to_string(), Value::from("utf8:".to_owned()+&String::from_utf8_lossy(&system.serial_out_data[..]))); },
-        Some(DataType::Base64) => { result.insert("serial_out_data".to_string(), Value::from("base64:".to_owned()+&base64::encode(&system.serial_out_data[..]))); },
+    let result = emulator.finish().unwrap();
+    if let (Some(job), Some(vcd_path)) = (job, vcd_path) {
+        let mut file = BufWriter::new(File::create(vcd_path).unwrap());
+        test65::vcd::write_vcd(&mut file, &job, &result).unwrap();
+        file.flush().unwrap();
     }
-    println!("{}", Value::Object(result).to_string());
+    println!("{}", serde_json::to_string(&result).unwrap());
 }
@@ -64,6 +64,17 @@ local covered = {
    [0x90]=true, [0xB0]=true, [0xD0]=true, [0xF0]=true,
 }
 
+-- `make coverage` checks these against the traces of the non-generated tests
+do
+   local list = {}
+   for n=0,255 do
+      if covered[n] then list[#list+1] = ("%02X"):format(n) end
+   end
+   local f = assert(io.open("gen/covered.txt", "wb"))
+   f:write(table.concat(list, ","), "\n")
+   f:close()
+end
+
 -- leave out the implied modes
 local MODES = {"abs","absx","absy","immediate","zp","zpi","zpi","zpiy","zpx","zpxi","zpy"}
 local TEMPLATES = {}
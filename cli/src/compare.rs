@@ -0,0 +1,590 @@
+use serde_json::{json, Value};
+use test65::JobResult;
+use test65::symbols::Symbols;
+use test65::trace::{self, Instruction, FIRST_REPORTED_CYCLE};
+use super::load_response;
+
+// Exit statuses, as diff(1) has them.
+const EXIT_DIFFERENT: i32 = 1;
+const EXIT_TROUBLE: i32 = 2;
+
+// Only this many differing instructions are printed; --json gets them all.
+const MAX_PRINTED_INSTRUCTIONS: usize = 10;
+// Serial output is shown from a little before the first difference.
+const SERIAL_CONTEXT: usize = 32;
+const SERIAL_SHOWN: usize = 64;
+// When the traces run different instructions, look this far ahead for where
+// they come back together, and want this many instructions in a row to agree
+// before believing they have.
+const RESYNC_WINDOW: usize = 256;
+const RESYNC_CONFIRM: usize = 4;
+
+struct Options {
+    names: (String, String),
+    json: bool,
+    complete: bool,
+    max_cycles: Option<u32>,
+    symbols_path: Option<String>,
+    paths: Vec<String>,
+}
+
+fn usage(argv0: &str) -> i32 {
+    eprintln!("Usage: {} compare [--names LEFT,RIGHT] [--json] [--complete] \
+               [--max-cycles N]\n\
+               \x20      [--symbols FILE] left.json right.json\n\
+               \n\
+               Compares two responses, usually real hardware against an \
+               emulator. Exits\n\
+               with status 0 if they match, 1 if they don't, and 2 if \
+               something went\n\
+               wrong. --complete also fails a trace that stops before its \
+               job did.\n\
+               --max-cycles fails a test whose left side was traced but ran \
+               for more than N\n\
+               cycles (when both sides ended the same way), as \
+               util/checktest.lua does.\n\
+               --symbols labels addresses from a symbol file (WLA-DX .sym, \
+               VICE labels, or\n\
+               ca65 .dbg), for responses to jobs that had none.",
+              argv0);
+    EXIT_TROUBLE
+}
+
+fn parse_options(args: &[String]) -> Option<Options> {
+    let mut ret = Options {
+        names: ("left".to_owned(), "right".to_owned()),
+        json: false, complete: false, max_cycles: None, symbols_path: None,
+        paths: Vec::new(),
+    };
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--names" => {
+                let mut names = it.next()?.splitn(2, ',');
+                ret.names = (names.next()?.to_owned(),
+                             names.next()?.to_owned());
+            },
+            "--json" => ret.json = true,
+            "--complete" => ret.complete = true,
+            "--max-cycles" => ret.max_cycles = Some(it.next()?.parse().ok()?),
+            "--symbols" => ret.symbols_path = Some(it.next()?.to_owned()),
+            x if x.starts_with("-") => return None,
+            x => ret.paths.push(x.to_owned()),
+        }
+    }
+    if ret.paths.len() != 2 { return None }
+    Some(ret)
+}
+
+// The bytes behind a `serial_out_data` string.
+fn decode_serial(data: &str) -> Option<Vec<u8>> {
+    if data.starts_with("base64:") { base64::decode(&data[7..]).ok() }
+    else if data.starts_with("utf8:") { Some(data[5..].as_bytes().to_vec()) }
+    else { None }
+}
+
+fn serial_difference(left: &Option<String>, right: &Option<String>)
+                     -> Option<Value> {
+    if left == right { return None }
+    let mut ret = json!({
+        "field": "serial_out_data",
+        "left": left,
+        "right": right,
+    });
+    let decode = |x: &Option<String>| decode_serial(x.as_ref()?);
+    if let (Some(l), Some(r)) = (decode(left), decode(right)) {
+        let first = l.iter().zip(r.iter()).position(|(a, b)| a != b)
+            .unwrap_or(l.len().min(r.len()));
+        ret["left_len"] = json!(l.len());
+        ret["right_len"] = json!(r.len());
+        ret["first_difference"] = json!(first);
+    }
+    Some(ret)
+}
+
+// `last_pc` as label+offset, from the response or else from --symbols.
+fn last_pc_symbol(result: &JobResult, symbols: Option<&Symbols>)
+                  -> Option<String> {
+    result.last_pc_symbol.clone()
+        .or_else(|| symbols?.lookup(result.last_pc?))
+}
+
+fn instruction_difference(left: &[String], right: &[String],
+                          l_trace: &[Instruction], r_trace: &[Instruction],
+                          (ln, rn): (Option<usize>, Option<usize>))
+                          -> Option<Value> {
+    let l = ln.map(|x| &l_trace[x]);
+    let r = rn.map(|x| &r_trace[x]);
+    let l_cycles = l.map(|x| &left[x.range()]).unwrap_or(&[]);
+    let r_cycles = r.map(|x| &right[x.range()]).unwrap_or(&[]);
+    if l_cycles == r_cycles { return None }
+    let differing: Vec<usize> = (0 .. l_cycles.len().max(r_cycles.len()))
+        .filter(|&i| l_cycles.get(i) != r_cycles.get(i)).collect();
+    Some(json!({
+        "field": "cycles",
+        "left_instruction": ln,
+        "right_instruction": rn,
+        "left_cycle": l.map(|x| x.cycle),
+        "right_cycle": r.map(|x| x.cycle),
+        "left_disasm": l.map(|x| &x.text),
+        "right_disasm": r.map(|x| &x.text),
+        "left_symbol": l.and_then(|x| x.symbol.as_ref()),
+        "right_symbol": r.and_then(|x| x.symbol.as_ref()),
+        "left_labels": l.map(|x| &x.labels),
+        "right_labels": r.map(|x| &x.labels),
+        "left": l_cycles,
+        "right": r_cycles,
+        "differing": differing,
+    }))
+}
+
+// Instructions line up if they start at the same PC. An interrupt (whose
+// opcode fetch is ignored) or reset sequence (which has none) only lines up
+// with another of the same kind.
+fn same_start(l: &Instruction, r: &Instruction) -> bool {
+    let sequence = |x: &Instruction| {
+        x.pc.is_none()
+            || x.labels.first().map(|x| x.as_str()) == Some("ignored opcode")
+    };
+    l.pc == r.pc
+        && ((!sequence(l) && !sequence(r)) || l.text == r.text)
+}
+
+fn in_step(l: &[Instruction], r: &[Instruction]) -> bool {
+    let n = RESYNC_CONFIRM.min(l.len()).min(r.len());
+    n > 0 && (0 .. n).all(|k| same_start(&l[k], &r[k]))
+}
+
+// The fewest instructions to skip, on either side or both, before the traces
+// are in step again.
+fn resync(l: &[Instruction], r: &[Instruction]) -> Option<(usize, usize)> {
+    for d in 1 ..= RESYNC_WINDOW {
+        for a in 0 ..= d {
+            let b = d - a;
+            if a < l.len() && b < r.len() && in_step(&l[a..], &r[b..]) {
+                return Some((a, b))
+            }
+        }
+    }
+    None
+}
+
+// Pairs up the instructions of the two traces, in order. One that the other
+// side has no match for (an extra loop iteration, say, or an interrupt taken
+// a little later) is paired with None.
+fn align(l: &[Instruction], r: &[Instruction])
+         -> Vec<(Option<usize>, Option<usize>)> {
+    let mut ret = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < l.len() && j < r.len() {
+        if same_start(&l[i], &r[j]) {
+            ret.push((Some(i), Some(j)));
+            i += 1;
+            j += 1;
+            continue
+        }
+        match resync(&l[i..], &r[j..]) {
+            Some((a, b)) => {
+                ret.extend((i .. i + a).map(|x| (Some(x), None)));
+                ret.extend((j .. j + b).map(|x| (None, Some(x))));
+                i += a;
+                j += b;
+            },
+            None => {
+                // They've gone their separate ways. Pair them up as they
+                // come for a while, then look again.
+                let n = RESYNC_WINDOW.min(l.len() - i).min(r.len() - j);
+                ret.extend((0 .. n).map(|x| (Some(i + x), Some(j + x))));
+                i += n;
+                j += n;
+            },
+        }
+    }
+    ret.extend((i .. l.len()).map(|x| (Some(x), None)));
+    ret.extend((j .. r.len()).map(|x| (None, Some(x))));
+    ret
+}
+
+// Lines up the two traces an instruction at a time, so that an instruction
+// that takes an extra cycle on one side doesn't throw off every cycle after
+// it, and one that only one side ran doesn't throw off every instruction
+// after it.
+fn cycle_differences(left: &[String], right: &[String],
+                     symbols: Option<&Symbols>) -> Vec<Value> {
+    let mut l = trace::decode(left);
+    let mut r = trace::decode(right);
+    if let Some(symbols) = symbols {
+        symbols.annotate(&mut l);
+        symbols.annotate(&mut r);
+    }
+    align(&l, &r).into_iter().filter_map(|pair| {
+        instruction_difference(left, right, &l, &r, pair)
+    }).collect()
+}
+
+// With --complete: a trace that was asked for, but stopped before the job
+// did, can't vouch for the cycles it left out.
+fn untraced_cycles(result: &JobResult) -> Option<usize> {
+    let traced = result.cycles.len() + FIRST_REPORTED_CYCLE;
+    if !result.cycles.is_empty() && result.num_cycles as usize > traced {
+        Some(result.num_cycles as usize - traced)
+    }
+    else { None }
+}
+
+fn compare(options: &Options, symbols: Option<&Symbols>, left: &JobResult,
+           right: &JobResult) -> Vec<Value> {
+    let mut ret = Vec::new();
+    if left.num_cycles != right.num_cycles {
+        ret.push(json!({"field": "num_cycles", "left": left.num_cycles,
+                        "right": right.num_cycles}));
+    }
+    if left.last_pc != right.last_pc {
+        ret.push(json!({"field": "last_pc", "left": left.last_pc,
+                        "right": right.last_pc,
+                        "left_symbol": last_pc_symbol(left, symbols),
+                        "right_symbol": last_pc_symbol(right, symbols)}));
+    }
+    if left.termination_cause != right.termination_cause {
+        ret.push(json!({"field": "termination_cause",
+                        "left": left.termination_cause,
+                        "right": right.termination_cause}));
+    }
+    else if let Some(limit) = options.max_cycles {
+        if !left.cycles.is_empty() && left.num_cycles > limit {
+            ret.push(json!({"field": "num_cycles", "side": "left",
+                            "max_cycles": limit,
+                            "num_cycles": left.num_cycles}));
+        }
+    }
+    ret.extend(serial_difference(&left.serial_out_data,
+                                 &right.serial_out_data));
+    if options.complete {
+        for (side, result) in [("left", left), ("right", right)].iter() {
+            if let Some(n) = untraced_cycles(result) {
+                ret.push(json!({"field": "num_cycles", "side": side,
+                                "untraced_cycles": n}));
+            }
+        }
+    }
+    ret.extend(cycle_differences(&left.cycles, &right.cycles, symbols));
+    ret
+}
+
+fn show_pc(pc: &Value, symbol: &Value) -> String {
+    match (pc.as_u64(), symbol.as_str()) {
+        (Some(pc), Some(symbol)) => format!("${:04X} ({})", pc, symbol),
+        (Some(pc), None) => format!("${:04X}", pc),
+        (None, _) => "(none)".to_owned(),
+    }
+}
+
+// Some of a serial output, from a little before the first difference.
+fn show_serial(data: &Value, first: usize) -> String {
+    let data = match data.as_str() {
+        Some(x) => x,
+        None => return "(none)".to_owned(),
+    };
+    let bytes = match decode_serial(data) {
+        Some(x) => x,
+        None => return format!("{:?}", data),
+    };
+    let start = first.saturating_sub(SERIAL_CONTEXT).min(bytes.len());
+    let shown = &bytes[start .. (start + SERIAL_SHOWN).min(bytes.len())];
+    if data.starts_with("utf8:") {
+        format!("from {}: {:?}", start, String::from_utf8_lossy(shown))
+    }
+    else {
+        let hex: Vec<String> = shown.iter()
+            .map(|x| format!("{:02X}", x)).collect();
+        format!("{} bytes, from {:06X}: {}", bytes.len(), start,
+                hex.join(" "))
+    }
+}
+
+fn print_instruction(names: &(String, String), diff: &Value) {
+    let at = |x: &Value| x.as_u64().map(|x| format!("cycle {}", x))
+        .unwrap_or_else(|| "nowhere".to_owned());
+    let dis = |side: &str| {
+        let text = diff[format!("{}_disasm", side)].as_str()
+            .unwrap_or("(nothing)");
+        match diff[format!("{}_symbol", side)].as_str() {
+            Some(symbol) => format!("{}: {}", symbol, text),
+            None => text.to_owned(),
+        }
+    };
+    let (ln, rn) = (&diff["left_instruction"], &diff["right_instruction"]);
+    match (ln.as_u64(), rn.as_u64()) {
+        (Some(l), Some(r)) if l == r =>
+            println!("Bus traffic differs in instruction {}.", l),
+        (Some(l), Some(r)) =>
+            println!("Bus traffic differs in instructions {} and {}.", l, r),
+        (Some(l), None) =>
+            println!("Only {} ran instruction {}.", names.0, l),
+        (None, Some(r)) =>
+            println!("Only {} ran instruction {}.", names.1, r),
+        (None, None) => println!("Bus traffic differs."),
+    }
+    println!("\t{:>7}: {} at {}", names.0, dis("left"),
+             at(&diff["left_cycle"]));
+    println!("\t{:>7}: {} at {}", names.1, dis("right"),
+             at(&diff["right_cycle"]));
+    let empty = Vec::new();
+    let left = diff["left"].as_array().unwrap_or(&empty);
+    let right = diff["right"].as_array().unwrap_or(&empty);
+    let first = diff["left_cycle"].as_u64().or(diff["right_cycle"].as_u64())
+        .unwrap_or(0);
+    let label = |side: &str, n: usize| {
+        diff[side].get(n).and_then(Value::as_str).unwrap_or("").to_owned()
+    };
+    for n in 0 .. left.len().max(right.len()) {
+        let l = left.get(n).and_then(Value::as_str);
+        let r = right.get(n).and_then(Value::as_str);
+        let (l_label, r_label) = (label("left_labels", n),
+                                  label("right_labels", n));
+        let what = if l_label == r_label || r_label.is_empty() { l_label }
+                   else if l_label.is_empty() { r_label }
+                   else { format!("{} / {}", l_label, r_label) };
+        println!("\t{}{:>7}  {:>7} - {:>7}  {}",
+                 if l != r { "*" } else { " " }, first + n as u64,
+                 l.unwrap_or("-------"), r.unwrap_or("-------"), what);
+    }
+}
+
+fn print_differences(names: &(String, String), differences: &[Value]) {
+    let mut printed_instructions = 0;
+    for diff in differences {
+        let (l, r) = (&diff["left"], &diff["right"]);
+        match diff["field"].as_str().unwrap_or("") {
+            "num_cycles" if diff["max_cycles"].is_u64() => {
+                println!("Test runs for too long. (Ran for {} cycles)",
+                         diff["num_cycles"]);
+            },
+            "num_cycles" if diff["side"].is_string() => {
+                let name = if diff["side"] == "left" { &names.0 }
+                           else { &names.1 };
+                println!("{}'s trace stops {} cycles before its job did.",
+                         name, diff["untraced_cycles"]);
+            },
+            "num_cycles" => {
+                println!("Cycle counts differ.");
+                println!("\t{:>7}: {}\n\t{:>7}: {}", names.0, l, names.1, r);
+            },
+            "last_pc" => {
+                println!("Final PCs differ.");
+                println!("\t{:>7}: {}\n\t{:>7}: {}", names.0,
+                         show_pc(l, &diff["left_symbol"]), names.1,
+                         show_pc(r, &diff["right_symbol"]));
+            },
+            "termination_cause" => {
+                println!("Termination causes differ.");
+                println!("\t{:>7}: {}\n\t{:>7}: {}", names.0,
+                         l.as_str().unwrap_or(""), names.1,
+                         r.as_str().unwrap_or(""));
+            },
+            "serial_out_data" => {
+                println!("Serial outputs differ.");
+                let first = diff["first_difference"].as_u64().unwrap_or(0)
+                    as usize;
+                if diff["first_difference"].is_u64() {
+                    println!("\tfirst difference at byte {}", first);
+                }
+                println!("\t{:>7}: {}\n\t{:>7}: {}", names.0,
+                         show_serial(l, first), names.1,
+                         show_serial(r, first));
+            },
+            "cycles" => {
+                if printed_instructions == MAX_PRINTED_INSTRUCTIONS {
+                    println!("(and more; use --json to see them all)");
+                }
+                if printed_instructions < MAX_PRINTED_INSTRUCTIONS {
+                    print_instruction(names, diff);
+                }
+                printed_instructions += 1;
+            },
+            field => {
+                println!("{} differs.", field);
+                println!("\t{:>7}: {}\n\t{:>7}: {}", names.0, l, names.1, r);
+            },
+        }
+    }
+}
+
+// Compares two responses field by field, and their traces instruction by
+// instruction. Exits with 0 if they match, 1 if they don't, or 2 if they
+// couldn't be compared.
+pub fn compare_main(argv0: &str, args: &[String]) -> i32 {
+    let options = match parse_options(args) {
+        Some(x) => x,
+        None => return usage(argv0),
+    };
+    let (left, right) = match (load_response(&options.paths[0]),
+                               load_response(&options.paths[1])) {
+        (Ok(l), Ok(r)) => (l, r),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("{}", e);
+            return EXIT_TROUBLE
+        },
+    };
+    let symbols = match options.symbols_path.as_ref().map(|x| {
+        Symbols::load(x)
+    }) {
+        Some(Ok(x)) => Some(x),
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            return EXIT_TROUBLE
+        },
+        None => None,
+    };
+    let differences = compare(&options, symbols.as_ref(), &left, &right);
+    if options.json {
+        println!("{}", json!({
+            "identical": differences.is_empty(),
+            "left": options.names.0,
+            "right": options.names.1,
+            "differences": differences,
+        }));
+    }
+    else {
+        print_differences(&options.names, &differences);
+    }
+    if differences.is_empty() { 0 } else { EXIT_DIFFERENT }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // NOPs at `pc` onward.
+    fn nops(pc: u16, n: u16) -> Vec<String> {
+        (pc .. pc + n).flat_map(|x| {
+            vec![format!("F{:04X}EA", x), format!("7{:04X}EA", x + 1)]
+        }).collect()
+    }
+
+    fn cycles(x: &[&str]) -> Vec<String> {
+        x.iter().map(|x| x.to_string()).collect()
+    }
+
+    fn result(cycles: Vec<String>) -> JobResult {
+        JobResult {
+            num_cycles: (cycles.len() + FIRST_REPORTED_CYCLE) as u32,
+            last_pc: Some(0x0200),
+            last_pc_symbol: None,
+            termination_cause: "limit".to_owned(),
+            serial_out_data: None,
+            cycles,
+            disasm: None,
+            emulator_regs: None,
+            board: None,
+            baud_rate: None,
+        }
+    }
+
+    fn differences(extra: &[&str], left: &JobResult, right: &JobResult)
+                   -> Vec<Value> {
+        let mut args: Vec<String> = extra.iter().map(|x| x.to_string())
+            .collect();
+        args.extend(vec!["l.json".to_owned(), "r.json".to_owned()]);
+        compare(&parse_options(&args).unwrap(), None, left, right)
+    }
+
+    // Which instructions each side's differences were in.
+    fn pairs(differences: &[Value]) -> Vec<(Value, Value)> {
+        differences.iter().map(|x| {
+            assert_eq!(x["field"], "cycles");
+            (x["left_instruction"].clone(), x["right_instruction"].clone())
+        }).collect()
+    }
+
+    #[test]
+    fn identical() {
+        let x = result(nops(0x0200, 10));
+        assert!(differences(&[], &x, &x.clone()).is_empty());
+    }
+
+    #[test]
+    fn one_cycle() {
+        let left = result(nops(0x0200, 10));
+        let mut right = left.clone();
+        right.cycles[7] = "70204FF".to_owned();
+        let x = differences(&[], &left, &right);
+        assert_eq!(pairs(&x), [(json!(3), json!(3))]);
+        assert_eq!(x[0]["left_cycle"], 11);
+        assert_eq!(x[0]["differing"], json!([1]));
+    }
+
+    #[test]
+    fn extra_interrupt() {
+        let left = result(nops(0x0200, 10));
+        // an IRQ at $0205, two NOPs and an RTI, and back to $0205
+        let mut right = nops(0x0200, 5);
+        right.extend(cycles(&["F0205EA", "70205EA", "601FF02", "601FE05",
+                              "601FD20", "5FFFE00", "5FFFF03"]));
+        right.extend(nops(0x0300, 2));
+        right.extend(cycles(&["F030240", "7030340", "701FC00", "701FD20",
+                              "701FE05", "701FF02"]));
+        right.extend(nops(0x0205, 5));
+        let x = differences(&[], &left, &result(right));
+        assert_eq!(x[0]["field"], "num_cycles");
+        let x = &x[1..];
+        assert_eq!(pairs(x), [(Value::Null, json!(5)),
+                               (Value::Null, json!(6)),
+                               (Value::Null, json!(7)),
+                               (Value::Null, json!(8))]);
+        assert_eq!(x[0]["right_disasm"], "(IRQ)");
+    }
+
+    #[test]
+    fn never_resyncs() {
+        let left = result(nops(0x0200, 10));
+        let right = result(nops(0x1000, 10));
+        let x = differences(&[], &left, &right);
+        let expected: Vec<(Value, Value)> = (0 .. 10)
+            .map(|n| (json!(n), json!(n))).collect();
+        assert_eq!(pairs(&x), expected);
+    }
+
+    #[test]
+    fn too_long() {
+        let x = result(nops(0x0200, 10));
+        assert!(differences(&["--max-cycles", "25"], &x, &x).is_empty());
+        assert_eq!(differences(&["--max-cycles", "24"], &x, &x),
+                   [json!({"field": "num_cycles", "side": "left",
+                           "max_cycles": 24, "num_cycles": 25})]);
+        // an untraced left side can't be held to it
+        let untraced = result(Vec::new());
+        assert!(differences(&["--max-cycles", "1"], &untraced, &untraced)
+                .is_empty());
+        // nor can one that ended differently, which is a difference anyway
+        let mut right = x.clone();
+        right.termination_cause = "brk".to_owned();
+        let y = differences(&["--max-cycles", "24"], &x, &right);
+        assert_eq!(y.len(), 1);
+        assert_eq!(y[0]["field"], "termination_cause");
+    }
+
+    #[test]
+    fn exit_statuses() {
+        let dir = std::env::temp_dir()
+            .join(format!("65test-compare-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_owned();
+        let same = result(nops(0x0200, 10));
+        let mut different = same.clone();
+        different.last_pc = Some(0x0300);
+        for (name, x) in [("a", &same), ("b", &same), ("c", &different)]
+            .iter() {
+            fs::write(path(name), serde_json::to_string(x).unwrap()).unwrap();
+        }
+        let status = |args: &[String]| compare_main("65test", args);
+        assert_eq!(status(&[path("a"), path("b")]), 0);
+        assert_eq!(status(&[path("a"), path("c")]), EXIT_DIFFERENT);
+        assert_eq!(status(&[path("a"), path("missing")]), EXIT_TROUBLE);
+        assert_eq!(status(&["--bogus".to_owned(), path("a"), path("b")]),
+                   EXIT_TROUBLE);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
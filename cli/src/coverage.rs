@@ -0,0 +1,107 @@
+use std::fs::File;
+use std::io;
+use serde_json::Value;
+use test65::JobResult;
+use test65::coverage::Coverage;
+
+// Exit statuses, as compare has them.
+const EXIT_MISSING: i32 = 1;
+const EXIT_TROUBLE: i32 = 2;
+
+struct Options {
+    json: bool,
+    // opcodes that must have been executed
+    require: Vec<u8>,
+    paths: Vec<String>,
+}
+
+fn usage(argv0: &str) -> i32 {
+    eprintln!("Usage: {} coverage [--json] [--require OPCODES] \
+               file.json...\n\
+               \n\
+               Totals up the opcodes executed in the traces of some \
+               responses, and in\n\
+               coverage reports from rustcore --coverage. --require takes a \
+               list of\n\
+               opcodes in hex, separated by commas, and exits with status 1 \
+               if any of\n\
+               them were never executed.",
+              argv0);
+    EXIT_TROUBLE
+}
+
+fn parse_options(args: &[String]) -> Option<Options> {
+    let mut ret = Options { json: false, require: Vec::new(),
+                            paths: Vec::new() };
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--json" => ret.json = true,
+            "--require" => {
+                for x in it.next()?.split(',').map(str::trim)
+                    .filter(|x| !x.is_empty()) {
+                    ret.require.push(u8::from_str_radix(x, 16).ok()?);
+                }
+            },
+            x if x.starts_with("-") => return None,
+            x => ret.paths.push(x.to_owned()),
+        }
+    }
+    if ret.paths.is_empty() { return None }
+    Some(ret)
+}
+
+// A response contributes its trace; a coverage report, its counts.
+fn add_file(coverage: &mut Coverage, path: &str) -> Result<(), String> {
+    let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+    let value: Value = serde_json::from_reader(io::BufReader::new(file))
+        .map_err(|e| format!("{}: {}", path, e))?;
+    if value.get("opcodes").is_some() {
+        let other: Coverage = serde_json::from_value(value)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        coverage.merge(&other);
+    }
+    else {
+        let result: JobResult = serde_json::from_value(value)
+            .map_err(|e| format!("{}: {}", path, e))?;
+        if result.cycles.is_empty() {
+            eprintln!("{}: no cycles in this response (was show_cycles on?)",
+                      path);
+        }
+        coverage.add_trace(&result.cycles);
+    }
+    Ok(())
+}
+
+// Prints the opcode coverage of some traces, and checks it against
+// --require.
+pub fn coverage_main(argv0: &str, args: &[String]) -> i32 {
+    let options = match parse_options(args) {
+        Some(x) => x,
+        None => return usage(argv0),
+    };
+    let mut coverage = Coverage::new();
+    for path in options.paths.iter() {
+        if let Err(e) = add_file(&mut coverage, path) {
+            eprintln!("{}", e);
+            return EXIT_TROUBLE
+        }
+    }
+    if options.json {
+        println!("{}", serde_json::to_string(&coverage)
+                 .expect("coverage always serializes"));
+    }
+    else {
+        let stdout = io::stdout();
+        let _ = coverage.write_report(&mut stdout.lock());
+    }
+    let missing: Vec<String> = options.require.iter()
+        .filter(|&&x| coverage.opcodes[x as usize].executed == 0)
+        .map(|&x| format!("${:02X} {}", x, coverage.opcodes[x as usize]
+                          .mnemonic)).collect();
+    if missing.is_empty() { 0 }
+    else {
+        eprintln!("Required, but never executed: {}", missing.join(", "));
+        EXIT_MISSING
+    }
+}
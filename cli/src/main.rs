@@ -0,0 +1,248 @@
+extern crate base64;
+extern crate crc;
+extern crate serde_json;
+extern crate test65;
+extern crate ureq;
+
+mod compare;
+mod coverage;
+mod submit;
+
+use std::io;
+use std::path::Path;
+use test65::{capture, discover, Job, JobResult};
+use test65::comm::Comm;
+use test65::symbols::Symbols;
+use test65::trace::{self, Cycle, FIRST_REPORTED_CYCLE};
+
+// Exit statuses, mirroring the 4xx/5xx split in API.md.
+const EXIT_BAD_JOB: i32 = 1;
+const EXIT_HARDWARE_FAILURE: i32 = 2;
+
+fn usage(argv0: &str) -> i32 {
+    eprintln!("Usage: {} run [--trace | --disasm] job.json\n       \
+               {} submit --url URL [options] job.json...\n       \
+               {} compare [options] left.json right.json\n       \
+               {} coverage [options] response.json...\n       \
+               {} disasm [--symbols FILE] response.json\n       \
+               {} vcd job.json response.json\n       \
+               {} discover [PORT...]\n       \
+               {} replay capture.cap job.json\n\
+               \n\
+               A path of - reads from standard input.",
+              argv0, argv0, argv0, argv0, argv0, argv0, argv0, argv0);
+    EXIT_BAD_JOB
+}
+
+fn load_job(job_path: &str) -> Result<Job, String> {
+    let result = if job_path == "-" {
+        let stdin = io::stdin();
+        let stdin = stdin.lock();
+        test65::get_job(stdin)
+    }
+    else {
+        let file = std::fs::File::open(job_path)
+            .map_err(|e| format!("{}: {}", job_path, e))?;
+        test65::get_job(io::BufReader::new(file))
+    };
+    result.map_err(|e| format!("{}: {}", job_path, e))
+}
+
+fn load_response(response_path: &str) -> Result<JobResult, String> {
+    let result = if response_path == "-" {
+        let stdin = io::stdin();
+        let stdin = stdin.lock();
+        serde_json::from_reader(stdin)
+    }
+    else {
+        let file = std::fs::File::open(response_path)
+            .map_err(|e| format!("{}: {}", response_path, e))?;
+        serde_json::from_reader(io::BufReader::new(file))
+    };
+    result.map_err(|e| format!("{}: {}", response_path, e))
+}
+
+fn print_result(result: &JobResult) {
+    println!("{}", serde_json::to_string(result)
+             .expect("results always serialize"));
+}
+
+// Writes the cycle strings out as a table, one cycle per line.
+fn print_trace(out: &mut dyn io::Write, cycles: &[String]) -> io::Result<()> {
+    writeln!(out, "  cycle  addr  data  what")?;
+    for (n, cycle) in cycles.iter().enumerate() {
+        match Cycle::parse(cycle) {
+            Some(x) => writeln!(out, "{:>7}  ${:04X}  ${:02X}  {}",
+                                n + FIRST_REPORTED_CYCLE, x.addr, x.data,
+                                x.describe_type())?,
+            None => writeln!(out, "{:>7}  {:?}  (garbled)",
+                             n + FIRST_REPORTED_CYCLE, cycle)?,
+        }
+    }
+    Ok(())
+}
+
+// Runs a job on a local board, as the CGI script would, and prints the
+// response JSON. The trace (or the listing), if asked for, goes to stderr so
+// the JSON can still be redirected into a file.
+fn run_main(argv0: &str, args: &[String]) -> i32 {
+    let mut trace = false;
+    let mut listing = false;
+    let mut job_path = None;
+    for arg in args.iter() {
+        match arg.as_str() {
+            "--trace" => trace = true,
+            "--disasm" => listing = true,
+            x if job_path.is_none() => job_path = Some(x),
+            _ => return usage(argv0),
+        }
+    }
+    let job_path = match job_path {
+        Some(x) => x,
+        None => return usage(argv0),
+    };
+    let job = match load_job(job_path) {
+        Ok(job) => job,
+        Err(e) => {
+            eprintln!("{}", e);
+            return EXIT_BAD_JOB
+        },
+    };
+    match test65::run_on_hardware(&job) {
+        Ok(result) => {
+            let stderr = io::stderr();
+            if listing {
+                let _ = trace::write_listing_from(&mut stderr.lock(),
+                                                  &result.cycles,
+                                                  FIRST_REPORTED_CYCLE,
+                                                  job.symbols.as_ref());
+            }
+            else if trace {
+                let _ = print_trace(&mut stderr.lock(), &result.cycles);
+            }
+            print_result(&result);
+            0
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => {
+            eprintln!("Your job cannot run on this hardware:\n\n{}", e);
+            EXIT_BAD_JOB
+        },
+        Err(e) => {
+            eprintln!("giving up on job: {}", e);
+            EXIT_HARDWARE_FAILURE
+        },
+    }
+}
+
+// Prints the trace in a response as a disassembled listing, labeling
+// addresses from a symbol file, if given.
+fn disasm_main(response_path: &str, symbols_path: Option<&str>) -> i32 {
+    let result = match load_response(response_path) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            return EXIT_BAD_JOB
+        },
+    };
+    let symbols = match symbols_path.map(Symbols::load) {
+        Some(Ok(x)) => Some(x),
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            return EXIT_BAD_JOB
+        },
+        None => None,
+    };
+    if result.cycles.is_empty() {
+        eprintln!("{}: no cycles in this response (was show_cycles on?)",
+                  response_path);
+        return EXIT_BAD_JOB
+    }
+    let stdout = io::stdout();
+    let _ = trace::write_listing_from(&mut stdout.lock(), &result.cycles,
+                                      FIRST_REPORTED_CYCLE, symbols.as_ref());
+    0
+}
+
+// Writes a response's trace, and its job's signal toggles, as a VCD.
+fn vcd_main(job_path: &str, response_path: &str) -> i32 {
+    let job = match load_job(job_path) {
+        Ok(job) => job,
+        Err(e) => {
+            eprintln!("{}", e);
+            return EXIT_BAD_JOB
+        },
+    };
+    let result = match load_response(response_path) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            return EXIT_BAD_JOB
+        },
+    };
+    let stdout = io::stdout();
+    match test65::vcd::write_vcd(&mut stdout.lock(), &job, &result) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            EXIT_HARDWARE_FAILURE
+        },
+    }
+}
+
+// Plays a capture back as if it were the board, running the job that was
+// captured. Useful for reproducing protocol failures after the fact.
+fn replay_main(capture_path: &str, job_path: &str) -> i32 {
+    let job = match load_job(job_path) {
+        Ok(job) => job,
+        Err(e) => {
+            eprintln!("{}", e);
+            return EXIT_BAD_JOB
+        },
+    };
+    let replay = match capture::ReplayPort::load(Path::new(capture_path)) {
+        Ok(replay) => replay,
+        Err(e) => {
+            eprintln!("{}: {}", capture_path, e);
+            return EXIT_BAD_JOB
+        },
+    };
+    // a capture was made without the board's settings, so replay it so too
+    let mut open = || Comm::with_port(Box::new(replay.clone()));
+    let result = test65::attempt_job(&job, Path::new(capture_path), &mut None,
+                                     &mut open);
+    // the same status `run` would have given, unless the replay diverged
+    let status = match result {
+        Ok(result) => {
+            print_result(&result);
+            0
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => {
+            eprintln!("replayed job cannot run on this hardware: {}", e);
+            EXIT_BAD_JOB
+        },
+        Err(e) => {
+            eprintln!("replayed job failed: {}", e);
+            EXIT_HARDWARE_FAILURE
+        },
+    };
+    if replay.diverged() { EXIT_HARDWARE_FAILURE } else { status }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let argv0 = args.get(0).map(String::as_str).unwrap_or("65test");
+    let status = match args.get(1).map(String::as_str) {
+        Some("run") => run_main(argv0, &args[2..]),
+        Some("submit") => submit::submit_main(argv0, &args[2..]),
+        Some("compare") => compare::compare_main(argv0, &args[2..]),
+        Some("coverage") => coverage::coverage_main(argv0, &args[2..]),
+        Some("disasm") if args.len() == 3 => disasm_main(&args[2], None),
+        Some("disasm") if args.len() == 5 && args[2] == "--symbols" =>
+            disasm_main(&args[4], Some(&args[3])),
+        Some("vcd") if args.len() == 4 => vcd_main(&args[2], &args[3]),
+        Some("discover") => discover::discover_main(argv0, &args[2..]),
+        Some("replay") if args.len() == 4 => replay_main(&args[2], &args[3]),
+        _ => usage(argv0),
+    };
+    std::process::exit(status)
+}
@@ -0,0 +1,385 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
+use crc::{crc32, crc64};
+use test65::JobResult;
+use super::{EXIT_BAD_JOB, EXIT_HARDWARE_FAILURE};
+
+const DEFAULT_CONCURRENCY: usize = 4;
+const DEFAULT_RETRIES: u32 = 3;
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+// A job can run for 10,000,000 cycles and wait behind other jobs for a board.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+
+struct Options {
+    url: String,
+    output: Option<PathBuf>,
+    concurrency: usize,
+    retries: u32,
+    cache: Option<PathBuf>,
+    // how long to wait before the first retry; each one after waits twice
+    // as long as the last
+    first_backoff: Duration,
+    jobs: Vec<PathBuf>,
+}
+
+enum Failure {
+    // The server (or we) rejected the job; sending it again won't help.
+    BadJob(String),
+    // Something went wrong on the server or on the way there.
+    Server(String),
+}
+
+impl Failure {
+    fn exit_status(&self) -> i32 {
+        match self {
+            Failure::BadJob(_) => EXIT_BAD_JOB,
+            Failure::Server(_) => EXIT_HARDWARE_FAILURE,
+        }
+    }
+    fn message(&self) -> &str {
+        match self {
+            Failure::BadJob(x) | Failure::Server(x) => x,
+        }
+    }
+}
+
+fn usage(argv0: &str) -> i32 {
+    eprintln!("Usage: {} submit --url URL [-o hw.json] [--jobs N] \
+               [--retries N] [--cache DIR] job.json...\n\
+               \n\
+               Each foo.job.json is written to foo.hw.json, unless -o is \
+               given\n\
+               (which only works with a single job).",
+              argv0);
+    EXIT_BAD_JOB
+}
+
+fn parse_options(args: &[String]) -> Option<Options> {
+    let mut ret = Options {
+        url: String::new(), output: None,
+        concurrency: DEFAULT_CONCURRENCY, retries: DEFAULT_RETRIES,
+        cache: None, first_backoff: FIRST_BACKOFF, jobs: Vec::new(),
+    };
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        match arg.as_str() {
+            "--url" => ret.url = it.next()?.clone(),
+            "-o" => ret.output = Some(it.next()?.into()),
+            "--jobs" => ret.concurrency = it.next()?.parse().ok()
+                .filter(|&x| x > 0)?,
+            "--retries" => ret.retries = it.next()?.parse().ok()?,
+            "--cache" => ret.cache = Some(it.next()?.into()),
+            x if x.starts_with("-") => return None,
+            x => ret.jobs.push(x.into()),
+        }
+    }
+    if ret.url.is_empty() || ret.jobs.is_empty()
+    || (ret.output.is_some() && ret.jobs.len() != 1) {
+        return None
+    }
+    Some(ret)
+}
+
+fn output_path(job_path: &Path) -> PathBuf {
+    let name = job_path.to_string_lossy();
+    if name.ends_with(".job.json") {
+        format!("{}.hw.json", &name[.. name.len() - 9]).into()
+    }
+    else {
+        format!("{}.hw.json", name).into()
+    }
+}
+
+// Responses are cached by a hash of the server's URL and the job's exact
+// bytes, so the same job sent to a different server is sent again.
+fn cache_path(dir: &Path, url: &str, job: &[u8]) -> PathBuf {
+    let mut key = Vec::with_capacity(url.len() + 1 + job.len());
+    key.extend_from_slice(url.as_bytes());
+    key.push(0);
+    key.extend_from_slice(job);
+    dir.join(format!("{:016x}{:08x}.json", crc64::checksum_ecma(&key),
+                     crc32::checksum_ieee(&key)))
+}
+
+// Written alongside and renamed into place, so a failure never leaves behind
+// a truncated file (or takes away a good one).
+fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".new");
+    fs::write(&temp_path, data)?;
+    fs::rename(&temp_path, path)
+}
+
+fn post(url: &str, job: &[u8]) -> Result<String, Failure> {
+    let response = ureq::post(url)
+        .set("Content-Type", "application/json")
+        .timeout(REQUEST_TIMEOUT)
+        .send_bytes(job);
+    match response {
+        Ok(response) => response.into_string().map_err(|e| {
+            Failure::Server(format!("reading response: {}", e))
+        }),
+        Err(ureq::Error::Status(code, response)) => {
+            let body = response.into_string().unwrap_or_default();
+            let what = format!("server said {}: {}", code, body.trim());
+            if code >= 400 && code < 500 { Err(Failure::BadJob(what)) }
+            else { Err(Failure::Server(what)) }
+        },
+        Err(e) => Err(Failure::Server(e.to_string())),
+    }
+}
+
+fn submit_one(options: &Options, job_path: &Path) -> Result<(), Failure> {
+    let job = fs::read(job_path)
+        .map_err(|e| Failure::BadJob(e.to_string()))?;
+    // Don't bother the server with a job it's only going to reject.
+    test65::get_job(&job[..])
+        .map_err(|e| Failure::BadJob(e.to_string()))?;
+    let out_path = options.output.clone()
+        .unwrap_or_else(|| output_path(job_path));
+    let cache_path = options.cache.as_ref()
+        .map(|dir| cache_path(dir, &options.url, &job));
+    if let Some(cache_path) = cache_path.as_ref() {
+        if let Ok(response) = fs::read(cache_path) {
+            return write_atomically(&out_path, &response)
+                .map_err(|e| Failure::Server(e.to_string()))
+        }
+    }
+    let mut backoff = options.first_backoff;
+    let mut attempt = 0;
+    let response = loop {
+        let result = post(&options.url, &job).and_then(|response| {
+            // A 200 that isn't a response record is the server's fault.
+            match serde_json::from_str::<JobResult>(&response) {
+                Ok(_) => Ok(response),
+                Err(e) => Err(Failure::Server(format!("bad response: {}",
+                                                      e))),
+            }
+        });
+        match result {
+            Ok(response) => break response,
+            Err(Failure::Server(e)) => {
+                if attempt >= options.retries {
+                    return Err(Failure::Server(e))
+                }
+                eprintln!("{:?}: {}, retrying in {:?}", job_path, e,
+                          backoff);
+            },
+            Err(e) => return Err(e),
+        }
+        attempt += 1;
+        std::thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    };
+    if let Some(cache_path) = cache_path.as_ref() {
+        // a cache that can't be written to just doesn't cache
+        let _ = fs::create_dir_all(cache_path.parent().unwrap());
+        let _ = write_atomically(cache_path, response.as_bytes());
+    }
+    write_atomically(&out_path, response.as_bytes())
+        .map_err(|e| Failure::Server(e.to_string()))
+}
+
+// Sends every job to the server, at most `--jobs` at a time. Returns the
+// worst status of any job.
+fn submit_all(options: Options) -> i32 {
+    let options = Arc::new(options);
+    let queue: Arc<Mutex<VecDeque<PathBuf>>>
+        = Arc::new(Mutex::new(options.jobs.iter().cloned().collect()));
+    let status = Arc::new(AtomicI32::new(0));
+    let workers: Vec<_> = (0 .. options.concurrency.min(options.jobs.len()))
+        .map(|_| {
+            let options = options.clone();
+            let queue = queue.clone();
+            let status = status.clone();
+            std::thread::spawn(move || loop {
+                let job_path = match queue.lock().unwrap().pop_front() {
+                    Some(x) => x,
+                    None => break,
+                };
+                if let Err(e) = submit_one(&options, &job_path) {
+                    eprintln!("{:?}: {}", job_path, e.message());
+                    status.fetch_max(e.exit_status(), Ordering::SeqCst);
+                }
+            })
+        }).collect();
+    for worker in workers {
+        worker.join().expect("submission thread panicked");
+    }
+    status.load(Ordering::SeqCst)
+}
+
+pub fn submit_main(argv0: &str, args: &[String]) -> i32 {
+    match parse_options(args) {
+        Some(options) => submit_all(options),
+        None => usage(argv0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicBool, AtomicUsize};
+    use std::thread::JoinHandle;
+    use std::time::Instant;
+
+    const JOB: &str = r#"{"init": [{"base": 512, "data": "utf8:x"}]}"#;
+    const RESPONSE: &str
+        = r#"{"num_cycles": 30, "termination_cause": "limit"}"#;
+
+    // A stand-in for the CGI script on 127.0.0.1. It gives the scripted
+    // responses in order, then 500s, and counts the requests it gets.
+    struct Server {
+        url: String,
+        requests: Arc<AtomicUsize>,
+        stop: Arc<AtomicBool>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    fn answer(mut stream: TcpStream, code: u16, body: &str) {
+        stream.set_nonblocking(false).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim().to_ascii_lowercase();
+            if line.is_empty() { break }
+            if line.starts_with("content-length:") {
+                length = line[15..].trim().parse().unwrap();
+            }
+        }
+        let mut job = vec![0; length];
+        reader.read_exact(&mut job).unwrap();
+        assert_eq!(job, JOB.as_bytes());
+        write!(stream, "HTTP/1.1 {} Whatever\r\nContent-Length: {}\r\n\
+                        Connection: close\r\n\r\n{}",
+               code, body.len(), body).unwrap();
+    }
+
+    impl Server {
+        fn new(script: Vec<(u16, &'static str)>) -> Server {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let url = format!("http://{}/65test.cgi",
+                              listener.local_addr().unwrap());
+            let requests = Arc::new(AtomicUsize::new(0));
+            let stop = Arc::new(AtomicBool::new(false));
+            let thread = {
+                let (requests, stop) = (requests.clone(), stop.clone());
+                std::thread::spawn(move || {
+                    let mut script = script.into_iter();
+                    while !stop.load(Ordering::SeqCst) {
+                        match listener.accept() {
+                            Ok((stream, _)) => {
+                                requests.fetch_add(1, Ordering::SeqCst);
+                                let (code, body) = script.next()
+                                    .unwrap_or((500, "out of script"));
+                                answer(stream, code, body);
+                            },
+                            Err(_) => std::thread::sleep(
+                                Duration::from_millis(5)),
+                        }
+                    }
+                })
+            };
+            Server { url, requests, stop, thread: Some(thread) }
+        }
+        fn requests(&self) -> usize {
+            self.requests.load(Ordering::SeqCst)
+        }
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::SeqCst);
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    // A directory of its own for each test, holding foo.job.json.
+    fn setup(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("65test-submit-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("foo.job.json"), JOB).unwrap();
+        dir
+    }
+
+    fn options(server: &Server, dir: &Path) -> Options {
+        Options {
+            url: server.url.clone(), output: None, concurrency: 1,
+            retries: 3, cache: Some(dir.join("cache")),
+            first_backoff: Duration::from_millis(20),
+            jobs: vec![dir.join("foo.job.json")],
+        }
+    }
+
+    #[test]
+    fn caches_success() {
+        let dir = setup("cache");
+        let server = Server::new(vec![(200, RESPONSE)]);
+        let out = dir.join("foo.hw.json");
+        assert_eq!(submit_all(options(&server, &dir)), 0);
+        assert_eq!(fs::read_to_string(&out).unwrap(), RESPONSE);
+        assert_eq!(server.requests(), 1);
+        fs::remove_file(&out).unwrap();
+        assert_eq!(submit_all(options(&server, &dir)), 0);
+        assert_eq!(fs::read_to_string(&out).unwrap(), RESPONSE);
+        assert_eq!(server.requests(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bad_job_is_not_retried() {
+        let dir = setup("4xx");
+        let server = Server::new(vec![(400, "no such board"),
+                                      (200, RESPONSE)]);
+        assert_eq!(submit_all(options(&server, &dir)), EXIT_BAD_JOB);
+        assert_eq!(server.requests(), 1);
+        assert!(!dir.join("foo.hw.json").exists());
+        // nor cached
+        assert_eq!(fs::read_dir(dir.join("cache"))
+                   .map(|x| x.count()).unwrap_or(0), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn server_errors_are_retried() {
+        let dir = setup("5xx");
+        let server = Server::new(vec![(503, "busy"), (502, "gateway"),
+                                      (200, RESPONSE)]);
+        let start = Instant::now();
+        assert_eq!(submit_all(options(&server, &dir)), 0);
+        // backed off 20ms, then 40ms
+        assert!(start.elapsed() >= Duration::from_millis(60));
+        assert_eq!(server.requests(), 3);
+        assert_eq!(fs::read_to_string(dir.join("foo.hw.json")).unwrap(),
+                   RESPONSE);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn server_errors_run_out_of_retries() {
+        let dir = setup("5xx-fail");
+        let server = Server::new(vec![(500, "on fire")]);
+        let mut options = options(&server, &dir);
+        options.retries = 2;
+        assert_eq!(submit_all(options), EXIT_HARDWARE_FAILURE);
+        assert_eq!(server.requests(), 3);
+        assert!(!dir.join("foo.hw.json").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,73 @@
+// How fast the emulator harness can run and trace a 10,000,000-cycle job.
+// The "CPU" here does as little as possible (an opcode fetch and an operand
+// read per instruction), so nearly all of the time is the harness's: cycle
+// reporting, flips, and formatting the trace.
+
+extern crate test65;
+
+use std::io;
+use std::time::Instant;
+use test65::Profile;
+use test65::harness::{Cpu, Pins, System};
+
+const NUM_CYCLES: u32 = 10000000;
+
+struct Nop {
+    // None until the reset vector has been pulled
+    pc: Option<u16>,
+}
+
+impl Pins for Nop {
+    fn set_irq(&mut self, _: bool) {}
+    fn set_nmi(&mut self, _: bool) {}
+    fn set_overflow(&mut self) {}
+    fn reset(&mut self) { self.pc = None }
+    fn get_s(&self) -> u8 { 0xFF }
+}
+
+impl Cpu for Nop {
+    fn step(&mut self, system: &mut System) {
+        let pc = match self.pc {
+            Some(x) => x,
+            None => {
+                let lo = system.read_vector(self, 0xFFFC);
+                let hi = system.read_vector(self, 0xFFFD);
+                self.pc = Some((hi as u16) << 8 | lo as u16);
+                return
+            },
+        };
+        system.read_opcode(self, pc);
+        system.read(self, pc.wrapping_add(1));
+        // stay out of zero page, the stack, and the vectors, where an opcode
+        // fetch would end the job
+        self.pc = Some(if pc >= 0xFF00 { 0x0200 } else { pc + 1 });
+    }
+}
+
+fn job() -> test65::Job {
+    let job = format!(r#"{{"init": [], "show_cycles": true,
+                          "max_cycles": {}, "terminate_on_brk": false,
+                          "terminate_on_infinite_loop": false,
+                          "irq": [1000, 2000], "nmi": [3000, 4000]}}"#,
+                      NUM_CYCLES);
+    test65::get_job_for(job.as_bytes(), Profile::Local).unwrap()
+}
+
+fn bench(what: &str, trace_out: Option<Box<dyn io::Write>>) {
+    let job = job();
+    let start = Instant::now();
+    let result = test65::run_on_emulator_with(&job, &mut Nop { pc: None },
+                                              Profile::Local, trace_out)
+        .unwrap();
+    let elapsed = start.elapsed();
+    let seconds = elapsed.as_secs() as f64
+        + elapsed.subsec_nanos() as f64 / 1e9;
+    assert_eq!(result.num_cycles, NUM_CYCLES);
+    println!("{}: {} cycles in {:.3}s, {:.0} cycles per second", what,
+             result.num_cycles, seconds, result.num_cycles as f64 / seconds);
+}
+
+fn main() {
+    bench("trace in the response", None);
+    bench("trace streamed out", Some(Box::new(io::sink())));
+}
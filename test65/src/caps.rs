@@ -0,0 +1,165 @@
+use std::io;
+
+pub const FEATURE_CYCLE_REPORTS: u32 = 0x0001;
+pub const FEATURE_SERIAL_IN: u32 = 0x0002;
+pub const FEATURE_SERIAL_OUT: u32 = 0x0004;
+pub const FEATURE_NMI: u32 = 0x0008;
+pub const FEATURE_IRQ: u32 = 0x0010;
+pub const FEATURE_RDY: u32 = 0x0020;
+pub const FEATURE_SO: u32 = 0x0040;
+pub const FEATURE_RES: u32 = 0x0080;
+pub const FEATURE_BAUD_RATE: u32 = 0x0100;
+
+// What the connected firmware can do, as reported by a `0x43` packet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    pub firmware_version: u16,
+    pub protocol_version: u8,
+    pub max_ranges: u8,
+    pub serial_in_buffer_size: u16,
+    pub max_physical_packet_size: u16,
+    pub max_logical_packet_size: u16,
+    pub max_flips: u16,
+    pub serial_out_limit: u32,
+    pub termination_flags: u8,
+    pub features: u32,
+}
+
+// Firmware that predates the capabilities query dies when asked. This is
+// what it could do.
+pub const LEGACY: Capabilities = Capabilities {
+    firmware_version: 1,
+    protocol_version: 0,
+    max_ranges: 8,
+    serial_in_buffer_size: 32,
+    max_physical_packet_size: 120,
+    max_logical_packet_size: 1200,
+    max_flips: 120,
+    serial_out_limit: super::SERIAL_OUT_LIMIT,
+    termination_flags: 0x3F,
+    features: FEATURE_CYCLE_REPORTS | FEATURE_SERIAL_IN | FEATURE_SERIAL_OUT
+        | FEATURE_NMI | FEATURE_IRQ | FEATURE_RDY | FEATURE_SO | FEATURE_RES,
+};
+
+impl Capabilities {
+    pub fn parse(buf: &[u8]) -> io::Result<Capabilities> {
+        if buf.len() < 21 {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      "capabilities packet too short"))
+        }
+        let u16_at = |n: usize| ((buf[n] as u16) << 8) | (buf[n+1] as u16);
+        let u32_at = |n: usize| ((buf[n] as u32) << 24)
+            | ((buf[n+1] as u32) << 16)
+            | ((buf[n+2] as u32) << 8)
+            | (buf[n+3] as u32);
+        let ret = Capabilities {
+            firmware_version: u16_at(0),
+            protocol_version: buf[2],
+            max_ranges: buf[3],
+            serial_in_buffer_size: u16_at(4),
+            max_physical_packet_size: u16_at(6),
+            max_logical_packet_size: u16_at(8),
+            max_flips: u16_at(10),
+            serial_out_limit: u32_at(12),
+            termination_flags: buf[16],
+            features: u32_at(17),
+        };
+        ret.packet_sizes()?;
+        Ok(ret)
+    }
+    // The inverse of `parse`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut ret = Vec::with_capacity(21);
+        ret.extend_from_slice(&[(self.firmware_version >> 8) as u8,
+                                self.firmware_version as u8,
+                                self.protocol_version,
+                                self.max_ranges]);
+        for &x in [self.serial_in_buffer_size, self.max_physical_packet_size,
+                   self.max_logical_packet_size, self.max_flips].iter() {
+            ret.extend_from_slice(&[(x >> 8) as u8, x as u8]);
+        }
+        let serial_out_limit = self.serial_out_limit;
+        ret.extend_from_slice(&[(serial_out_limit >> 24) as u8,
+                                (serial_out_limit >> 16) as u8,
+                                (serial_out_limit >> 8) as u8,
+                                serial_out_limit as u8,
+                                self.termination_flags]);
+        let features = self.features;
+        ret.extend_from_slice(&[(features >> 24) as u8,
+                                (features >> 16) as u8,
+                                (features >> 8) as u8,
+                                features as u8]);
+        ret
+    }
+    // The physical and logical packet sizes, if they make sense.
+    pub fn packet_sizes(&self) -> io::Result<(usize, usize)> {
+        let physical = self.max_physical_packet_size as usize;
+        let logical = self.max_logical_packet_size as usize;
+        if physical == 0 || physical > 255 || logical < physical {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      format!("invalid packet sizes in \
+                                               capabilities (physical {}, \
+                                               logical {})",
+                                              physical, logical)))
+        }
+        Ok((physical, logical))
+    }
+    pub fn has(&self, feature: u32) -> bool {
+        self.features & feature == feature
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Firmware version 3, protocol 1, 16 ranges, a 64-byte serial buffer, 120/1200-byte packets, 240 flips, a 4096-byte
+    // serial output limit, every termination condition and every feature.
+    const PAYLOAD: [u8; 21] = [
+        0x00, 0x03, 0x01, 0x10, 0x00, 0x40, 0x00, 0x78, 0x04, 0xB0,
+        0x00, 0xF0, 0x00, 0x00, 0x10, 0x00, 0x3F, 0x00, 0x00, 0x01, 0xFF,
+    ];
+
+    #[test]
+    fn parse() {
+        let caps = Capabilities::parse(&PAYLOAD).unwrap();
+        assert_eq!(caps, Capabilities {
+            firmware_version: 3,
+            protocol_version: 1,
+            max_ranges: 16,
+            serial_in_buffer_size: 64,
+            max_physical_packet_size: 120,
+            max_logical_packet_size: 1200,
+            max_flips: 240,
+            serial_out_limit: 4096,
+            termination_flags: 0x3F,
+            features: 0x01FF,
+        });
+        assert!(caps.has(FEATURE_BAUD_RATE));
+        assert!(caps.has(FEATURE_NMI | FEATURE_RES));
+        assert_eq!(caps.to_bytes(), &PAYLOAD[..]);
+        assert_eq!(Capabilities::parse(&LEGACY.to_bytes()).unwrap(), LEGACY);
+        // later firmware may add fields on the end
+        let mut longer = PAYLOAD.to_vec();
+        longer.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(Capabilities::parse(&longer).unwrap(), caps);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(Capabilities::parse(&[]).is_err());
+        assert!(Capabilities::parse(&PAYLOAD[..20]).is_err());
+        let with_sizes = |physical: u16, logical: u16| {
+            let mut buf = PAYLOAD;
+            buf[6] = (physical >> 8) as u8;
+            buf[7] = physical as u8;
+            buf[8] = (logical >> 8) as u8;
+            buf[9] = logical as u8;
+            Capabilities::parse(&buf)
+        };
+        assert!(with_sizes(255, 255).is_ok());
+        assert!(with_sizes(0, 1200).is_err());
+        assert!(with_sizes(256, 1200).is_err());
+        assert!(with_sizes(120, 119).is_err());
+    }
+}
@@ -0,0 +1,465 @@
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crc::crc32;
+use super::cobs;
+use super::comm::Port;
+
+// If present, names a directory to write a capture of every job into.
+pub const CAPTURE_DIR_PATH: &str = ".65test_capture_dir.txt";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction { HostToDevice, DeviceToHost }
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Direction::HostToDevice => "H>D",
+            Direction::DeviceToHost => "D>H",
+        }
+    }
+}
+
+// Chops a one-directional byte stream into physical units: COBS frames,
+// three-byte ACK sequences, and runs of zeroes (shutdowns and deaths).
+enum SplitState { Idle, Zeros(usize), Frame }
+struct Splitter {
+    state: SplitState,
+    buf: Vec<u8>,
+}
+
+impl Splitter {
+    fn new() -> Splitter {
+        Splitter { state: SplitState::Idle, buf: Vec::new() }
+    }
+    fn push(&mut self, byte: u8, out: &mut Vec<Vec<u8>>) {
+        match self.state {
+            SplitState::Idle => {
+                self.buf.push(byte);
+                self.state = if byte == 0 { SplitState::Zeros(1) }
+                else { SplitState::Frame };
+            },
+            SplitState::Zeros(n) => {
+                if byte == 0 {
+                    self.buf.push(byte);
+                    self.state = SplitState::Zeros(n+1);
+                }
+                else if n >= 2 {
+                    // leading extra zeroes get their own unit
+                    if n > 2 {
+                        out.push(self.buf.drain(.. n-2).collect());
+                    }
+                    self.buf.push(byte);
+                    out.push(std::mem::replace(&mut self.buf, Vec::new()));
+                    self.state = SplitState::Idle;
+                }
+                else {
+                    out.push(std::mem::replace(&mut self.buf, vec![byte]));
+                    self.state = SplitState::Frame;
+                }
+            },
+            SplitState::Frame => {
+                self.buf.push(byte);
+                if byte == 0 {
+                    out.push(std::mem::replace(&mut self.buf, Vec::new()));
+                    self.state = SplitState::Idle;
+                }
+            },
+        }
+    }
+    fn flush(&mut self, out: &mut Vec<Vec<u8>>) {
+        if !self.buf.is_empty() {
+            out.push(std::mem::replace(&mut self.buf, Vec::new()));
+        }
+        self.state = SplitState::Idle;
+    }
+}
+
+fn describe_ack(dir: Direction, typ: u8) -> String {
+    match (dir, typ) {
+        (_, 1) => "ack".to_owned(),
+        (_, 2) => "fragment ack".to_owned(),
+        (_, 3) => "ack, role reversal".to_owned(),
+        (Direction::DeviceToHost, 4) => "wakeup part 1".to_owned(),
+        (Direction::DeviceToHost, 5) => "wakeup part 2".to_owned(),
+        (Direction::DeviceToHost, 6) => "wakeup part 3".to_owned(),
+        (_, 7) => "heartbeat".to_owned(),
+        (_, 8) => "echo response".to_owned(),
+        (_, x) => format!("unknown ack type {:02X}", x),
+    }
+}
+
+fn describe_frame(frame: &[u8]) -> String {
+    let mut it = frame.iter().cloned();
+    let decoded: Vec<u8> = cobs::In::new(&mut it).collect();
+    if decoded.len() < 6 {
+        return format!("bad frame ({} bytes decoded)", decoded.len())
+    }
+    let typ = decoded[0];
+    let length = decoded[1] as usize;
+    let crc_at = decoded.len() - 4;
+    let crc_rx = ((decoded[crc_at] as u32) << 24)
+        | ((decoded[crc_at+1] as u32) << 16)
+        | ((decoded[crc_at+2] as u32) << 8)
+        | (decoded[crc_at+3] as u32);
+    let crc_calc = crc32::checksum_ieee(&decoded[..crc_at]);
+    let crc = if crc_rx == crc_calc { "CRC ok".to_owned() }
+    else { format!("CRC error (read {:08X}, calculated {:08X})",
+                   crc_rx, crc_calc) };
+    let what = match (typ, length) {
+        (0, 0) => "keepalive".to_owned(),
+        (0, _) => "fragment".to_owned(),
+        (255, _) => "echo request".to_owned(),
+        (x, _) => format!("packet type {:02X}", x),
+    };
+    let length_note = if length != crc_at - 2 {
+        format!(" (but carries {})", crc_at - 2)
+    } else { String::new() };
+    format!("{}, length {}{}, {}", what, length, length_note, crc)
+}
+
+fn describe(dir: Direction, unit: &[u8]) -> String {
+    if unit[0] != 0 {
+        describe_frame(unit)
+    }
+    else if unit.len() == 3 && unit[1] == 0 && unit[2] != 0 {
+        describe_ack(dir, unit[2])
+    }
+    else if unit.iter().all(|&x| x == 0) {
+        format!("{} zeroes", unit.len())
+    }
+    else {
+        "garbage".to_owned()
+    }
+}
+
+// A port that logs every physical unit that passes through it, one line per
+// unit:
+//
+//     <seconds since open> <H>D or D>H> <description> | <bytes in hex>
+//
+// Baud rate changes are noted on lines starting with `#`.
+pub struct Capture<P: Port> {
+    port: P,
+    out: File,
+    start: Instant,
+    host: Splitter,
+    device: Splitter,
+}
+
+impl<P: Port> Capture<P> {
+    pub fn new(port: P, out: File) -> Capture<P> {
+        Capture { port, out, start: Instant::now(),
+                  host: Splitter::new(), device: Splitter::new() }
+    }
+    fn log(&mut self, dir: Direction, units: Vec<Vec<u8>>) {
+        let elapsed = self.start.elapsed();
+        let elapsed = elapsed.as_secs() as f64
+            + elapsed.subsec_micros() as f64 / 1000000.0;
+        for unit in units.iter() {
+            let hex: Vec<String> = unit.iter()
+                .map(|x| format!("{:02X}", x)).collect();
+            // a capture that fails to write shouldn't break the job
+            let _ = write!(self.out, "{:11.6} {} {} | {}\n",
+                           elapsed, dir.as_str(), describe(dir, unit),
+                           hex.join(" "));
+        }
+    }
+}
+
+impl<P: Port> Read for Capture<P> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.port.read(buf)?;
+        let mut units = Vec::new();
+        for &byte in buf[..len].iter() {
+            self.device.push(byte, &mut units);
+        }
+        self.log(Direction::DeviceToHost, units);
+        Ok(len)
+    }
+}
+
+impl<P: Port> Write for Capture<P> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.port.write(buf)?;
+        let mut units = Vec::new();
+        for &byte in buf[..len].iter() {
+            self.host.push(byte, &mut units);
+        }
+        self.log(Direction::HostToDevice, units);
+        Ok(len)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+
+impl<P: Port> Port for Capture<P> {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.port.set_timeout(timeout)
+    }
+    fn set_baud_rate(&mut self, rate: u32) -> io::Result<()> {
+        let _ = write!(self.out, "# switching to {} baud\n", rate);
+        self.port.set_baud_rate(rate)
+    }
+}
+
+impl<P: Port> Drop for Capture<P> {
+    fn drop(&mut self) {
+        let mut units = Vec::new();
+        self.host.flush(&mut units);
+        self.log(Direction::HostToDevice, units);
+        let mut units = Vec::new();
+        self.device.flush(&mut units);
+        self.log(Direction::DeviceToHost, units);
+    }
+}
+
+// Returns a fresh path to capture a job on the given board into, if captures
+// are enabled.
+pub fn new_capture_path(port_path: &Path) -> io::Result<Option<PathBuf>> {
+    let dir = match File::open(CAPTURE_DIR_PATH) {
+        Ok(file) => {
+            let mut line = String::new();
+            io::BufReader::new(file).read_line(&mut line)?;
+            PathBuf::from(line.trim())
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0));
+    let board: String = port_path.to_string_lossy().chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    Ok(Some(dir.join(format!("{}.{:06}-{}{}.cap", now.as_secs(),
+                             now.subsec_micros(), std::process::id(),
+                             board))))
+}
+
+// Captures are appended to, so that every time the port is opened during one
+// attempt ends up in the same capture.
+pub fn open_capture(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+struct ReplayState {
+    units: Vec<(Direction, Vec<u8>)>,
+    next: usize,
+    consumed: usize,
+    diverged: bool,
+}
+
+impl ReplayState {
+    fn diverge(&mut self, what: String) {
+        if !self.diverged {
+            eprintln!("replay diverged from the capture at unit {}: {}",
+                      self.next + 1, what);
+            self.diverged = true;
+        }
+    }
+}
+
+// Plays the device's side of a capture back to a `Comm`. Clones share their
+// position, so a job that reopens the port picks up where it left off, as it
+// did when captured.
+#[derive(Clone)]
+pub struct ReplayPort {
+    state: Rc<RefCell<ReplayState>>,
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.split_whitespace().map(|x| u8::from_str_radix(x, 16).ok()).collect()
+}
+
+impl ReplayPort {
+    pub fn load(path: &Path) -> io::Result<ReplayPort> {
+        let mut units = Vec::new();
+        for (n, line) in io::BufReader::new(File::open(path)?).lines()
+            .enumerate() {
+            let line = line?;
+            if line.trim().is_empty() || line.starts_with("#") { continue }
+            let mut fields = line.split_whitespace();
+            let dir = match fields.nth(1) {
+                Some("H>D") => Direction::HostToDevice,
+                Some("D>H") => Direction::DeviceToHost,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                               format!("line {}: no \
+                                                        direction", n+1))),
+            };
+            let bytes = line.rsplitn(2, '|').next().and_then(parse_hex);
+            match bytes {
+                Some(ref bytes) if !bytes.is_empty() => (),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                               format!("line {}: bad bytes",
+                                                       n+1))),
+            }
+            units.push((dir, bytes.unwrap()));
+        }
+        Ok(ReplayPort::from_units(units))
+    }
+    pub fn from_units(units: Vec<(Direction, Vec<u8>)>) -> ReplayPort {
+        ReplayPort {
+            state: Rc::new(RefCell::new(ReplayState {
+                units, next: 0, consumed: 0, diverged: false,
+            })),
+        }
+    }
+    pub fn diverged(&self) -> bool {
+        self.state.borrow().diverged
+    }
+}
+
+impl Read for ReplayPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        match state.units.get(state.next) {
+            Some(&(Direction::DeviceToHost, ref bytes)) => {
+                let len = buf.len().min(bytes.len() - state.consumed);
+                buf[..len].copy_from_slice(&bytes[state.consumed
+                                                  .. state.consumed + len]);
+                state.consumed += len;
+                if state.consumed == bytes.len() {
+                    state.next += 1;
+                    state.consumed = 0;
+                }
+                Ok(len)
+            },
+            // the device is waiting for the host, so nothing arrives
+            _ => Err(io::Error::new(io::ErrorKind::TimedOut,
+                                    "replay: nothing from the device")),
+        }
+    }
+}
+
+impl Write for ReplayPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.borrow_mut();
+        let expected = match state.units.get(state.next) {
+            Some(&(Direction::HostToDevice, ref bytes)) => Some(bytes.clone()),
+            _ => None,
+        };
+        match expected {
+            Some(bytes) => {
+                if &bytes[..] != buf {
+                    state.diverge(format!("host wrote {:02X?}, capture has \
+                                           {:02X?}", buf, bytes));
+                }
+                state.next += 1;
+            },
+            None => state.diverge(format!("host wrote {:02X?} when the \
+                                           capture has none", buf)),
+        }
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+impl Port for ReplayPort {
+    fn set_timeout(&mut self, _: Duration) -> io::Result<()> { Ok(()) }
+    fn set_baud_rate(&mut self, _: u32) -> io::Result<()> { Ok(()) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    // The device's half of a conversation, handed over a few bytes at a time
+    // so that units get split across reads.
+    struct Script {
+        device: VecDeque<u8>,
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(3).min(self.device.len());
+            for x in buf[..len].iter_mut() {
+                *x = self.device.pop_front().unwrap();
+            }
+            Ok(len)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { Ok(buf.len()) }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    impl Port for Script {
+        fn set_timeout(&mut self, _: Duration) -> io::Result<()> { Ok(()) }
+        fn set_baud_rate(&mut self, _: u32) -> io::Result<()> { Ok(()) }
+    }
+
+    fn frame(typ: u8, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![typ, data.len() as u8];
+        packet.extend_from_slice(data);
+        let crc = crc32::checksum_ieee(&packet);
+        packet.extend_from_slice(&[(crc >> 24) as u8, (crc >> 16) as u8,
+                                   (crc >> 8) as u8, crc as u8]);
+        cobs::encode(&packet)
+    }
+
+    fn read_n(port: &mut dyn Read, n: usize) -> Vec<u8> {
+        let mut buf = vec![0; n];
+        port.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn capture_and_replay() {
+        let query = frame(0x0A, &[]);
+        let caps = frame(0x43, &[1, 2, 3]);
+        let init = frame(0x01, b"hello");
+        let mut device = vec![0, 0, 3];
+        device.extend_from_slice(&caps);
+        let death = [0; 5];
+        device.extend_from_slice(&death);
+        let path = std::env::temp_dir()
+            .join(format!("65test-capture-{}.cap", std::process::id()));
+        {
+            let port = Script { device: device.into_iter().collect() };
+            let mut cap = Capture::new(port, File::create(&path).unwrap());
+            cap.write_all(&query).unwrap();
+            read_n(&mut cap, 3 + caps.len());
+            cap.write_all(&[0, 0, 3]).unwrap();
+            cap.write_all(&init).unwrap();
+            read_n(&mut cap, death.len());
+        }
+        let mut text = String::new();
+        File::open(&path).unwrap().read_to_string(&mut text).unwrap();
+        let described: Vec<&str> = text.lines().map(|x| {
+            x.splitn(2, "H>D ").nth(1)
+                .or_else(|| x.splitn(2, "D>H ").nth(1)).unwrap()
+                .split(" | ").next().unwrap()
+        }).collect();
+        assert_eq!(described, [
+            "packet type 0A, length 0, CRC ok",
+            "ack, role reversal",
+            "packet type 43, length 3, CRC ok",
+            "ack, role reversal",
+            "packet type 01, length 5, CRC ok",
+            "5 zeroes",
+        ]);
+        let mut replay = ReplayPort::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        replay.write_all(&query).unwrap();
+        let mut expected = vec![0, 0, 3];
+        expected.extend_from_slice(&caps);
+        assert_eq!(read_n(&mut replay, expected.len()), expected);
+        replay.write_all(&[0, 0, 3]).unwrap();
+        assert!(!replay.diverged());
+        // the host sends something other than what was captured
+        replay.write_all(&frame(0x01, b"world")).unwrap();
+        assert!(replay.diverged());
+        assert_eq!(read_n(&mut replay, death.len()), death);
+        let mut buf = [0; 1];
+        assert_eq!(replay.read(&mut buf).unwrap_err().kind(),
+                   io::ErrorKind::TimedOut);
+    }
+}
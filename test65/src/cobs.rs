@@ -0,0 +1,281 @@
+use std::io;
+use std::io::prelude::*;
+use std::hash::Hasher;
+use crc::{crc32,Hasher32};
+
+// Consistent Overhead Byte Stuffing. Every frame is a series of blocks, each
+// a code byte N (1-255) followed by N-1 non-zero bytes, and the frame ends
+// with a 0. A block with N < 255 stands for its bytes plus a zero, except that
+// the last block's zero is left off. A block with N = 255 has no zero after
+// it, which is how runs of more than 254 non-zero bytes get through.
+//
+// Both directions can hash the decoded bytes as they pass. That's CRC-32 by
+// default (which is what the 65test wire protocol uses), but any `Hasher`
+// will do.
+
+const MAX_BLOCK: usize = 254;
+
+// The most bytes that `len` bytes could encode to, including the terminator.
+pub fn max_encoded_len(len: usize) -> usize {
+    len + len / MAX_BLOCK + 2
+}
+
+// Encodes `data` into a complete frame, including the terminating 0.
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut o = Out::new(Vec::with_capacity(max_encoded_len(data.len())));
+    o.write_all(data).expect("writing to a Vec failed");
+    o.finish().expect("writing to a Vec failed")
+}
+
+// Decodes one frame. The terminating 0 is optional; anything after it is
+// ignored.
+pub fn decode(frame: &[u8]) -> io::Result<Vec<u8>> {
+    let mut it = frame.iter().cloned().chain(std::iter::once(0));
+    let mut i = In::new(&mut it);
+    let ret: Vec<u8> = (&mut i).collect();
+    if i.is_error() {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "COBS framing error"))
+    }
+    else { Ok(ret) }
+}
+
+#[derive(Debug)]
+enum InState {
+    // waiting for a code byte; true if the previous block implies a zero
+    Code(bool),
+    // bytes left in this block, and whether it implies a zero afterward
+    Data(u8, bool),
+    Eof, Err,
+}
+
+// Streaming decoder. Pulls encoded bytes from an iterator and yields decoded
+// ones until the end of the frame, or until something goes wrong; check
+// `is_error` to tell which.
+pub struct In<'a, T: Iterator<Item=u8> + 'a, H: Hasher = crc32::Digest> {
+    it: &'a mut T,
+    state: InState,
+    hasher: H,
+}
+
+impl<'a, T: Iterator<Item=u8> + 'a> In<'a, T> {
+    pub fn new(it: &'a mut T) -> In<'a, T> {
+        In::with_hasher(it, crc32::Digest::new(crc32::IEEE))
+    }
+    pub fn crc(&self) -> u32 {
+        self.hasher.sum32()
+    }
+}
+
+impl<'a, T: Iterator<Item=u8> + 'a, H: Hasher> In<'a, T, H> {
+    pub fn with_hasher(it: &'a mut T, hasher: H) -> In<'a, T, H> {
+        In { it, state: InState::Code(false), hasher }
+    }
+    pub fn hasher(&self) -> &H { &self.hasher }
+    pub fn into_hasher(self) -> H { self.hasher }
+    pub fn is_error(&self) -> bool {
+        match self.state { InState::Err => true, _ => false }
+    }
+    fn emit(&mut self, x: u8) -> Option<u8> {
+        self.hasher.write_u8(x);
+        Some(x)
+    }
+}
+
+impl<'a, T: Iterator<Item=u8> + 'a, H: Hasher> Iterator for In<'a, T, H> {
+    type Item = u8;
+    fn next(&mut self) -> Option<u8> {
+        loop {
+            match self.state {
+                InState::Code(zero_pending) => {
+                    match self.it.next() {
+                        None => self.state = InState::Err,
+                        // a pending zero at the end of the frame is dropped
+                        Some(0) => self.state = InState::Eof,
+                        Some(code) => {
+                            self.state = InState::Data(code - 1, code != 255);
+                            if zero_pending { return self.emit(0) }
+                        },
+                    }
+                },
+                InState::Data(0, zero_after) => {
+                    self.state = InState::Code(zero_after)
+                },
+                InState::Data(rem, zero_after) => {
+                    match self.it.next() {
+                        None | Some(0) => self.state = InState::Err,
+                        Some(x) => {
+                            self.state = InState::Data(rem - 1, zero_after);
+                            return self.emit(x)
+                        },
+                    }
+                },
+                InState::Eof | InState::Err => return None,
+            }
+        }
+    }
+}
+
+impl<'a, T: Iterator<Item=u8> + 'a, H: Hasher> Read for In<'a, T, H> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut i = 0;
+        while i < buf.len() {
+            match self.next() {
+                None => break,
+                Some(x) => {
+                    buf[i] = x;
+                    i = i + 1;
+                },
+            }
+        }
+        Ok(i)
+    }
+}
+
+// Streaming encoder. Bytes written to it come out of the inner writer a block
+// at a time; `finish` writes the last block and the terminator.
+pub struct Out<W: Write, H: Hasher = crc32::Digest> {
+    inner: W,
+    block: Vec<u8>,
+    hasher: H,
+}
+
+impl<W: Write> Out<W> {
+    pub fn new(inner: W) -> Out<W> {
+        Out::with_hasher(inner, crc32::Digest::new(crc32::IEEE))
+    }
+    pub fn crc(&self) -> u32 {
+        self.hasher.sum32()
+    }
+}
+
+impl<W: Write, H: Hasher> Out<W, H> {
+    pub fn with_hasher(inner: W, hasher: H) -> Out<W, H> {
+        let mut block = Vec::with_capacity(MAX_BLOCK + 1);
+        block.push(0);
+        Out { inner, block, hasher }
+    }
+    pub fn hasher(&self) -> &H { &self.hasher }
+    // Writes without hashing, e.g. for the checksum itself.
+    pub fn write_unhashed(&mut self, buf: &[u8]) -> io::Result<()> {
+        for &c in buf {
+            if c == 0 {
+                self.end_block()?;
+            }
+            else {
+                self.block.push(c);
+                if self.block.len() == MAX_BLOCK + 1 {
+                    self.end_block()?;
+                }
+            }
+        }
+        Ok(())
+    }
+    pub fn finish(mut self) -> io::Result<W> {
+        self.end_block()?;
+        self.inner.write_all(&[0])?;
+        Ok(self.inner)
+    }
+    fn end_block(&mut self) -> io::Result<()> {
+        self.block[0] = self.block.len() as u8;
+        self.inner.write_all(&self.block[..])?;
+        self.block.truncate(1);
+        Ok(())
+    }
+}
+
+impl<W: Write, H: Hasher> Write for Out<W, H> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hasher.write(buf);
+        self.write_unhashed(buf)?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift, so the cases are the same every run
+    struct Rng(u32);
+    impl Rng {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+    }
+
+    fn round_trip(data: &[u8]) {
+        let frame = encode(data);
+        assert!(frame.len() <= max_encoded_len(data.len()));
+        assert_eq!(frame.iter().position(|&x| x == 0),
+                   Some(frame.len() - 1), "zero inside {:?}", frame);
+        assert_eq!(decode(&frame).unwrap(), data);
+    }
+
+    #[test]
+    fn random_lengths() {
+        let mut rng = Rng(0x6502);
+        for _ in 0 .. 500 {
+            let len = rng.next() as usize % 1000;
+            // mostly non-zero, so that long blocks turn up
+            let data: Vec<u8> = (0 .. len).map(|_| {
+                let x = rng.next();
+                if x % 8 == 0 { 0 } else { (x >> 8) as u8 }
+            }).collect();
+            round_trip(&data);
+        }
+    }
+
+    #[test]
+    fn long_runs() {
+        for &len in [253, 254, 255, 256, 508, 509, 1000].iter() {
+            let run = vec![0xA5; len];
+            round_trip(&run);
+            for &(before, after) in [(true, false), (false, true),
+                                     (true, true)].iter() {
+                let mut data = Vec::new();
+                if before { data.push(0) }
+                data.extend_from_slice(&run);
+                if after { data.push(0) }
+                round_trip(&data);
+            }
+        }
+    }
+
+    #[test]
+    fn zeros() {
+        round_trip(&[]);
+        round_trip(&[0]);
+        round_trip(&[0, 0, 0]);
+        round_trip(&[0, 1, 2]);
+        round_trip(&[1, 2, 0]);
+        round_trip(&[1, 0, 0, 2]);
+        round_trip(&[0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn streaming_crc() {
+        let mut rng = Rng(0xC02);
+        for _ in 0 .. 50 {
+            let len = rng.next() as usize % 600;
+            let data: Vec<u8> = (0 .. len).map(|_| rng.next() as u8)
+                .collect();
+            let expected = crc32::checksum_ieee(&data);
+            let mut o = Out::new(Vec::new());
+            o.write_all(&data).unwrap();
+            assert_eq!(o.crc(), expected);
+            let frame = o.finish().unwrap();
+            let mut it = frame.into_iter();
+            let mut i = In::new(&mut it);
+            let decoded: Vec<u8> = (&mut i).collect();
+            assert!(!i.is_error());
+            assert_eq!(decoded, data);
+            assert_eq!(i.crc(), expected);
+        }
+    }
+}
@@ -7,10 +7,61 @@ use std::path::Path;
 use std::time::Duration;
 use std::borrow::BorrowMut;
 use super::cobs;
+use super::caps::Capabilities;
 
 pub const BUF_SIZE: usize = 128;
 pub const MAX_PHYSICAL_PACKET_SIZE: usize = 120;
 pub const MAX_LOGICAL_PACKET_SIZE: usize = MAX_PHYSICAL_PACKET_SIZE * 10;
+pub const DEFAULT_BAUD_RATE: u32 = 115200;
+// 250000 baud divides evenly into the clocks of both the SAM3X and the 16U2
+// that bridges its Programming Port to USB, so it's the fastest rate that
+// doesn't suffer from rounding.
+pub const PREFERRED_BAUD_RATE: u32 = 250000;
+// The device gives up on a new baud rate after 500ms. We wait longer than
+// that before giving up ourselves.
+const BAUD_CONFIRM_TIMEOUT: Duration = Duration::from_millis(1000);
+// type 0xFF, length 0, CRC, already COBS encoded
+const ECHO_REQUEST: [u8; 8] = [0x02,0xFF,0x05,0xD2,0xFD,0xEF,0x8D,0x00];
+
+// Anything that can stand in for the serial port. Reads must time out
+// (returning an error or zero bytes) if no data arrives within the timeout.
+pub trait Port: Read + Write {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+    fn set_baud_rate(&mut self, rate: u32) -> io::Result<()>;
+}
+
+impl Port for serial::SystemPort {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        SerialPort::set_timeout(self, timeout)?;
+        Ok(())
+    }
+    fn set_baud_rate(&mut self, rate: u32) -> io::Result<()> {
+        self.reconfigure(&|settings: &mut SerialPortSettings| {
+            settings.set_baud_rate(serial::BaudRate::from_speed(rate
+                                                                as usize))
+        })?;
+        Ok(())
+    }
+}
+
+pub fn open_port(path: &Path) -> io::Result<serial::SystemPort> {
+    let mut port = match serial::open(path) {
+        Ok(port) => port,
+        Err(_) =>
+            return Err(io::Error::new(io::ErrorKind::Other,
+                                      format!("Unable to open serial port \
+                                               {:?}", path.to_str())))
+    };
+    port.reconfigure(&|settings: &mut SerialPortSettings| {
+        settings.set_baud_rate(serial::Baud115200)?;
+        settings.set_char_size(serial::Bits8);
+        settings.set_parity(serial::ParityNone);
+        settings.set_stop_bits(serial::Stop1);
+        settings.set_flow_control(serial::FlowNone);
+        Ok(())
+    })?;
+    Ok(port)
+}
 
 #[derive(Debug)]
 enum Mode {
@@ -18,12 +69,16 @@ enum Mode {
 }
 
 pub struct Comm {
-    port: serial::SystemPort,
+    port: Box<dyn Port>,
     raw_len: u32,
     raw_consumed: u32,
     raw_buf: [u8; BUF_SIZE],
     mode: Mode,
     sent_ping: bool,
+    baud_rate: u32,
+    // as reported by the firmware's capabilities, once known
+    max_physical: usize,
+    max_logical: usize,
 }
 
 fn eof() -> io::Error {
@@ -31,23 +86,33 @@ fn eof() -> io::Error {
                    "unexpected EOF")
 }
 
+const UNEXPECTED_SHUTDOWN: &str = "unexpected shutdown";
+
+// The device answered with the death sequence instead of an ack.
+fn is_shutdown(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::InvalidData
+        && e.get_ref().map_or(false, |x| x.to_string() == UNEXPECTED_SHUTDOWN)
+}
+
+// Type, length, data, and CRC-32 of all three, COBS encoded.
+pub fn frame_packet(typ: u8, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut o = cobs::Out::new(Vec::with_capacity(
+        cobs::max_encoded_len(data.len() + 6)));
+    o.write_all(&[typ, data.len() as u8])?;
+    o.write_all(data)?;
+    let crc = o.crc();
+    o.write_unhashed(&[(crc >> 24) as u8,
+                       (crc >> 16) as u8,
+                       (crc >> 8) as u8,
+                       crc as u8])?;
+    o.finish()
+}
+
 impl Comm {
     pub fn new(path: &Path) -> io::Result<Comm> {
-        let mut port = match serial::open(path) {
-            Ok(port) => port,
-            Err(_) =>
-                return Err(io::Error::new(io::ErrorKind::Other,
-                                          format!("Unable to open serial port \
-                                                   {:?}", path.to_str())))
-        };
-        port.reconfigure(&|settings: &mut SerialPortSettings| {
-            settings.set_baud_rate(serial::Baud115200)?;
-            settings.set_char_size(serial::Bits8);
-            settings.set_parity(serial::ParityNone);
-            settings.set_stop_bits(serial::Stop1);
-            settings.set_flow_control(serial::FlowNone);
-            Ok(())
-        })?;
+        Comm::with_port(Box::new(open_port(path)?))
+    }
+    pub fn with_port(mut port: Box<dyn Port>) -> io::Result<Comm> {
         port.set_timeout(Duration::from_secs(1))?;
         let mut ret = Comm {
             port,
@@ -56,6 +121,9 @@ impl Comm {
             raw_consumed: 0,
             mode: Mode::Raw,
             sent_ping: false,
+            baud_rate: DEFAULT_BAUD_RATE,
+            max_physical: MAX_PHYSICAL_PACKET_SIZE,
+            max_logical: MAX_LOGICAL_PACKET_SIZE,
         };
         let mut valid_handshake = false;
         // Consume all input if we can, and try to get a wakeup sequence
@@ -194,6 +262,8 @@ impl Comm {
             _ => panic!("read_packet called from wrong mode ({:?})", self.mode)
         }
         buf.clear();
+        let max_physical = self.max_physical;
+        let max_logical = self.max_logical;
         loop {
             let packet_type;
             let length;
@@ -204,7 +274,7 @@ impl Comm {
                 match packet_type {
                     0 => {
                         if length != 0
-                        && length != MAX_PHYSICAL_PACKET_SIZE as u8 {
+                        && length as usize != max_physical {
                             return Err(io::Error::new(io::ErrorKind::Other,
                                                       "Invalid fragment \
                                                        length"))
@@ -218,13 +288,17 @@ impl Comm {
                         }
                     },
                     _ => {
-                        if length > MAX_PHYSICAL_PACKET_SIZE as u8 {
+                        if length as usize > max_physical {
                             return Err(io::Error::new(io::ErrorKind::Other,
                                                       "Physical packet too \
                                                        large"))
                         }
                     },
                 }
+                if buf.len() + length as usize > max_logical {
+                    return Err(io::Error::new(io::ErrorKind::Other,
+                                              "Logical packet too large"))
+                }
                 if length != 0 {
                     buf.reserve(length as usize);
                     let beg = buf.len();
@@ -296,26 +370,22 @@ impl Comm {
             Mode::Sender => (),
             _ => panic!("send_packet called from wrong mode ({:?})", self.mode)
         }
-        while data.len() > MAX_PHYSICAL_PACKET_SIZE {
-            let len = {
-                let mut o = cobs::Out::new(&mut self.raw_buf[..]);
-                o.write_all(&[0, MAX_PHYSICAL_PACKET_SIZE as u8])?;
-                o.write_all(&data[..MAX_PHYSICAL_PACKET_SIZE])?;
-                o.finish()?
-            };
-            self.port.write_all(&self.raw_buf[..len])?;
+        if data.len() > self.max_logical {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("{}-byte packet is larger than \
+                                               the device accepts ({})",
+                                              data.len(), self.max_logical)))
+        }
+        while data.len() > self.max_physical {
+            let frame = frame_packet(0, &data[..self.max_physical])?;
+            self.port.write_all(&frame)?;
             match self.next() { Some(0) => (), _ => return Err(eof()) }
             match self.next() { Some(0) => (), _ => return Err(eof()) }
             match self.next() { Some(2) => (), _ => return Err(eof()) }
-            data = &data[MAX_PHYSICAL_PACKET_SIZE..];
+            data = &data[self.max_physical..];
         }
-        let len = {
-            let mut o = cobs::Out::new(&mut self.raw_buf[..]);
-            o.write_all(&[typ, data.len() as u8])?;
-            o.write_all(data)?;
-            o.finish()?
-        };
-        self.port.write_all(&self.raw_buf[..len])?;
+        let frame = frame_packet(typ, data)?;
+        self.port.write_all(&frame)?;
         match self.next() { Some(0) => (), _ => {while let Some(_) = self.next() {} return Err(eof()) }}
         match self.next() { Some(0) => (), _ => return Err(eof()) }
         loop {
@@ -328,7 +398,7 @@ impl Comm {
                 },
                 Some(0) =>
                     return Err(io::Error::new(io::ErrorKind::InvalidData,
-                                              "unexpected shutdown")),
+                                              UNEXPECTED_SHUTDOWN)),
                 Some(_) =>
                     return Err(io::Error::new(io::ErrorKind::InvalidData,
                                               "protocol error")),
@@ -342,7 +412,92 @@ impl Comm {
         }
         Ok(())
     }
-    fn set_port_timeout(&mut self, duration: Duration) -> serial::Result<()> {
+    // None if the device died when asked, as firmware that predates the
+    // query does.
+    pub fn query_capabilities(&mut self) -> io::Result<Option<Capabilities>> {
+        let mut buf = Vec::new();
+        match self.send_packet(0x0A, &[], true) {
+            Err(ref e) if is_shutdown(e) => return Ok(None),
+            x => x?,
+        }
+        match self.read_packet(&mut buf)? {
+            0x43 => (),
+            x => return Err(io::Error::new(io::ErrorKind::Other,
+                                           format!("unexpected packet type \
+                                                    {:02X} in response to \
+                                                    capabilities query", x)))
+        }
+        self.ack_packet(true)?;
+        Capabilities::parse(&buf[..]).map(Some)
+    }
+    // Frames packets to fit what the firmware reported it can take.
+    pub fn set_packet_sizes(&mut self, caps: &Capabilities)
+                            -> io::Result<()> {
+        let (physical, logical) = caps.packet_sizes()?;
+        self.max_physical = physical;
+        self.max_logical = logical;
+        Ok(())
+    }
+    pub fn max_physical_packet_size(&self) -> usize { self.max_physical }
+    pub fn max_logical_packet_size(&self) -> usize { self.max_logical }
+    pub fn echo(&mut self) -> io::Result<()> {
+        match self.mode {
+            Mode::Sender => (),
+            _ => panic!("echo called from wrong mode ({:?})", self.mode)
+        }
+        self.port.write_all(&ECHO_REQUEST)?;
+        match self.next() { Some(0) => (), _ => return Err(eof()) }
+        match self.next() { Some(0) => (), _ => return Err(eof()) }
+        match self.next() {
+            Some(8) => Ok(()),
+            Some(_) => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "protocol error")),
+            None => Err(eof()),
+        }
+    }
+    pub fn baud_rate(&self) -> u32 { self.baud_rate }
+    // Asks the device to switch to a new baud rate, and confirms it with an
+    // echo. If that fails, we both go back to the default rate. Returns the
+    // rate we ended up at.
+    pub fn negotiate_baud_rate(&mut self, rate: u32) -> io::Result<u32> {
+        self.send_packet(0x0B, &[(rate >> 24) as u8,
+                                 (rate >> 16) as u8,
+                                 (rate >> 8) as u8,
+                                 rate as u8], false)?;
+        // give the device a moment to finish sending the ACK and switch
+        std::thread::sleep(Duration::from_millis(50));
+        self.port.set_baud_rate(rate)?;
+        self.discard_input();
+        self.set_port_timeout(BAUD_CONFIRM_TIMEOUT)?;
+        self.port.write_all(&ECHO_REQUEST)?;
+        let confirmed = self.expect_echo_response();
+        self.set_port_timeout(Duration::from_secs(5))?;
+        if confirmed {
+            self.baud_rate = rate;
+            return Ok(rate)
+        }
+        eprintln!("{} baud didn't work, falling back to {}", rate,
+                  DEFAULT_BAUD_RATE);
+        std::thread::sleep(BAUD_CONFIRM_TIMEOUT);
+        self.port.set_baud_rate(DEFAULT_BAUD_RATE)?;
+        self.discard_input();
+        self.baud_rate = DEFAULT_BAUD_RATE;
+        self.echo()?;
+        Ok(DEFAULT_BAUD_RATE)
+    }
+    // Deliberately doesn't use `next`, which would ping the device (at what
+    // may be the wrong baud rate) if it timed out.
+    fn expect_echo_response(&mut self) -> bool {
+        self.get_byte() == Some(0) && self.get_byte() == Some(0)
+            && self.get_byte() == Some(8)
+    }
+    fn discard_input(&mut self) {
+        self.raw_consumed = self.raw_len;
+        if self.set_port_timeout(Duration::from_millis(10)).is_ok() {
+            while let Some(_) = self.get_byte() {}
+        }
+    }
+    fn set_port_timeout(&mut self, duration: Duration) -> io::Result<()> {
         self.port.set_timeout(duration)
     }
     fn get_byte(&mut self) -> Option<u8> {
@@ -390,8 +545,7 @@ impl Iterator for Comm {
                         self.port.write_all(&[0,0,7]).is_ok();
                     },
                     Mode::Sender => {
-                        self.port.write_all(&[0x02,0xFF,0x05,0xD2,0xFD,0xEF,
-                                              0x8D,0x00]).is_ok();
+                        self.port.write_all(&ECHO_REQUEST).is_ok();
                     },
                     _ => return None,
                 }
@@ -408,3 +562,86 @@ impl Drop for Comm {
         self.port.write_all(&[0,0,0,0]).is_ok();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use super::super::capture::{Direction, ReplayPort};
+
+    const WAKEUP: [u8; 9] = [0, 0, 4, 0, 0, 5, 0, 0, 6];
+
+    // A replay that also keeps track of the baud rates it's set to.
+    struct Recording {
+        replay: ReplayPort,
+        rates: Rc<RefCell<Vec<u32>>>,
+    }
+
+    impl Read for Recording {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.replay.read(buf)
+        }
+    }
+
+    impl Write for Recording {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.replay.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> { self.replay.flush() }
+    }
+
+    impl Port for Recording {
+        fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+            self.replay.set_timeout(timeout)
+        }
+        fn set_baud_rate(&mut self, rate: u32) -> io::Result<()> {
+            RefCell::borrow_mut(&self.rates).push(rate);
+            self.replay.set_baud_rate(rate)
+        }
+    }
+
+    // Asks for 250000 baud, with the board answering the echo at that rate
+    // with `answer`, and (if that isn't a good one) falling back. Returns the
+    // rate we ended up at, and the rates the port was set to.
+    fn negotiate(answer: &[u8]) -> (u32, Vec<u32>) {
+        let request = frame_packet(0x0B, &[0x00, 0x03, 0xD0, 0x90]).unwrap();
+        let mut units = vec![
+            (Direction::DeviceToHost, WAKEUP.to_vec()),
+            (Direction::HostToDevice, request),
+            (Direction::DeviceToHost, vec![0, 0, 1]),
+            (Direction::HostToDevice, ECHO_REQUEST.to_vec()),
+        ];
+        if !answer.is_empty() {
+            units.push((Direction::DeviceToHost, answer.to_vec()));
+        }
+        if answer != [0, 0, 8] {
+            units.push((Direction::HostToDevice, ECHO_REQUEST.to_vec()));
+            units.push((Direction::DeviceToHost, vec![0, 0, 8]));
+        }
+        units.push((Direction::HostToDevice, vec![0, 0, 0, 0]));
+        let replay = ReplayPort::from_units(units);
+        let rates = Rc::new(RefCell::new(Vec::new()));
+        let port = Recording { replay: replay.clone(), rates: rates.clone() };
+        let mut comm = Comm::with_port(Box::new(port)).unwrap();
+        let rate = comm.negotiate_baud_rate(PREFERRED_BAUD_RATE).unwrap();
+        assert_eq!(comm.baud_rate(), rate);
+        std::mem::drop(comm);
+        assert!(!replay.diverged());
+        assert!(replay.clone().read(&mut [0]).is_err());
+        let rates = rates.borrow().clone();
+        (rate, rates)
+    }
+
+    #[test]
+    fn baud_rate() {
+        assert_eq!(negotiate(&[0, 0, 8]),
+                   (PREFERRED_BAUD_RATE, vec![PREFERRED_BAUD_RATE]));
+        let fell_back = (DEFAULT_BAUD_RATE,
+                         vec![PREFERRED_BAUD_RATE, DEFAULT_BAUD_RATE]);
+        // the echo came back garbled...
+        assert_eq!(negotiate(&[0xF8, 0x80, 0x00]), fell_back);
+        // ...or not at all
+        assert_eq!(negotiate(&[]), fell_back);
+    }
+}
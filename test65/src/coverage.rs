@@ -0,0 +1,306 @@
+// Which opcodes a trace executed, how often, and under which conditions that
+// change how an opcode behaves on the bus: crossing a page (indexed modes,
+// and taken branches), decimal mode (ADC and SBC), branches taken and not,
+// and an interrupt being taken right after. All of it is worked out from the
+// bus cycles alone, so it works the same for hardware and emulator traces.
+// Decimal mode shows up as the extra cycle the W65C02S spends on it, so an
+// ADC or SBC stalled by RDY can be mistaken for one in decimal mode.
+
+use std::io;
+use super::disasm::{self, Mode};
+use super::trace::{self, Cycle, OPCODE_READ};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OpcodeCoverage {
+    pub opcode: u8,
+    pub mnemonic: String,
+    pub executed: u64,
+    // Each of these is only present for the opcodes it applies to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub page_crossed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decimal: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch_taken: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch_not_taken: Option<u64>,
+    // followed immediately by an IRQ or NMI
+    pub interrupted: u64,
+}
+
+// Coverage of any number of traces. Feed it whole traces with `add_trace`,
+// or a cycle at a time with `add_cycle` and then `finish`. The last
+// instruction of each trace is left out, since a trace usually stops partway
+// through it (or on the opcode fetch that ended the job).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Coverage {
+    pub instructions: u64,
+    pub opcodes: Vec<OpcodeCoverage>,
+    // the last two instructions (or whatever came before the first), as
+    // cycle strings
+    #[serde(skip)]
+    previous: Vec<String>,
+    #[serde(skip)]
+    current: Vec<String>,
+}
+
+fn is_branch(opcode: u8) -> bool {
+    match disasm::mode(opcode) {
+        Mode::Relative | Mode::ZeroPageRelative => true,
+        _ => false,
+    }
+}
+
+fn is_indexed(opcode: u8) -> bool {
+    match disasm::mode(opcode) {
+        Mode::AbsoluteX | Mode::AbsoluteY | Mode::ZeroPageIndirectY => true,
+        _ => false,
+    }
+}
+
+fn is_decimal_capable(opcode: u8) -> bool {
+    let mnemonic = disasm::mnemonic(opcode);
+    mnemonic == "ADC" || mnemonic == "SBC"
+}
+
+// Cycles an ADC or SBC takes in binary mode, not crossing a page.
+fn binary_cycles(mode: Mode) -> usize {
+    match mode {
+        Mode::Immediate => 2,
+        Mode::ZeroPage => 3,
+        Mode::ZeroPageX | Mode::Absolute | Mode::AbsoluteX
+            | Mode::AbsoluteY => 4,
+        Mode::ZeroPageIndirect | Mode::ZeroPageIndirectY => 5,
+        _ => 6,
+    }
+}
+
+// The data of the cycles labeled `label`, as a little-endian word.
+fn labeled_word(parsed: &[Option<Cycle>], labels: &[String], label: &str)
+                -> Option<u16> {
+    let mut bytes = parsed.iter().zip(labels.iter())
+        .filter(|(_, l)| *l == label).filter_map(|(x, _)| x.as_ref());
+    let lo = bytes.next()?.data;
+    let hi = bytes.next()?.data;
+    Some((hi as u16) << 8 | lo as u16)
+}
+
+fn crossed(a: u16, b: u16) -> bool { a & 0xFF00 != b & 0xFF00 }
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        let opcodes = (0 ..= 255u8).map(|opcode| {
+            let applies = |x: bool| if x { Some(0) } else { None };
+            OpcodeCoverage {
+                opcode,
+                mnemonic: disasm::mnemonic(opcode).to_owned(),
+                executed: 0,
+                page_crossed: applies(is_indexed(opcode) || is_branch(opcode)),
+                decimal: applies(is_decimal_capable(opcode)),
+                branch_taken: applies(is_branch(opcode)),
+                branch_not_taken: applies(is_branch(opcode)),
+                interrupted: 0,
+            }
+        }).collect();
+        Coverage { instructions: 0, opcodes, previous: Vec::new(),
+                   current: Vec::new() }
+    }
+    pub fn add_trace(&mut self, cycles: &[String]) {
+        for cycle in cycles { self.add_cycle(cycle) }
+        self.finish();
+    }
+    pub fn add_cycle(&mut self, cycle: &str) {
+        let fetch = Cycle::parse(cycle)
+            .map(|x| x.typ == OPCODE_READ).unwrap_or(false);
+        if fetch && !self.current.is_empty() {
+            if !self.previous.is_empty() {
+                let previous = std::mem::replace(&mut self.previous,
+                                                 Vec::new());
+                self.count(&previous);
+            }
+            self.previous = std::mem::replace(&mut self.current, Vec::new());
+        }
+        self.current.push(cycle.to_owned());
+    }
+    // Ends a trace fed in with `add_cycle`.
+    pub fn finish(&mut self) {
+        if !self.previous.is_empty() {
+            let previous = std::mem::replace(&mut self.previous, Vec::new());
+            self.count(&previous);
+        }
+        self.current.clear();
+    }
+    // Adds in another `Coverage`'s counts.
+    pub fn merge(&mut self, other: &Coverage) {
+        self.instructions += other.instructions;
+        let add = |a: &mut Option<u64>, b: Option<u64>| {
+            if let (Some(a), Some(b)) = (a.as_mut(), b) { *a += b }
+        };
+        for (a, b) in self.opcodes.iter_mut().zip(other.opcodes.iter()) {
+            a.executed += b.executed;
+            add(&mut a.page_crossed, b.page_crossed);
+            add(&mut a.decimal, b.decimal);
+            add(&mut a.branch_taken, b.branch_taken);
+            add(&mut a.branch_not_taken, b.branch_not_taken);
+            a.interrupted += b.interrupted;
+        }
+    }
+    // Counts `self.previous`, given that `self.current` comes next.
+    fn count(&mut self, cycles: &[String]) {
+        let instruction = match trace::decode(cycles).into_iter().next() {
+            Some(x) => x,
+            None => return,
+        };
+        if instruction.labels.get(0).map(|x| x.as_str()) != Some("opcode") {
+            return
+        }
+        let parsed: Vec<Option<Cycle>> = cycles.iter()
+            .map(|x| Cycle::parse(x)).collect();
+        let pc = match parsed[0] { Some(x) => x.addr, None => return };
+        let opcode = parsed[0].unwrap().data;
+        let next = trace::decode(&self.current).into_iter().next();
+        let next_pc = self.current.get(0).and_then(|x| Cycle::parse(x))
+            .map(|x| x.addr);
+        let mode = disasm::mode(opcode);
+        let labels = &instruction.labels;
+        let stats = &mut self.opcodes[opcode as usize];
+        self.instructions += 1;
+        stats.executed += 1;
+        if next.map(|x| x.labels.get(0).map(|x| x.as_str())
+                    == Some("ignored opcode")).unwrap_or(false) {
+            stats.interrupted += 1;
+        }
+        let mut page_crossed = false;
+        if is_branch(opcode) {
+            let len = disasm::length(opcode) as u16;
+            let fallthrough = pc.wrapping_add(len);
+            let offset = parsed.iter().zip(labels.iter())
+                .filter(|(_, l)| *l == "operand").filter_map(|(x, _)| *x)
+                .last().map(|x| x.data as i8 as u16);
+            let target = offset.map(|x| fallthrough.wrapping_add(x));
+            let taken = match target {
+                Some(target) if target != fallthrough =>
+                    next_pc == Some(target),
+                // a branch to the next instruction goes there either way,
+                // but takes an extra cycle to do it when it's taken
+                _ => labels.len() > if mode == Mode::Relative { 2 } else { 5 },
+            };
+            if taken {
+                *stats.branch_taken.as_mut().unwrap() += 1;
+                page_crossed = target.map(|x| crossed(x, fallthrough))
+                    .unwrap_or(false);
+            }
+            else { *stats.branch_not_taken.as_mut().unwrap() += 1 }
+        }
+        else if is_indexed(opcode) {
+            let label = if mode == Mode::ZeroPageIndirectY { "pointer" }
+                        else { "operand" };
+            let base = labeled_word(&parsed, labels, label);
+            if let (Some(base), Some(effective))
+                = (base, instruction.effective) {
+                page_crossed = crossed(base, effective);
+            }
+        }
+        if page_crossed { *stats.page_crossed.as_mut().unwrap() += 1 }
+        if is_decimal_capable(opcode)
+        && labels.len() > binary_cycles(mode) + page_crossed as usize {
+            *stats.decimal.as_mut().unwrap() += 1;
+        }
+    }
+    pub fn opcodes_executed(&self) -> usize {
+        self.opcodes.iter().filter(|x| x.executed > 0).count()
+    }
+    // A 16x16 grid of how many times each opcode was executed, then the
+    // conditions that never came up for the opcodes that were.
+    pub fn write_report(&self, out: &mut dyn io::Write) -> io::Result<()> {
+        writeln!(out, "{} instructions; {} of 256 opcodes executed",
+                 self.instructions, self.opcodes_executed())?;
+        write!(out, "   ")?;
+        for n in 0 .. 16 { write!(out, "{:>6}", format!("_{:X}", n))? }
+        writeln!(out)?;
+        for row in self.opcodes.chunks(16) {
+            write!(out, "{:X}_ ", row[0].opcode >> 4)?;
+            for x in row {
+                let count = match x.executed {
+                    0 => "-".to_owned(),
+                    n if n < 100000 => n.to_string(),
+                    n if n < 100000000 => format!("{}k", n / 1000),
+                    n => format!("{}M", n / 1000000),
+                };
+                write!(out, "{:>6}", count)?;
+            }
+            writeln!(out)?;
+        }
+        let mut missing = Vec::new();
+        for x in self.opcodes.iter().filter(|x| x.executed > 0) {
+            let mut never = Vec::new();
+            if x.page_crossed == Some(0) { never.push("page crossed") }
+            if x.decimal == Some(0) { never.push("decimal") }
+            if x.branch_taken == Some(0) { never.push("branch taken") }
+            if x.branch_not_taken == Some(0) {
+                never.push("branch not taken")
+            }
+            if !never.is_empty() {
+                missing.push(format!("  ${:02X} {}: {}", x.opcode,
+                                     x.mnemonic, never.join(", ")));
+            }
+        }
+        if !missing.is_empty() {
+            writeln!(out, "Never seen:")?;
+            for line in missing { writeln!(out, "{}", line)? }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classification() {
+        let cycles: Vec<String> = [
+            // $0200: BNE $0207, taken
+            "F0200D0", "7020105", "7020202",
+            // $0207: BEQ $0219, not taken
+            "F0207F0", "7020810",
+            // $0209: LDA $12F0,X, crossing into $13xx
+            "F0209BD", "7020AF0", "7020B12", "7020B12", "7131042",
+            // $020C: LDA $1200,X, not crossing
+            "F020CBD", "7020D00", "7020E12", "7121042",
+            // $020F: ADC #$01, binary
+            "F020F69", "7021001",
+            // $0211: ADC #$01, decimal
+            "F021169", "7021201", "7021201",
+            // $0213: BNE $0195, taken, across a page
+            "F0213D0", "7021480", "7021515", "7021515",
+            // $0195: NOP, then an IRQ
+            "F0195EA", "70196EA",
+            "F0196EA", "70196EA", "601FF01", "601FE96", "601FD20",
+            "5FFFE00", "5FFFF03",
+            // $0300: left out, as the end of the trace
+            "F0300EA",
+        ].iter().map(|x| x.to_string()).collect();
+        let mut coverage = Coverage::new();
+        coverage.add_trace(&cycles);
+        assert_eq!(coverage.instructions, 8);
+        let op = |x: usize| &coverage.opcodes[x];
+        assert_eq!((op(0xD0).executed, op(0xD0).branch_taken,
+                    op(0xD0).branch_not_taken, op(0xD0).page_crossed),
+                   (2, Some(2), Some(0), Some(1)));
+        assert_eq!((op(0xF0).executed, op(0xF0).branch_taken,
+                    op(0xF0).branch_not_taken, op(0xF0).page_crossed),
+                   (1, Some(0), Some(1), Some(0)));
+        assert_eq!((op(0xBD).executed, op(0xBD).page_crossed,
+                    op(0xBD).decimal), (2, Some(1), None));
+        assert_eq!((op(0x69).executed, op(0x69).decimal,
+                    op(0x69).page_crossed), (2, Some(1), None));
+        assert_eq!((op(0xEA).executed, op(0xEA).interrupted), (1, 1));
+        assert_eq!(coverage.opcodes_executed(), 5);
+        // a cycle at a time comes out the same
+        let mut by_cycle = Coverage::new();
+        for cycle in cycles.iter() { by_cycle.add_cycle(cycle) }
+        by_cycle.finish();
+        assert_eq!(by_cycle.opcodes, coverage.opcodes);
+    }
+}
@@ -0,0 +1,449 @@
+// An interactive debugger for a job running on an emulator. It reads
+// commands a line at a time, and answers in kind; see HELP. The CPU can only
+// be stopped between instructions, so stepping by cycle runs whole
+// instructions and shows every cycle they made.
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufWriter, Write};
+use super::disasm;
+use super::harness::{Cpu, Emulator, FlipType, Hit, Watchpoint};
+use super::trace;
+
+const HELP: &str = "\
+Numbers are decimal, or hex with a $ or 0x in front. If the job has symbols,
+an address can also be a label.
+  step [N]            run N instructions (default 1), disassembling each
+  cycle [N]           run until N more cycles have passed (default 1), and
+                      show each bus cycle
+  until CYCLE         run until cycle CYCLE
+  continue            run until something stops it
+  break ADDR          stop before running an instruction at ADDR
+  delete ADDR         remove that breakpoint
+  watch ADDR[-END] [r|w|rw]
+                      stop after an instruction reads (or writes) there
+  unwatch ADDR        remove the watchpoints starting at ADDR
+  info                list breakpoints and watchpoints
+  regs                show the registers
+  set REG VALUE       set pc, a, x, y, s, or p
+  mem ADDR [LEN]      show memory (default 64 bytes)
+  poke ADDR BYTE...   change memory, rwmap or no
+  irq [LEN [CYCLE]]   pull IRQB low for LEN cycles (default 10), starting at
+  nmi [LEN [CYCLE]]   CYCLE (default the next one); the same for NMIB
+  trap on|off         whether a terminate_on_* condition stops here (it
+                      does by default) or ends the job
+  snapshot PATH       save a snapshot of the job, for rustcore --resume
+  quit
+An empty line repeats the last step, cycle, or continue.";
+
+// what `mem` and `irq`/`nmi` do without being told
+const DEFAULT_DUMP_LEN: usize = 64;
+const DEFAULT_PULSE_LEN: u32 = 10;
+
+enum Limit {
+    Instructions(u32),
+    Cycle(u32),
+    Forever,
+}
+
+struct Debugger<'a, 'b, C: Cpu> {
+    emulator: &'b mut Emulator<'a, C>,
+    out: &'b mut dyn Write,
+    breakpoints: BTreeSet<u16>,
+    watchpoints: Vec<Watchpoint>,
+}
+
+fn parse_number(x: &str) -> Option<u32> {
+    if x.starts_with("$") { u32::from_str_radix(&x[1..], 16).ok() }
+    else if x.starts_with("0x") { u32::from_str_radix(&x[2..], 16).ok() }
+    else { x.parse().ok() }
+}
+
+fn parse_addr(x: &str) -> Option<u16> {
+    parse_number(x).filter(|&x| x <= 0xFFFF).map(|x| x as u16)
+}
+
+fn parse_byte(x: &str) -> Option<u8> {
+    parse_number(x).filter(|&x| x <= 0xFF).map(|x| x as u8)
+}
+
+fn flags(p: u8) -> String {
+    "NV1BDIZC".chars().enumerate().map(|(n, c)| {
+        if p & (0x80 >> n) != 0 { c } else { c.to_ascii_lowercase() }
+    }).collect()
+}
+
+impl<'a, 'b, C: Cpu> Debugger<'a, 'b, C> {
+    fn pc(&mut self) -> Option<u16> {
+        self.emulator.cpu().registers().map(|x| x.pc)
+    }
+    fn addr(&self, x: &str) -> Option<u16> {
+        parse_addr(x).or_else(|| self.emulator.symbols()?.address_of(x))
+    }
+    // `$0234 (main+$4)`, or just `$0234` without symbols.
+    fn show_addr(&self, addr: u16) -> String {
+        match self.emulator.symbols() {
+            Some(symbols) => symbols.show(addr),
+            None => format!("${:04X}", addr),
+        }
+    }
+    // The instruction at `pc`, from memory.
+    fn disassemble_at(&self, pc: u16) -> String {
+        let opcode = self.emulator.peek(pc);
+        let operand: Vec<u8> = (1 .. disasm::length(opcode) as u16)
+            .map(|n| self.emulator.peek(pc.wrapping_add(n))).collect();
+        disasm::disassemble(pc, opcode, &operand)
+    }
+    fn show_location(&mut self) -> io::Result<()> {
+        let cycle = self.emulator.num_cycles();
+        if let Some(cause) = self.emulator.termination_cause() {
+            return writeln!(self.out, "The job is over ({}) after {} \
+                                       cycles.", cause, cycle)
+        }
+        match self.pc() {
+            Some(pc) => {
+                let (at, text) = (self.show_addr(pc), self.disassemble_at(pc));
+                writeln!(self.out, "cycle {}, {}  {}", cycle, at, text)
+            },
+            None => writeln!(self.out, "cycle {}", cycle),
+        }
+    }
+    fn show_regs(&mut self) -> io::Result<()> {
+        match self.emulator.cpu().registers() {
+            Some(x) => writeln!(self.out, "PC=${:04X} A=${:02X} X=${:02X} \
+                                           Y=${:02X} S=${:02X} P=${:02X} \
+                                           ({})",
+                                x.pc, x.a, x.x, x.y, x.s, x.p, flags(x.p)),
+            None => writeln!(self.out, "This CPU doesn't report its \
+                                        registers."),
+        }
+    }
+    fn show_mem(&mut self, start: u16, len: usize) -> io::Result<()> {
+        let bytes: Vec<u8> = (0 .. len)
+            .map(|n| self.emulator.peek(start.wrapping_add(n as u16)))
+            .collect();
+        for (n, row) in bytes.chunks(16).enumerate() {
+            let hex: Vec<String> = row.iter()
+                .map(|x| format!("{:02X}", x)).collect();
+            let text: String = row.iter().map(|&x| {
+                if x >= 0x20 && x < 0x7F { x as char } else { '.' }
+            }).collect();
+            writeln!(self.out, "${:04X}  {:<47}  {}",
+                     start.wrapping_add(n as u16 * 16), hex.join(" "), text)?;
+        }
+        Ok(())
+    }
+    // Runs until `limit`, or a breakpoint, watchpoint, or trapped
+    // termination, or the end of the job. A breakpoint where the CPU already
+    // is doesn't count, so that it can be stepped past. Only stepping lists
+    // what ran; `until` and `continue` can run for a long time, and just show
+    // where they ended up.
+    fn run(&mut self, limit: Limit, show_cycles: bool) -> io::Result<()> {
+        let first = self.emulator.num_cycles();
+        let show = if let Limit::Instructions(_) = limit { true }
+                   else { show_cycles };
+        self.emulator.probe().log = if show { Some(Vec::new()) } else { None };
+        let mut instructions = 0;
+        let mut stop = None;
+        while self.emulator.is_running() {
+            match limit {
+                Limit::Instructions(n) if instructions >= n => break,
+                Limit::Cycle(n) if self.emulator.num_cycles() >= n => break,
+                _ => (),
+            }
+            if instructions > 0 {
+                if let Some(pc) = self.pc() {
+                    if self.breakpoints.contains(&pc) {
+                        stop = Some(format!("breakpoint at {}",
+                                            self.show_addr(pc)));
+                        break
+                    }
+                }
+            }
+            self.emulator.step();
+            instructions += 1;
+            let probe = self.emulator.probe();
+            let mut reasons: Vec<String> = probe.trapped.take().map(|x| {
+                format!("{} (the job would have ended here)", x)
+            }).into_iter().collect();
+            let hits: Vec<Hit> = probe.hits.drain(..).collect();
+            reasons.extend(hits.into_iter().map(|x| {
+                format!("watchpoint: {} ${:02X} {} {} at cycle {}",
+                        if x.write { "wrote" } else { "read" }, x.value,
+                        if x.write { "to" } else { "from" },
+                        self.show_addr(x.addr), x.cycle)
+            }));
+            if !reasons.is_empty() {
+                stop = Some(reasons.join("; "));
+                break
+            }
+        }
+        let log = self.emulator.probe().log.take().unwrap_or_default();
+        let log: Vec<String> = log.into_iter()
+            .map(trace::cycle_string).collect();
+        if show_cycles {
+            trace::write_listing_from(self.out, &log, first as usize,
+                                      self.emulator.symbols())?;
+        }
+        else if show {
+            for instruction in trace::decode(&log) {
+                let pc = instruction.pc.map(|x| self.show_addr(x))
+                    .unwrap_or_default();
+                writeln!(self.out, "{:<5}  {}", pc, instruction.text)?;
+            }
+        }
+        if let Some(stop) = stop {
+            writeln!(self.out, "Stopped: {}", stop)?;
+        }
+        self.show_location()
+    }
+    fn set_register(&mut self, reg: &str, value: u32) -> Option<()> {
+        let mut regs = self.emulator.cpu().registers()?;
+        let byte = if value <= 0xFF { Some(value as u8) } else { None };
+        match reg {
+            "pc" => regs.pc = if value <= 0xFFFF { value as u16 }
+                              else { return None },
+            "a" => regs.a = byte?,
+            "x" => regs.x = byte?,
+            "y" => regs.y = byte?,
+            "s" => regs.s = byte?,
+            "p" => regs.p = byte?,
+            _ => return None,
+        }
+        if self.emulator.cpu().set_registers(&regs) { Some(()) } else { None }
+    }
+    fn pulse(&mut self, typ: FlipType, args: &[&str]) -> Option<()> {
+        let length = match args.get(0) {
+            Some(x) => parse_number(x)?,
+            None => DEFAULT_PULSE_LEN,
+        };
+        let start = match args.get(1) {
+            Some(x) => parse_number(x)?,
+            None => self.emulator.num_cycles() + 1,
+        };
+        self.emulator.pulse(typ, start, length);
+        Some(())
+    }
+    fn snapshot(&mut self, path: &str) -> io::Result<()> {
+        let snapshot = self.emulator.snapshot()?;
+        let mut file = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut file, &snapshot)?;
+        file.flush()
+    }
+    // Returns false on `quit`, and None if the command didn't make sense.
+    fn command(&mut self, words: &[&str]) -> io::Result<Option<bool>> {
+        let arg = |n: usize| words.get(n).cloned();
+        let count = |n: usize| match arg(n) {
+            Some(x) => parse_number(x),
+            None => Some(1),
+        };
+        let running = self.emulator.is_running();
+        match words[0] {
+            "step" | "s" | "cycle" | "until" | "u" | "continue" | "c"
+                if !running => {
+                writeln!(self.out, "The job is over.")?;
+            },
+            "step" | "s" => match count(1) {
+                Some(n) => self.run(Limit::Instructions(n), false)?,
+                None => return Ok(None),
+            },
+            "cycle" => match count(1) {
+                Some(n) => {
+                    let end = self.emulator.num_cycles().saturating_add(n);
+                    self.run(Limit::Cycle(end), true)?
+                },
+                None => return Ok(None),
+            },
+            "until" | "u" => match arg(1).and_then(parse_number) {
+                Some(n) => self.run(Limit::Cycle(n), false)?,
+                None => return Ok(None),
+            },
+            "continue" | "c" => self.run(Limit::Forever, false)?,
+            "break" | "b" => match arg(1).and_then(|x| self.addr(x)) {
+                Some(x) => { self.breakpoints.insert(x); },
+                None => return Ok(None),
+            },
+            "delete" | "d" => match arg(1).and_then(|x| self.addr(x)) {
+                Some(x) => { self.breakpoints.remove(&x); },
+                None => return Ok(None),
+            },
+            "watch" | "w" => {
+                let mut range = match arg(1) {
+                    Some(x) => x.splitn(2, '-'),
+                    None => return Ok(None),
+                };
+                let start = range.next().and_then(|x| self.addr(x));
+                let end = match range.next() {
+                    Some(x) => self.addr(x),
+                    None => start,
+                };
+                let (read, write) = match arg(2).unwrap_or("rw") {
+                    "r" => (true, false),
+                    "w" => (false, true),
+                    "rw" => (true, true),
+                    _ => return Ok(None),
+                };
+                match (start, end) {
+                    (Some(start), Some(end)) if start <= end =>
+                        self.watchpoints.push(Watchpoint {
+                            start, end, read, write
+                        }),
+                    _ => return Ok(None),
+                }
+                self.emulator.probe().watchpoints = self.watchpoints.clone();
+            },
+            "unwatch" => match arg(1).and_then(|x| self.addr(x)) {
+                Some(x) => {
+                    self.watchpoints.retain(|w| w.start != x);
+                    self.emulator.probe().watchpoints
+                        = self.watchpoints.clone();
+                },
+                None => return Ok(None),
+            },
+            "info" | "i" => {
+                for &x in self.breakpoints.iter() {
+                    writeln!(self.out, "break {}", self.show_addr(x))?;
+                }
+                for x in self.watchpoints.iter() {
+                    writeln!(self.out, "watch {}-{} {}{}",
+                             self.show_addr(x.start), self.show_addr(x.end),
+                             if x.read { "r" } else { "" },
+                             if x.write { "w" } else { "" })?;
+                }
+                let trap = self.emulator.probe().trap;
+                writeln!(self.out, "trap {}", if trap { "on" } else { "off" })?;
+            },
+            "regs" | "r" => self.show_regs()?,
+            "set" => {
+                let value = arg(2).and_then(parse_number);
+                match (arg(1), value) {
+                    (Some(reg), Some(value)) => {
+                        if self.set_register(reg, value).is_none() {
+                            return Ok(None)
+                        }
+                        self.show_regs()?
+                    },
+                    _ => return Ok(None),
+                }
+            },
+            "mem" | "x" => {
+                let len = match arg(2) {
+                    Some(x) => parse_number(x).map(|x| x as usize),
+                    None => Some(DEFAULT_DUMP_LEN),
+                };
+                match (arg(1).and_then(|x| self.addr(x)), len) {
+                    (Some(start), Some(len)) => self.show_mem(start, len)?,
+                    _ => return Ok(None),
+                }
+            },
+            "poke" => {
+                let start = arg(1).and_then(|x| self.addr(x));
+                let bytes: Option<Vec<u8>> = words.get(2..).unwrap_or(&[])
+                    .iter().map(|x| parse_byte(x)).collect();
+                match (start, bytes) {
+                    (Some(start), Some(bytes)) if !bytes.is_empty() => {
+                        for (n, x) in bytes.into_iter().enumerate() {
+                            self.emulator.poke(start.wrapping_add(n as u16),
+                                               x);
+                        }
+                    },
+                    _ => return Ok(None),
+                }
+            },
+            "irq" => if self.pulse(FlipType::Irq, &words[1..]).is_none() {
+                return Ok(None)
+            },
+            "nmi" => if self.pulse(FlipType::Nmi, &words[1..]).is_none() {
+                return Ok(None)
+            },
+            "trap" => match arg(1) {
+                Some("on") => self.emulator.probe().trap = true,
+                Some("off") => self.emulator.probe().trap = false,
+                _ => return Ok(None),
+            },
+            "snapshot" => match arg(1) {
+                Some(path) => if let Err(e) = self.snapshot(path) {
+                    writeln!(self.out, "{}", e)?;
+                },
+                None => return Ok(None),
+            },
+            "help" | "h" | "?" => writeln!(self.out, "{}", HELP)?,
+            "quit" | "q" => return Ok(Some(false)),
+            _ => return Ok(None),
+        }
+        Ok(Some(true))
+    }
+}
+
+// Debugs `emulator` until `input` runs out or says `quit`. Terminations are
+// trapped from the start.
+pub fn debug<C: Cpu>(emulator: &mut Emulator<C>, input: &mut dyn BufRead,
+                     out: &mut dyn Write) -> io::Result<()> {
+    emulator.probe().trap = true;
+    let mut debugger = Debugger {
+        emulator, out,
+        breakpoints: BTreeSet::new(),
+        watchpoints: Vec::new(),
+    };
+    debugger.show_location()?;
+    let mut last = String::new();
+    loop {
+        write!(debugger.out, "(65test) ")?;
+        debugger.out.flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 { break }
+        let mut line = line.trim().to_owned();
+        if line.is_empty() { line = last.clone() }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() { continue }
+        match debugger.command(&words)? {
+            Some(true) => (),
+            Some(false) => break,
+            None => writeln!(debugger.out, "Didn't understand that; try \
+                                            \"help\".")?,
+        }
+        if ["step", "s", "cycle", "continue", "c"].contains(&words[0]) {
+            last = line;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::Profile;
+    use super::super::toy::{self, Toy, LOOP};
+
+    // What the debugger said in response to each line of `commands`.
+    fn session(commands: &str) -> Vec<String> {
+        let job = toy::job(LOOP, r#", "max_cycles": 100"#);
+        let mut cpu = Toy::new();
+        let mut emulator = Emulator::new(&job, &mut cpu, Profile::Local,
+                                         None);
+        let mut out = Vec::new();
+        debug(&mut emulator, &mut commands.as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        out.split("(65test) ").skip(1).map(|x| x.to_owned()).collect()
+    }
+
+    #[test]
+    fn break_step_continue() {
+        let out = session("break $0205\ncontinue\nstep 2\n\ncontinue\n\
+                           delete $0205\ncontinue\n");
+        assert_eq!(out, [
+            "",
+            "Stopped: breakpoint at $0205\ncycle 13, $0205  INC A\n",
+            "$0205  INC A\n$0206  JMP $0200\ncycle 18, $0200  LDA #$05\n",
+            // an empty line steps again
+            "$0200  LDA #$05\n$0202  STA $0010\ncycle 24, $0205  INC A\n",
+            // the breakpoint it's already at doesn't count
+            "Stopped: breakpoint at $0205\ncycle 35, $0205  INC A\n",
+            "",
+            "The job is over (limit) after 100 cycles.\n",
+            // the prompt that found the end of the input
+            "",
+        ]);
+    }
+}
@@ -0,0 +1,224 @@
+// A 65C02 disassembler, covering every opcode the W65C02S has, including the
+// WDC-only RMB/SMB/BBR/BBS, WAI and STP, and the various NOPs.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Implied, Accumulator, Immediate,
+    ZeroPage, ZeroPageX, ZeroPageY,
+    ZeroPageIndirect, ZeroPageXIndirect, ZeroPageIndirectY,
+    Absolute, AbsoluteX, AbsoluteY,
+    AbsoluteIndirect, AbsoluteXIndirect,
+    Relative,
+    // BBR and BBS: a zero page address, then a branch offset
+    ZeroPageRelative,
+}
+use self::Mode::*;
+
+impl Mode {
+    // Bytes of operand that follow the opcode.
+    pub fn operand_len(&self) -> usize {
+        match self {
+            Implied | Accumulator => 0,
+            Immediate | ZeroPage | ZeroPageX | ZeroPageY | ZeroPageIndirect
+                | ZeroPageXIndirect | ZeroPageIndirectY | Relative => 1,
+            Absolute | AbsoluteX | AbsoluteY | AbsoluteIndirect
+                | AbsoluteXIndirect | ZeroPageRelative => 2,
+        }
+    }
+}
+
+pub const BRK: u8 = 0x00;
+
+// BRK is listed as Immediate, since it's followed by a signature byte that
+// the CPU skips over.
+const OPCODES: [(&str, Mode); 256] = [
+    // $00
+    ("BRK", Immediate), ("ORA", ZeroPageXIndirect),
+    ("NOP", Immediate), ("NOP", Implied),
+    ("TSB", ZeroPage), ("ORA", ZeroPage),
+    ("ASL", ZeroPage), ("RMB0", ZeroPage),
+    ("PHP", Implied), ("ORA", Immediate),
+    ("ASL", Accumulator), ("NOP", Implied),
+    ("TSB", Absolute), ("ORA", Absolute),
+    ("ASL", Absolute), ("BBR0", ZeroPageRelative),
+    // $10
+    ("BPL", Relative), ("ORA", ZeroPageIndirectY),
+    ("ORA", ZeroPageIndirect), ("NOP", Implied),
+    ("TRB", ZeroPage), ("ORA", ZeroPageX),
+    ("ASL", ZeroPageX), ("RMB1", ZeroPage),
+    ("CLC", Implied), ("ORA", AbsoluteY),
+    ("INC", Accumulator), ("NOP", Implied),
+    ("TRB", Absolute), ("ORA", AbsoluteX),
+    ("ASL", AbsoluteX), ("BBR1", ZeroPageRelative),
+    // $20
+    ("JSR", Absolute), ("AND", ZeroPageXIndirect),
+    ("NOP", Immediate), ("NOP", Implied),
+    ("BIT", ZeroPage), ("AND", ZeroPage),
+    ("ROL", ZeroPage), ("RMB2", ZeroPage),
+    ("PLP", Implied), ("AND", Immediate),
+    ("ROL", Accumulator), ("NOP", Implied),
+    ("BIT", Absolute), ("AND", Absolute),
+    ("ROL", Absolute), ("BBR2", ZeroPageRelative),
+    // $30
+    ("BMI", Relative), ("AND", ZeroPageIndirectY),
+    ("AND", ZeroPageIndirect), ("NOP", Implied),
+    ("BIT", ZeroPageX), ("AND", ZeroPageX),
+    ("ROL", ZeroPageX), ("RMB3", ZeroPage),
+    ("SEC", Implied), ("AND", AbsoluteY),
+    ("DEC", Accumulator), ("NOP", Implied),
+    ("BIT", AbsoluteX), ("AND", AbsoluteX),
+    ("ROL", AbsoluteX), ("BBR3", ZeroPageRelative),
+    // $40
+    ("RTI", Implied), ("EOR", ZeroPageXIndirect),
+    ("NOP", Immediate), ("NOP", Implied),
+    ("NOP", ZeroPage), ("EOR", ZeroPage),
+    ("LSR", ZeroPage), ("RMB4", ZeroPage),
+    ("PHA", Implied), ("EOR", Immediate),
+    ("LSR", Accumulator), ("NOP", Implied),
+    ("JMP", Absolute), ("EOR", Absolute),
+    ("LSR", Absolute), ("BBR4", ZeroPageRelative),
+    // $50
+    ("BVC", Relative), ("EOR", ZeroPageIndirectY),
+    ("EOR", ZeroPageIndirect), ("NOP", Implied),
+    ("NOP", ZeroPageX), ("EOR", ZeroPageX),
+    ("LSR", ZeroPageX), ("RMB5", ZeroPage),
+    ("CLI", Implied), ("EOR", AbsoluteY),
+    ("PHY", Implied), ("NOP", Implied),
+    ("NOP", Absolute), ("EOR", AbsoluteX),
+    ("LSR", AbsoluteX), ("BBR5", ZeroPageRelative),
+    // $60
+    ("RTS", Implied), ("ADC", ZeroPageXIndirect),
+    ("NOP", Immediate), ("NOP", Implied),
+    ("STZ", ZeroPage), ("ADC", ZeroPage),
+    ("ROR", ZeroPage), ("RMB6", ZeroPage),
+    ("PLA", Implied), ("ADC", Immediate),
+    ("ROR", Accumulator), ("NOP", Implied),
+    ("JMP", AbsoluteIndirect), ("ADC", Absolute),
+    ("ROR", Absolute), ("BBR6", ZeroPageRelative),
+    // $70
+    ("BVS", Relative), ("ADC", ZeroPageIndirectY),
+    ("ADC", ZeroPageIndirect), ("NOP", Implied),
+    ("STZ", ZeroPageX), ("ADC", ZeroPageX),
+    ("ROR", ZeroPageX), ("RMB7", ZeroPage),
+    ("SEI", Implied), ("ADC", AbsoluteY),
+    ("PLY", Implied), ("NOP", Implied),
+    ("JMP", AbsoluteXIndirect), ("ADC", AbsoluteX),
+    ("ROR", AbsoluteX), ("BBR7", ZeroPageRelative),
+    // $80
+    ("BRA", Relative), ("STA", ZeroPageXIndirect),
+    ("NOP", Immediate), ("NOP", Implied),
+    ("STY", ZeroPage), ("STA", ZeroPage),
+    ("STX", ZeroPage), ("SMB0", ZeroPage),
+    ("DEY", Implied), ("BIT", Immediate),
+    ("TXA", Implied), ("NOP", Implied),
+    ("STY", Absolute), ("STA", Absolute),
+    ("STX", Absolute), ("BBS0", ZeroPageRelative),
+    // $90
+    ("BCC", Relative), ("STA", ZeroPageIndirectY),
+    ("STA", ZeroPageIndirect), ("NOP", Implied),
+    ("STY", ZeroPageX), ("STA", ZeroPageX),
+    ("STX", ZeroPageY), ("SMB1", ZeroPage),
+    ("TYA", Implied), ("STA", AbsoluteY),
+    ("TXS", Implied), ("NOP", Implied),
+    ("STZ", Absolute), ("STA", AbsoluteX),
+    ("STZ", AbsoluteX), ("BBS1", ZeroPageRelative),
+    // $A0
+    ("LDY", Immediate), ("LDA", ZeroPageXIndirect),
+    ("LDX", Immediate), ("NOP", Implied),
+    ("LDY", ZeroPage), ("LDA", ZeroPage),
+    ("LDX", ZeroPage), ("SMB2", ZeroPage),
+    ("TAY", Implied), ("LDA", Immediate),
+    ("TAX", Implied), ("NOP", Implied),
+    ("LDY", Absolute), ("LDA", Absolute),
+    ("LDX", Absolute), ("BBS2", ZeroPageRelative),
+    // $B0
+    ("BCS", Relative), ("LDA", ZeroPageIndirectY),
+    ("LDA", ZeroPageIndirect), ("NOP", Implied),
+    ("LDY", ZeroPageX), ("LDA", ZeroPageX),
+    ("LDX", ZeroPageY), ("SMB3", ZeroPage),
+    ("CLV", Implied), ("LDA", AbsoluteY),
+    ("TSX", Implied), ("NOP", Implied),
+    ("LDY", AbsoluteX), ("LDA", AbsoluteX),
+    ("LDX", AbsoluteY), ("BBS3", ZeroPageRelative),
+    // $C0
+    ("CPY", Immediate), ("CMP", ZeroPageXIndirect),
+    ("NOP", Immediate), ("NOP", Implied),
+    ("CPY", ZeroPage), ("CMP", ZeroPage),
+    ("DEC", ZeroPage), ("SMB4", ZeroPage),
+    ("INY", Implied), ("CMP", Immediate),
+    ("DEX", Implied), ("WAI", Implied),
+    ("CPY", Absolute), ("CMP", Absolute),
+    ("DEC", Absolute), ("BBS4", ZeroPageRelative),
+    // $D0
+    ("BNE", Relative), ("CMP", ZeroPageIndirectY),
+    ("CMP", ZeroPageIndirect), ("NOP", Implied),
+    ("NOP", ZeroPageX), ("CMP", ZeroPageX),
+    ("DEC", ZeroPageX), ("SMB5", ZeroPage),
+    ("CLD", Implied), ("CMP", AbsoluteY),
+    ("PHX", Implied), ("STP", Implied),
+    ("NOP", Absolute), ("CMP", AbsoluteX),
+    ("DEC", AbsoluteX), ("BBS5", ZeroPageRelative),
+    // $E0
+    ("CPX", Immediate), ("SBC", ZeroPageXIndirect),
+    ("NOP", Immediate), ("NOP", Implied),
+    ("CPX", ZeroPage), ("SBC", ZeroPage),
+    ("INC", ZeroPage), ("SMB6", ZeroPage),
+    ("INX", Implied), ("SBC", Immediate),
+    ("NOP", Implied), ("NOP", Implied),
+    ("CPX", Absolute), ("SBC", Absolute),
+    ("INC", Absolute), ("BBS6", ZeroPageRelative),
+    // $F0
+    ("BEQ", Relative), ("SBC", ZeroPageIndirectY),
+    ("SBC", ZeroPageIndirect), ("NOP", Implied),
+    ("NOP", ZeroPageX), ("SBC", ZeroPageX),
+    ("INC", ZeroPageX), ("SMB7", ZeroPage),
+    ("SED", Implied), ("SBC", AbsoluteY),
+    ("PLX", Implied), ("NOP", Implied),
+    ("NOP", Absolute), ("SBC", AbsoluteX),
+    ("INC", AbsoluteX), ("BBS7", ZeroPageRelative),
+];
+
+pub fn mnemonic(opcode: u8) -> &'static str { OPCODES[opcode as usize].0 }
+pub fn mode(opcode: u8) -> Mode { OPCODES[opcode as usize].1 }
+// Bytes in the whole instruction, opcode included.
+pub fn length(opcode: u8) -> usize { 1 + mode(opcode).operand_len() }
+
+fn branch_target(pc: u16, len: usize, offset: u8) -> u16 {
+    pc.wrapping_add(len as u16).wrapping_add(offset as i8 as u16)
+}
+
+// Disassembles the instruction at `pc`. `operand` holds whatever operand
+// bytes are known; missing ones show up as `??`.
+pub fn disassemble(pc: u16, opcode: u8, operand: &[u8]) -> String {
+    let mode = mode(opcode);
+    let mnemonic = mnemonic(opcode);
+    let byte = |n: usize| operand.get(n).map(|x| format!("{:02X}", x))
+        .unwrap_or_else(|| "??".to_owned());
+    let word = || byte(1) + &byte(0);
+    let operand = match mode {
+        Implied => return mnemonic.to_owned(),
+        Accumulator => "A".to_owned(),
+        Immediate => format!("#${}", byte(0)),
+        ZeroPage => format!("${}", byte(0)),
+        ZeroPageX => format!("${},X", byte(0)),
+        ZeroPageY => format!("${},Y", byte(0)),
+        ZeroPageIndirect => format!("(${})", byte(0)),
+        ZeroPageXIndirect => format!("(${},X)", byte(0)),
+        ZeroPageIndirectY => format!("(${}),Y", byte(0)),
+        Absolute => format!("${}", word()),
+        AbsoluteX => format!("${},X", word()),
+        AbsoluteY => format!("${},Y", word()),
+        AbsoluteIndirect => format!("(${})", word()),
+        AbsoluteXIndirect => format!("(${},X)", word()),
+        Relative => match operand.get(0) {
+            Some(&x) => format!("${:04X}", branch_target(pc, 2, x)),
+            None => "$????".to_owned(),
+        },
+        ZeroPageRelative => match operand.get(1) {
+            Some(&x) => format!("${},${:04X}", byte(0),
+                                branch_target(pc, 3, x)),
+            None => format!("${},$????", byte(0)),
+        },
+    };
+    format!("{} {}", mnemonic, operand)
+}
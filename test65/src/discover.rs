@@ -0,0 +1,180 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use super::comm::Comm;
+use super::pool;
+
+// Device names that a Due's Programming Port might show up as.
+const CANDIDATE_PREFIXES: &[&str] = &[
+    "ttyACM", "ttyUSB", "cu.usbmodem", "cu.usbserial",
+];
+// The USB vendor and product IDs of a Due's Programming Port (its 16U2) and
+// Native Port (the SAM3X itself), under both of Arduino's vendor IDs. Only
+// devices with one of these are probed, unless they're named explicitly;
+// opening a port resets whatever is on the other end.
+const DUE_USB_IDS: &[(u16, u16)] = &[
+    (0x2341, 0x003D), (0x2341, 0x003E), (0x2A03, 0x003D), (0x2A03, 0x003E),
+];
+const SYS_TTY: &str = "/sys/class/tty";
+
+// The USB vendor and product ID of the device behind the tty `name`, as
+// sysfs (under `sys_tty`) has them. Not knowing is None.
+fn usb_id(sys_tty: &Path, name: &str) -> Option<(u16, u16)> {
+    let device = fs::canonicalize(sys_tty.join(name).join("device")).ok()?;
+    // the tty hangs off a USB interface, which hangs off the USB device
+    for dir in device.ancestors().take(4) {
+        let read = |file: &str| {
+            let text = fs::read_to_string(dir.join(file)).ok()?;
+            u16::from_str_radix(text.trim(), 16).ok()
+        };
+        if let (Some(vendor), Some(product))
+            = (read("idVendor"), read("idProduct")) {
+            return Some((vendor, product))
+        }
+    }
+    None
+}
+
+fn candidates() -> io::Result<Vec<PathBuf>> {
+    let mut ret = Vec::new();
+    for entry in fs::read_dir("/dev")? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !CANDIDATE_PREFIXES.iter().any(|x| name.starts_with(x)) {
+            continue
+        }
+        match usb_id(Path::new(SYS_TTY), &name) {
+            Some(id) if DUE_USB_IDS.contains(&id) => ret.push(entry.path()),
+            Some((vendor, product)) =>
+                eprintln!("{:?}: USB ID {:04x}:{:04x} isn't a Due's, \
+                           skipping", entry.path(), vendor, product),
+            None =>
+                eprintln!("{:?}: can't tell what this is, skipping (name it \
+                           to probe it anyway)", entry.path()),
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+// Opening the port resets the Due, so this gets us a fresh wakeup sequence.
+// An echo round trip afterward makes sure it's really talking our protocol.
+fn probe(path: &Path) -> io::Result<()> {
+    let mut comm = Comm::new(path)?;
+    comm.echo()
+}
+
+// Returns the paths of all the healthy boards among `paths`, or, if it's
+// empty, among the Dues we can find. Boards that are currently running a job
+// are assumed to be healthy, and aren't disturbed.
+pub fn discover(paths: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let paths = if paths.is_empty() { candidates()? } else { paths.to_vec() };
+    let mut ret = Vec::new();
+    for path in paths {
+        let board = match pool::Board::try_lock(&path)? {
+            None => {
+                eprintln!("{:?}: busy, assuming healthy", path);
+                ret.push(path);
+                continue
+            },
+            Some(board) => board,
+        };
+        match probe(&path) {
+            Ok(_) => {
+                eprintln!("{:?}: found a board", path);
+                ret.push(path);
+            },
+            Err(e) => eprintln!("{:?}: {}", path, e),
+        }
+        std::mem::drop(board);
+    }
+    Ok(ret)
+}
+
+// Replaces the pool with the boards we found. The new pool is written
+// alongside and renamed into place, so a job starting at the same time never
+// sees a partial list. If we found nothing, the old pool is left alone.
+pub fn update_pool(paths: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let found = discover(paths)?;
+    if found.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound,
+                                  "No boards found, leaving the pool alone"))
+    }
+    let temp_path = format!("{}.new", pool::POOL_PATH);
+    {
+        let mut file = File::create(&temp_path)?;
+        writeln!(file, "# written by 65test board discovery")?;
+        for path in found.iter() {
+            writeln!(file, "{}", path.to_string_lossy())?;
+        }
+    }
+    fs::rename(&temp_path, pool::POOL_PATH)?;
+    Ok(found)
+}
+
+// `discover [PORT...]`, for the CGI script and the command line alike.
+// Exits with 0 if it found any boards, 1 for a usage error, and 2 if it
+// didn't.
+pub fn discover_main(argv0: &str, args: &[String]) -> i32 {
+    if args.iter().any(|x| x.starts_with("-")) {
+        eprintln!("Usage: {} discover [PORT...]\n\
+                   \n\
+                   Probes for boards, and rewrites {} with every one that \
+                   answers. With no\n\
+                   ports named, probes the serial devices whose USB IDs are \
+                   an Arduino Due's.\n\
+                   Naming ports probes just those instead.",
+                  argv0, pool::POOL_PATH);
+        return 1
+    }
+    let paths: Vec<PathBuf> = args.iter().map(PathBuf::from).collect();
+    match update_pool(&paths) {
+        Ok(found) => {
+            for path in found.iter() {
+                println!("{}", path.to_string_lossy());
+            }
+            0
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            2
+        },
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn usb_ids() {
+        let sys = std::env::temp_dir()
+            .join(format!("65test-discover-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&sys);
+        // a CDC ACM tty hangs off the interface, and a USB serial one off a
+        // port below that
+        let device = sys.join("devices/usb1/1-1");
+        let interface = device.join("1-1:1.0");
+        fs::create_dir_all(interface.join("ttyUSB0")).unwrap();
+        fs::write(device.join("idVendor"), "2341\n").unwrap();
+        fs::write(device.join("idProduct"), "003d\n").unwrap();
+        let tty = sys.join("class/tty");
+        for name in ["ttyACM0", "ttyUSB0", "ttyS0"].iter() {
+            fs::create_dir_all(tty.join(name)).unwrap();
+        }
+        symlink(&interface, tty.join("ttyACM0/device")).unwrap();
+        symlink(interface.join("ttyUSB0"), tty.join("ttyUSB0/device"))
+            .unwrap();
+        assert_eq!(usb_id(&tty, "ttyACM0"), Some((0x2341, 0x003D)));
+        assert_eq!(usb_id(&tty, "ttyUSB0"), Some((0x2341, 0x003D)));
+        assert!(DUE_USB_IDS.contains(&(0x2341, 0x003D)));
+        // not a USB device at all
+        assert_eq!(usb_id(&tty, "ttyS0"), None);
+        assert_eq!(usb_id(&tty, "ttyACM9"), None);
+        fs::remove_dir_all(&sys).unwrap();
+    }
+}
@@ -0,0 +1,551 @@
+// A GDB remote serial protocol stub, for debugging a job running on an
+// emulator with GDB (or anything else that speaks the protocol). Memory and
+// the serial ports are the job's. The registers are a, x, y, p, sp, and pc,
+// in that order, as in MAME's 6502 stub; the target description says so.
+// Watchpoints are checked on every bus cycle, but the CPU can only stop
+// between instructions, so they stop after the instruction that hit them.
+// `monitor trap off` makes the job's `terminate_on_*` conditions end it,
+// instead of stopping it with SIGTRAP.
+
+use std::collections::{BTreeSet, VecDeque};
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use super::harness::{Cpu, Emulator, Watchpoint};
+
+const TARGET_XML: &str = "<?xml version=\"1.0\"?>\n\
+<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+<target version=\"1.0\">\n\
+  <feature name=\"org.65test.w65c02s\">\n\
+    <reg name=\"a\" bitsize=\"8\" type=\"uint8\" regnum=\"0\"/>\n\
+    <reg name=\"x\" bitsize=\"8\" type=\"uint8\"/>\n\
+    <reg name=\"y\" bitsize=\"8\" type=\"uint8\"/>\n\
+    <reg name=\"p\" bitsize=\"8\" type=\"uint8\"/>\n\
+    <reg name=\"sp\" bitsize=\"8\" type=\"uint8\"/>\n\
+    <reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>\n\
+  </feature>\n\
+</target>\n";
+
+const PACKET_SIZE: usize = 4096;
+// While running, the connection is checked for a ^C this often (in
+// instructions).
+const INTERRUPT_CHECK_INTERVAL: u32 = 4096;
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// The kinds of Z packet.
+const SOFTWARE_BREAKPOINT: u8 = 0;
+const HARDWARE_BREAKPOINT: u8 = 1;
+const WRITE_WATCHPOINT: u8 = 2;
+const READ_WATCHPOINT: u8 = 3;
+const ACCESS_WATCHPOINT: u8 = 4;
+
+struct Stub<'a, 'b, C: Cpu> {
+    emulator: &'b mut Emulator<'a, C>,
+    stream: TcpStream,
+    // bytes read, but not yet looked at
+    pending: VecDeque<u8>,
+    acks: bool,
+    breakpoints: BTreeSet<u16>,
+    // (kind, watchpoint)
+    watchpoints: Vec<(u8, Watchpoint)>,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn unhex(x: &str) -> Option<Vec<u8>> {
+    if x.len() % 2 != 0 { return None }
+    (0 .. x.len() / 2)
+        .map(|n| u8::from_str_radix(x.get(n * 2 .. n * 2 + 2)?, 16).ok())
+        .collect()
+}
+
+fn number(x: &str) -> Option<u32> { u32::from_str_radix(x, 16).ok() }
+
+// "ADDR,LEN"
+fn addr_len(x: &str) -> Option<(u16, usize)> {
+    let mut it = x.splitn(2, ',');
+    let addr = number(it.next()?).filter(|&x| x <= 0xFFFF)?;
+    Some((addr as u16, number(it.next()?)? as usize))
+}
+
+// `data` as a packet: escaped, and checksummed.
+fn frame(data: &str) -> Vec<u8> {
+    let mut packet = vec![b'$'];
+    for &x in data.as_bytes() {
+        if b"$#}*".contains(&x) { packet.extend(&[b'}', x ^ 0x20]) }
+        else { packet.push(x) }
+    }
+    let sum = packet[1..].iter().fold(0u8, |a, &x| a.wrapping_add(x));
+    packet.extend(format!("#{:02x}", sum).as_bytes());
+    packet
+}
+
+impl<'a, 'b, C: Cpu> Stub<'a, 'b, C> {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        loop {
+            if let Some(x) = self.pending.pop_front() { return Ok(x) }
+            let mut buf = [0; 1024];
+            let n = self.stream.read(&mut buf)?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          "GDB hung up"))
+            }
+            self.pending.extend(buf[..n].iter().cloned());
+        }
+    }
+    // True if GDB has sent a ^C since we last looked.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut buf = [0; 1024];
+        let result = self.stream.read(&mut buf);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(n) => self.pending.extend(buf[..n].iter().cloned()),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => return Err(e),
+        }
+        let before = self.pending.len();
+        self.pending.retain(|&x| x != 0x03);
+        Ok(self.pending.len() != before)
+    }
+    // The next packet, or None for a bare ^C.
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                b'$' => break,
+                0x03 => return Ok(None),
+                _ => (),
+            }
+        }
+        let mut data = Vec::new();
+        let mut sum: u8 = 0;
+        loop {
+            let x = self.read_byte()?;
+            if x == b'#' { break }
+            sum = sum.wrapping_add(x);
+            if x == b'}' {
+                let x = self.read_byte()?;
+                sum = sum.wrapping_add(x);
+                data.push(x ^ 0x20);
+            }
+            else { data.push(x) }
+        }
+        let check = [self.read_byte()?, self.read_byte()?];
+        let check = std::str::from_utf8(&check).ok()
+            .and_then(|x| u8::from_str_radix(x, 16).ok());
+        if self.acks {
+            if check != Some(sum) {
+                self.stream.write_all(b"-")?;
+                return self.receive()
+            }
+            self.stream.write_all(b"+")?;
+        }
+        Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+    }
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = frame(data);
+        loop {
+            self.stream.write_all(&packet)?;
+            if !self.acks { return Ok(()) }
+            loop {
+                match self.read_byte()? {
+                    b'+' => return Ok(()),
+                    b'-' => break,
+                    _ => (),
+                }
+            }
+        }
+    }
+    // Console output, as GDB shows it.
+    fn say(&mut self, text: &str) -> io::Result<()> {
+        self.send(&format!("O{}", hex(text.as_bytes())))
+    }
+    fn registers(&mut self) -> Option<Vec<u8>> {
+        let x = self.emulator.cpu().registers()?;
+        Some(vec![x.a, x.x, x.y, x.p, x.s, x.pc as u8, (x.pc >> 8) as u8])
+    }
+    fn set_registers(&mut self, bytes: &[u8]) -> Option<()> {
+        let mut regs = self.emulator.cpu().registers()?;
+        regs.a = *bytes.get(0)?;
+        regs.x = *bytes.get(1)?;
+        regs.y = *bytes.get(2)?;
+        regs.p = *bytes.get(3)?;
+        regs.s = *bytes.get(4)?;
+        regs.pc = (*bytes.get(6)? as u16) << 8 | *bytes.get(5)? as u16;
+        if self.emulator.cpu().set_registers(&regs) { Some(()) } else { None }
+    }
+    // Register `n` is at bytes `n` .. `n+1` of `g`, except pc, which is two.
+    fn set_register(&mut self, n: usize, value: &[u8]) -> Option<()> {
+        let mut bytes = self.registers()?;
+        let size = if n == 5 { 2 } else { 1 };
+        if n > 5 || value.len() != size { return None }
+        bytes[n .. n + size].copy_from_slice(value);
+        self.set_registers(&bytes)
+    }
+    fn update_watchpoints(&mut self) {
+        self.emulator.probe().watchpoints = self.watchpoints.iter()
+            .map(|x| x.1).collect();
+    }
+    fn set_point(&mut self, args: &str, insert: bool) -> Option<()> {
+        let mut it = args.splitn(3, ',');
+        let kind = number(it.next()?)? as u8;
+        let addr = number(it.next()?).filter(|&x| x <= 0xFFFF)? as u16;
+        let len = number(it.next()?)?.max(1);
+        let end = addr.checked_add((len - 1) as u16)?;
+        let (read, write) = match kind {
+            SOFTWARE_BREAKPOINT | HARDWARE_BREAKPOINT => {
+                if insert { self.breakpoints.insert(addr); }
+                else { self.breakpoints.remove(&addr); }
+                return Some(())
+            },
+            WRITE_WATCHPOINT => (false, true),
+            READ_WATCHPOINT => (true, false),
+            ACCESS_WATCHPOINT => (true, true),
+            _ => return None,
+        };
+        let point = (kind, Watchpoint { start: addr, end, read, write });
+        if insert { self.watchpoints.push(point) }
+        else if let Some(n) = self.watchpoints.iter().position(|&x| x == point) {
+            self.watchpoints.remove(n);
+        }
+        self.update_watchpoints();
+        Some(())
+    }
+    fn exited(&mut self) -> io::Result<String> {
+        let text = format!("The job is over ({}) after {} cycles.\n",
+                           self.emulator.termination_cause().unwrap_or(""),
+                           self.emulator.num_cycles());
+        self.say(&text)?;
+        Ok("W00".to_owned())
+    }
+    // Steps once, or until something stops it. Returns the stop reply.
+    fn resume(&mut self, single: bool) -> io::Result<String> {
+        let mut instructions = 0;
+        loop {
+            if !self.emulator.is_running() { return self.exited() }
+            if instructions > 0 {
+                let pc = self.emulator.cpu().registers().map(|x| x.pc);
+                if let Some(pc) = pc {
+                    if self.breakpoints.contains(&pc) {
+                        return Ok(format!("T{:02x}swbreak:;", SIGTRAP))
+                    }
+                }
+                if instructions % INTERRUPT_CHECK_INTERVAL == 0
+                && self.interrupted()? {
+                    return Ok(format!("S{:02x}", SIGINT))
+                }
+            }
+            self.emulator.step();
+            instructions += 1;
+            let probe = self.emulator.probe();
+            let trapped = probe.trapped.take();
+            let hit = probe.hits.drain(..).next();
+            if let Some(cause) = trapped {
+                self.say(&format!("{} (the job would have ended here)\n",
+                                  cause))?;
+            }
+            if let Some(hit) = hit {
+                return Ok(format!("T{:02x}{}:{:04x};", SIGTRAP,
+                                  if hit.write { "watch" } else { "rwatch" },
+                                  hit.addr))
+            }
+            if trapped.is_some() || single {
+                if !self.emulator.is_running() { return self.exited() }
+                return Ok(format!("S{:02x}", SIGTRAP))
+            }
+        }
+    }
+    fn monitor(&mut self, command: &str) -> io::Result<()> {
+        match command.trim() {
+            "trap on" => self.emulator.probe().trap = true,
+            "trap off" => self.emulator.probe().trap = false,
+            "cycle" => {
+                let text = format!("cycle {}\n", self.emulator.num_cycles());
+                self.say(&text)?
+            },
+            _ => self.say("monitor commands: trap on, trap off, cycle\n")?,
+        }
+        Ok(())
+    }
+    fn features(&self, args: &str) -> Option<String> {
+        // "target.xml:OFFSET,LENGTH"
+        let mut it = args.splitn(2, ':');
+        if it.next()? != "target.xml" { return None }
+        let (offset, length) = {
+            let mut it = it.next()?.splitn(2, ',');
+            (number(it.next()?)? as usize, number(it.next()?)? as usize)
+        };
+        let rest = TARGET_XML.get(offset.min(TARGET_XML.len()) ..)?;
+        if rest.len() <= length { Some(format!("l{}", rest)) }
+        else { Some(format!("m{}", &rest[.. length])) }
+    }
+    // The reply to `packet`, if any. Err(Interrupted) means GDB is done.
+    fn handle(&mut self, packet: &str) -> io::Result<Option<String>> {
+        let done = || Err(io::Error::new(io::ErrorKind::Interrupted,
+                                         "GDB is done"));
+        let error = Some("E01".to_owned());
+        let ok = Some("OK".to_owned());
+        let (command, args) = packet.split_at(packet.len().min(1));
+        Ok(match command {
+            "?" => Some(if self.emulator.is_running() {
+                format!("S{:02x}", SIGTRAP)
+            } else { "W00".to_owned() }),
+            "g" => match self.registers() {
+                Some(x) => Some(hex(&x)),
+                None => error,
+            },
+            "G" => match unhex(args).and_then(|x| self.set_registers(&x)) {
+                Some(()) => ok,
+                None => error,
+            },
+            "p" => {
+                let regs = self.registers();
+                match (regs, number(args)) {
+                    (Some(x), Some(5)) => Some(hex(&x[5..7])),
+                    (Some(x), Some(n)) if n < 5 =>
+                        Some(hex(&x[n as usize .. n as usize + 1])),
+                    _ => error,
+                }
+            },
+            "P" => {
+                let mut it = args.splitn(2, '=');
+                let n = it.next().and_then(number);
+                let value = it.next().and_then(unhex);
+                match (n, value) {
+                    (Some(n), Some(value)) => {
+                        match self.set_register(n as usize, &value) {
+                            Some(()) => ok,
+                            None => error,
+                        }
+                    },
+                    _ => error,
+                }
+            },
+            "m" => match addr_len(args) {
+                Some((addr, len)) => {
+                    let bytes: Vec<u8> = (0 .. len.min(PACKET_SIZE / 2))
+                        .map(|n| self.emulator.peek(addr.wrapping_add(n as u16)))
+                        .collect();
+                    Some(hex(&bytes))
+                },
+                None => error,
+            },
+            "M" => {
+                let mut it = args.splitn(2, ':');
+                let target = it.next().and_then(addr_len);
+                match (target, it.next().and_then(unhex)) {
+                    (Some((addr, len)), Some(bytes)) if bytes.len() == len => {
+                        for (n, x) in bytes.into_iter().enumerate() {
+                            self.emulator.poke(addr.wrapping_add(n as u16), x);
+                        }
+                        ok
+                    },
+                    _ => error,
+                }
+            },
+            "Z" | "z" => match self.set_point(args, command == "Z") {
+                Some(()) => ok,
+                None => Some(String::new()),
+            },
+            "s" | "c" => {
+                // resuming at another address isn't supported
+                if !args.is_empty() { error }
+                else { Some(self.resume(command == "s")?) }
+            },
+            "H" => ok,
+            "D" => {
+                self.send("OK")?;
+                return done()
+            },
+            "k" => return done(),
+            _ if packet.starts_with("qSupported") => {
+                Some(format!("PacketSize={:x};qXfer:features:read+;\
+                              QStartNoAckMode+;swbreak+;hwbreak+",
+                             PACKET_SIZE))
+            },
+            _ if packet == "QStartNoAckMode" => {
+                self.send("OK")?;
+                self.acks = false;
+                None
+            },
+            _ if packet == "qAttached" => Some("1".to_owned()),
+            _ if packet == "qC" => Some("QC1".to_owned()),
+            _ if packet == "qfThreadInfo" => Some("m1".to_owned()),
+            _ if packet == "qsThreadInfo" => Some("l".to_owned()),
+            _ if packet.starts_with("qXfer:features:read:") => {
+                match self.features(&packet[20..]) {
+                    Some(x) => Some(x),
+                    None => error,
+                }
+            },
+            _ if packet.starts_with("qRcmd,") => {
+                let command = unhex(&packet[6..])
+                    .map(|x| String::from_utf8_lossy(&x).into_owned());
+                match command {
+                    Some(x) => {
+                        self.monitor(&x)?;
+                        ok
+                    },
+                    None => error,
+                }
+            },
+            _ => Some(String::new()),
+        })
+    }
+}
+
+// Serves one GDB connection, until it detaches, kills the job, or hangs up.
+// Terminations are trapped from the start.
+pub fn serve<C: Cpu>(emulator: &mut Emulator<C>, stream: TcpStream)
+                     -> io::Result<()> {
+    emulator.probe().trap = true;
+    stream.set_nodelay(true)?;
+    let mut stub = Stub {
+        emulator, stream,
+        pending: VecDeque::new(),
+        acks: true,
+        breakpoints: BTreeSet::new(),
+        watchpoints: Vec::new(),
+    };
+    loop {
+        let reply = match stub.receive() {
+            // a ^C while stopped
+            Ok(None) => Some(format!("S{:02x}", SIGINT)),
+            Ok(Some(packet)) => match stub.handle(&packet) {
+                Ok(x) => x,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted =>
+                    return Ok(()),
+                Err(e) => return Err(e),
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof =>
+                return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if let Some(reply) = reply { stub.send(&reply)? }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+    use super::super::Profile;
+    use super::super::toy::{self, Toy, LOOP};
+
+    // `body`, checksummed, but not escaped.
+    fn packet(body: &[u8]) -> Vec<u8> {
+        let sum = body.iter().fold(0u8, |a, &x| a.wrapping_add(x));
+        let mut packet = vec![b'$'];
+        packet.extend(body);
+        packet.extend(format!("#{:02x}", sum).as_bytes());
+        packet
+    }
+
+    // GDB's end of the connection.
+    struct Gdb(TcpStream);
+
+    impl Gdb {
+        fn byte(&mut self) -> u8 {
+            let mut buf = [0];
+            self.0.read_exact(&mut buf).unwrap();
+            buf[0]
+        }
+        // Sends `raw` as is, and returns the stub's ack.
+        fn send_raw(&mut self, raw: &[u8]) -> u8 {
+            self.0.write_all(raw).unwrap();
+            self.byte()
+        }
+        // The stub's next packet, with its checksum checked and acked.
+        fn reply(&mut self) -> String {
+            assert_eq!(self.byte(), b'$');
+            let mut data = Vec::new();
+            loop {
+                match self.byte() {
+                    b'#' => break,
+                    x => data.push(x),
+                }
+            }
+            let check = [self.byte(), self.byte()];
+            assert_eq!(&check, &packet(&data)[data.len() + 2 ..]);
+            self.0.write_all(b"+").unwrap();
+            String::from_utf8(data).unwrap()
+        }
+        fn ask(&mut self, data: &str) -> String {
+            assert_eq!(self.send_raw(&frame(data)), b'+');
+            self.reply()
+        }
+    }
+
+    // Serves a job running LOOP to `script`, which plays GDB.
+    fn session<F: FnOnce(&mut Gdb) + Send + 'static>(script: F) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+            script(&mut Gdb(stream));
+        });
+        let job = toy::job(LOOP, r#", "max_cycles": 100"#);
+        let mut cpu = Toy::new();
+        let mut emulator = Emulator::new(&job, &mut cpu, Profile::Local,
+                                         None);
+        let (stream, _) = listener.accept().unwrap();
+        serve(&mut emulator, stream).unwrap();
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn framing() {
+        assert_eq!(frame("OK"), b"$OK#9a");
+        assert_eq!(frame(""), b"$#00");
+        // $, #, } and * are escaped, and the checksum covers the escapes
+        assert_eq!(frame("a}b"), packet(b"a}]b"));
+        assert_eq!(frame("$#*"), packet(b"}\x04}\x03}\x0a"));
+        session(|gdb| {
+            // a bad checksum is nacked, and the packet is sent again
+            assert_eq!(gdb.send_raw(b"$m200,1#00"), b'-');
+            assert_eq!(gdb.ask("m200,1"), "a9");
+            // an escaped m
+            assert_eq!(gdb.send_raw(&packet(b"}M200,2")), b'+');
+            assert_eq!(gdb.reply(), "a905");
+            // a reply that isn't acked is sent again
+            gdb.0.write_all(&frame("m201,1")).unwrap();
+            assert_eq!(gdb.byte(), b'+');
+            let first: Vec<u8> = (0 .. 6).map(|_| gdb.byte()).collect();
+            assert_eq!(first, frame("05"));
+            gdb.0.write_all(b"-").unwrap();
+            assert_eq!(gdb.reply(), "05");
+            assert_eq!(gdb.send_raw(&frame("k")), b'+');
+        });
+    }
+
+    #[test]
+    fn commands() {
+        session(|gdb| {
+            assert_eq!(gdb.ask("?"), "S05");
+            // the first step runs the reset sequence
+            assert_eq!(gdb.ask("s"), "S05");
+            // a, x, y, p, sp, pc
+            assert_eq!(gdb.ask("g"), "00000034fc0002");
+            assert_eq!(gdb.ask("m200,5"), "a9058d1000");
+            assert_eq!(gdb.ask("Z0,205,1"), "OK");
+            assert_eq!(gdb.ask("c"), "T05swbreak:;");
+            assert_eq!(gdb.ask("g"), "05000034fc0502");
+            assert_eq!(gdb.ask("m10,1"), "05");
+            // INC A
+            assert_eq!(gdb.ask("s"), "S05");
+            assert_eq!(gdb.ask("g"), "06000034fc0602");
+            assert_eq!(gdb.ask("z0,205,1"), "OK");
+            assert_eq!(gdb.ask("Z2,10,1"), "OK");
+            // JMP $0200, LDA #$05, and the STA that hits the watchpoint
+            assert_eq!(gdb.ask("c"), "T05watch:0010;");
+            assert_eq!(gdb.ask("p5"), "0502");
+            assert_eq!(gdb.send_raw(&frame("k")), b'+');
+        });
+    }
+}
@@ -0,0 +1,609 @@
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use super::caps;
+use super::caps::Capabilities;
+use super::capture;
+use super::comm;
+use super::comm::Comm;
+use super::pool;
+use super::pool::Settings;
+use super::{trace, Job, JobResult, CYCLES_TO_REPORT};
+
+const MAX_OVERALL_RETRIES: u32 = 3;
+
+fn send_one_init(mut data: &[u8], comm: &mut Comm)
+                 -> io::Result<()> {
+    let max = comm.max_physical_packet_size();
+    while data.len() > max {
+        comm.send_packet(0x01, &data[..max], false)?;
+        data = &data[max..];
+    }
+    comm.send_packet(0x01, data, false)?;
+    Ok(())
+}
+
+fn send_init(base: u16, mut rem: usize, data: &[u8],
+             comm: &mut Comm) -> io::Result<()> {
+    comm.send_packet(0x09, &[(base >> 8) as u8, base as u8], false)?;
+    while rem > 0 {
+        let sublen = rem.min(data.len());
+        send_one_init(&data[..sublen], comm)?;
+        rem -= sublen;
+    }
+    Ok(())
+}
+
+pub fn check_capabilities(job: &Job, caps: &Capabilities) -> Result<(), String> {
+    let needs = [
+        (job.show_cycles == Some(true), caps::FEATURE_CYCLE_REPORTS,
+         "show_cycles"),
+        (job.serial_in_addr.is_some(), caps::FEATURE_SERIAL_IN,
+         "serial_in_addr"),
+        (job.serial_out_addr.is_some(), caps::FEATURE_SERIAL_OUT,
+         "serial_out_addr"),
+        (job.nmi.is_some(), caps::FEATURE_NMI, "nmi"),
+        (job.irq.is_some(), caps::FEATURE_IRQ, "irq"),
+        (job.rdy.is_some(), caps::FEATURE_RDY, "rdy"),
+        (job.so.is_some(), caps::FEATURE_SO, "so"),
+        (job.res.is_some(), caps::FEATURE_RES, "res"),
+    ];
+    for &(used, feature, name) in needs.iter() {
+        if used && !caps.has(feature) {
+            return Err(format!("The board's firmware (version {}) does not \
+                                support `{}`.", caps.firmware_version, name))
+        }
+    }
+    if job.serial_in_addr.is_some() && caps.serial_in_buffer_size == 0 {
+        return Err(format!("The board's firmware (version {}) has no room \
+                            for serial input.", caps.firmware_version))
+    }
+    if let Some(rwmap) = &job.rwmap {
+        if rwmap.len() > caps.max_ranges as usize {
+            return Err(format!("The board's firmware supports at most {} \
+                                `rwmap` ranges.", caps.max_ranges))
+        }
+    }
+    let num_flips: usize = [&job.nmi, &job.irq, &job.rdy, &job.so, &job.res]
+        .iter().map(|x| x.as_ref().map(Vec::len).unwrap_or(0)).sum();
+    if num_flips > caps.max_flips as usize {
+        return Err(format!("The board's firmware supports at most {} signal \
+                            toggles in total.", caps.max_flips))
+    }
+    let unsupported = job.termination_flags() & !caps.termination_flags;
+    if unsupported != 0 {
+        return Err(format!("The board's firmware does not support some of \
+                            the requested termination conditions (flags \
+                            {:02X}).", unsupported))
+    }
+    Ok(())
+}
+
+pub fn open_comm(port_path: &Path, capture: Option<&PathBuf>) -> io::Result<Comm> {
+    let port = comm::open_port(port_path)?;
+    match capture {
+        None => Comm::with_port(Box::new(port)),
+        Some(path) => {
+            let file = capture::open_capture(path)?;
+            Comm::with_port(Box::new(capture::Capture::new(port, file)))
+        },
+    }
+}
+
+// Runs a job once on the board at `port_path`. `open` is called to (re)open
+// the port, which (re)starts the board. If `settings` holds what an earlier
+// job found out about the board, we skip the capabilities query, and the baud
+// rate negotiation if it fell back last time; either way, it's left holding
+// what this job used. An error of kind `InvalidInput` means the job needs
+// something the board can't do, and retrying won't help.
+pub fn attempt_job(job: &Job, port_path: &Path,
+                   settings: &mut Option<Settings>,
+                   open: &mut dyn FnMut() -> io::Result<Comm>)
+                   -> io::Result<JobResult> {
+    let cached = settings.take();
+    let mut comm = open()?;
+    let caps = match &cached {
+        Some(cached) => cached.caps.clone(),
+        None => match comm.query_capabilities()? {
+            Some(caps) => caps,
+            None => {
+                // Old firmware dies when it sees the query. Give it a fresh
+                // start, and assume it can do what old firmware could.
+                eprintln!("capabilities query answered with the death \
+                           sequence, assuming legacy firmware");
+                std::mem::drop(comm);
+                comm = open()?;
+                caps::LEGACY
+            },
+        },
+    };
+    comm.set_packet_sizes(&caps)?;
+    if let Err(reason) = check_capabilities(job, &caps) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, reason))
+    }
+    let fell_back = cached
+        .map_or(false, |x| x.baud_rate != comm::PREFERRED_BAUD_RATE);
+    let baud_rate = if caps.has(caps::FEATURE_BAUD_RATE) && !fell_back {
+        comm.negotiate_baud_rate(comm::PREFERRED_BAUD_RATE)?
+    }
+    else { comm.baud_rate() };
+    *settings = Some(Settings { caps: caps.clone(), baud_rate });
+    let max_logical = comm.max_logical_packet_size();
+    let mut buf = Vec::with_capacity(max_logical);
+    // Send initialization records
+    for rec in job.init.iter() {
+        let size = rec.size();
+        if rec.data.len() < size
+        && rec.data.len() < max_logical {
+            buf.clear();
+            while buf.len() < max_logical - rec.data.len()
+            && buf.len() < size {
+                buf.extend_from_slice(&rec.data);
+            }
+            send_init(rec.base, size, &buf, &mut comm)?;
+        }
+        else {
+            send_init(rec.base, size, &rec.data, &mut comm)?;
+        }
+    }
+    // Send RW map
+    if let Some(rw) = &job.rwmap {
+        buf.clear();
+        for rw in rw {
+            buf.write_all(&[(rw.start >> 8) as u8,
+                            rw.start as u8,
+                            (rw.end >> 8) as u8,
+                            rw.end as u8])?;
+        }
+        comm.send_packet(0x02, &buf[..],
+                         false)?;
+    }
+    // Serial in
+    if let Some(addr) = job.serial_in_addr {
+        comm.send_packet(0x03, &[(addr >> 8) as u8,
+                                 addr as u8], false)?;
+    }
+    // Serial out
+    if let Some(addr) = job.serial_out_addr {
+        comm.send_packet(0x04, &[(addr >> 8) as u8,
+                                 addr as u8], false)?;
+    }
+    // Show cycles
+    if let Some(true) = job.show_cycles {
+        comm.send_packet(0x05, &[(CYCLES_TO_REPORT >> 24) as u8,
+                                 (CYCLES_TO_REPORT >> 16) as u8,
+                                 (CYCLES_TO_REPORT >> 8) as u8,
+                                 CYCLES_TO_REPORT as u8], false)?;
+    }
+    // Max cycles
+    if let Some(max) = job.max_cycles {
+        comm.send_packet(0x06, &[(max >> 24) as u8,
+                                 (max >> 16) as u8,
+                                 (max >> 8) as u8,
+                                 max as u8], false)?;
+    }
+    // Termination causes
+    let termination_flag = job.termination_flags();
+    if termination_flag != 0x3F {
+        comm.send_packet(0x07, &[termination_flag], false)?;
+    }
+    // Flag changes
+    #[derive(Debug)]
+    enum Flag {Nmi, Irq, Rdy, So, Res};
+    let job_changes = [
+        (Flag::Nmi, &job.nmi),
+        (Flag::Irq, &job.irq),
+        (Flag::Rdy, &job.rdy),
+        (Flag::So, &job.so),
+        (Flag::Res, &job.res),
+    ];
+    let mut changes = Vec::new();
+    for (typ, opt) in job_changes.iter() {
+        if let Some(v) = opt {
+            for e in v.iter() {
+                changes.push((typ, *e))
+            }
+        }
+    }
+    if !changes.is_empty() {
+        changes.sort_by(|a,b| { a.1.cmp(&b.1) });
+        let mut nmi_on = false;
+        let mut irq_on = false;
+        let mut rdy_on = true;
+        let mut so_on = false;
+        let mut res_on = false;
+        buf.clear();
+        for (typ, cycle) in changes {
+            let (flag, id) = match typ {
+                Flag::Nmi => (&mut nmi_on, 2),
+                Flag::Irq => (&mut irq_on, 3),
+                Flag::Rdy => (&mut rdy_on, 4),
+                Flag::So => (&mut so_on, 1),
+                Flag::Res => (&mut res_on, 0),
+            };
+            *flag = !*flag;
+            if *flag {
+                buf.push(0x80 | id);
+            }
+            else {
+                buf.push(id);
+            }
+            buf.push((cycle >> 16) as u8);
+            buf.push((cycle >> 8) as u8);
+            buf.push(cycle as u8);
+        }
+        comm.send_packet(0x08, &buf[..], false)?;
+    }
+    comm.send_packet(0xFE, &[], true)?;
+    let mut cycle_reports = Vec::new();
+    let mut serial_out_data = Vec::new();
+    let num_cycles;
+    let execution_time;
+    let last_pc;
+    let termination_cause;
+    let mut serial_in_data = match job.serial_in_data {
+        Some(ref data) => &data[..],
+        None => &[]
+    };
+    loop {
+        let packet_type = comm.read_packet(&mut buf);
+        match packet_type {
+            Ok(0x01) => {
+                // Cycle reports
+                if buf.len() % 4 != 0 {
+                    return Err(io::Error::new(io::ErrorKind::Other,
+                                              "bad cycle report packet \
+                                               length"))
+                }
+                for chunk in buf.chunks(4) {
+                    cycle_reports.push(trace::cycle_string(
+                        ((chunk[0] as u32) << 24) | ((chunk[1] as u32) << 16)
+                        | ((chunk[2] as u32) << 8) | (chunk[3] as u32)));
+                }
+                if cycle_reports.len() > CYCLES_TO_REPORT {
+                    return Err(io::Error::new(io::ErrorKind::Other,
+                                              "too many cycle reports"))
+                }
+                comm.ack_packet(false)?
+            },
+            Ok(0x02) => {
+                // Serial read request
+                if buf.len() != 0 {
+                    return Err(io::Error::new(io::ErrorKind::Other,
+                                              "non-empty serial read \
+                                               request"))
+                }
+                comm.ack_packet(true)?;
+                let to_send
+                    = (caps.serial_in_buffer_size as usize)
+                    .min(serial_in_data.len());
+                comm.send_packet(0x53, &serial_in_data[..to_send], true)?;
+                serial_in_data = &serial_in_data[to_send..];
+            },
+            Ok(0x03) => {
+                // Serial write
+                if buf.len() == 0 {
+                    return Err(io::Error::new(io::ErrorKind::Other,
+                                              "empty serial write"))
+                }
+                if job.serial_out_fmt.is_some() {
+                    serial_out_data.extend_from_slice(&buf[..]);
+                }
+                comm.ack_packet(false)?;
+            },
+            Ok(0x04) => {
+                // Termination
+                if buf.len() != 11 {
+                    return Err(io::Error::new(io::ErrorKind::Other,
+                                              "wrong termination length"))
+                }
+                num_cycles = ((buf[0] as u32) << 24)
+                    | ((buf[1] as u32) << 16)
+                    | ((buf[2] as u32) << 8)
+                    | (buf[3] as u32);
+                execution_time = ((buf[4] as u32) << 24)
+                    | ((buf[5] as u32) << 16)
+                    | ((buf[6] as u32) << 8)
+                    | (buf[7] as u32);
+                last_pc = ((buf[8] as u16) << 8)
+                    | (buf[9] as u16);
+                termination_cause = buf[10];
+                comm.ack_packet(false)?;
+                break;
+            },
+            Ok(x) => {
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          format!("unknown packet type \
+                                                   {:02X}", x)))
+            },
+            Err(e) => {
+                if false {
+                    while let Some(x) = comm.next() {
+                        if x != 0 {
+                            eprintln!("{}", format!("related byte? {:02X}",
+                                                    x));
+                        }
+                    }
+                }
+                return Err(e)
+            }
+        }
+    }
+    std::mem::drop(comm);
+    let termination_cause = [
+        "limit", "brk", "infinite_loop", "zero_fetch", "stack_fetch",
+        "vector_fetch", "bad_write"
+    ][termination_cause as usize];
+    eprint!("{}",
+            format!("job ran {} cycles in {}ms (about {}Hz) on {:?} at {} \
+                     baud, terminated by {}\n",
+                    num_cycles, execution_time,
+                    num_cycles * 1000 / execution_time.max(1),
+                    port_path, baud_rate, termination_cause));
+    let mut result = JobResult {
+        num_cycles,
+        last_pc: Some(last_pc),
+        last_pc_symbol: None,
+        termination_cause: termination_cause.to_owned(),
+        serial_out_data: job.serial_out_fmt
+            .map(|fmt| fmt.encode(&serial_out_data[..])),
+        disasm: job.disassemble(&cycle_reports),
+        cycles: cycle_reports,
+        emulator_regs: None,
+        board: Some(port_path.to_string_lossy().into_owned()),
+        baud_rate: Some(baud_rate),
+    };
+    if let Some(symbols) = job.symbols.as_ref() {
+        symbols.annotate_result(&mut result);
+    }
+    Ok(result)
+}
+
+// Runs a job on whichever board in the pool is free, retrying on another (or
+// the same) board if something goes wrong. An error of kind `InvalidInput`
+// means the job can't run on these boards, and one of kind `TimedOut` means
+// none of them was free.
+pub fn run_on_hardware(job: &Job) -> io::Result<JobResult> {
+    let mut n = 0;
+    loop {
+        let mut board = pool::acquire()?;
+        let capture = capture::new_capture_path(board.path())?;
+        // a capture has to replay on its own, without the board's settings
+        let mut settings = match capture {
+            Some(_) => None,
+            None => board.settings().cloned(),
+        };
+        let result = {
+            let port_path = board.path();
+            attempt_job(job, port_path, &mut settings,
+                        &mut || open_comm(port_path, capture.as_ref()))
+        };
+        match result {
+            Ok(result) => {
+                board.report_success(settings)?;
+                return Ok(result)
+            },
+            Err(e) => {
+                if e.kind() == io::ErrorKind::InvalidInput {
+                    return Err(e)
+                }
+                n += 1;
+                let capture_note = match capture {
+                    Some(path) => format!(" (captured to {:?})", path),
+                    None => String::new(),
+                };
+                eprint!("{}",
+                        format!("retry {} of {} on {:?}: {}{}\n", n,
+                                MAX_OVERALL_RETRIES, board.path(), e,
+                                capture_note));
+                board.report_failure(&e)?;
+                if n >= MAX_OVERALL_RETRIES {
+                    // a serial port timing out isn't a busy pool
+                    return Err(io::Error::new(io::ErrorKind::Other, e))
+                }
+                // let another job have this board while we wait
+                std::mem::drop(board);
+                std::thread::sleep(std::time::Duration::from_millis(1000));
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::io::Read;
+    use super::super::caps::LEGACY;
+    use super::super::capture::{Direction, ReplayPort};
+    use super::super::{toy, Range};
+
+    const WAKEUP: [u8; 9] = [0, 0, 4, 0, 0, 5, 0, 0, 6];
+    const SHUTDOWN: [u8; 4] = [0, 0, 0, 0];
+
+    fn toggles(n: u32) -> String {
+        let cycles: Vec<String> = (0 .. n).map(|x| (x * 10 + 100).to_string())
+            .collect();
+        format!("[{}]", cycles.join(","))
+    }
+
+    fn with_ranges(n: u16) -> Job {
+        let mut job = toy::job(&[0xEA], "");
+        job.rwmap = Some((0 .. n).map(|x| Range { start: x * 2,
+                                                  end: x * 2 + 1 })
+                         .collect());
+        job
+    }
+
+    fn rejection(extra: &str, caps: &Capabilities) -> Option<String> {
+        check_capabilities(&toy::job(&[0xEA], extra), caps).err()
+    }
+
+    #[test]
+    fn capabilities() {
+        let everything = r#", "show_cycles": true, "serial_in_addr": 61440,
+                             "serial_out_addr": 61441, "nmi": [100],
+                             "irq": [100], "rdy": [100], "so": [100],
+                             "res": [100]"#;
+        assert_eq!(rejection(everything, &LEGACY), None);
+        let features = [
+            (caps::FEATURE_CYCLE_REPORTS, "show_cycles"),
+            (caps::FEATURE_SERIAL_IN, "serial_in_addr"),
+            (caps::FEATURE_SERIAL_OUT, "serial_out_addr"),
+            (caps::FEATURE_NMI, "nmi"),
+            (caps::FEATURE_IRQ, "irq"),
+            (caps::FEATURE_RDY, "rdy"),
+            (caps::FEATURE_SO, "so"),
+            (caps::FEATURE_RES, "res"),
+        ];
+        for &(feature, name) in features.iter() {
+            let caps = Capabilities { features: LEGACY.features & !feature,
+                                      ..LEGACY };
+            assert_eq!(rejection(everything, &caps),
+                       Some(format!("The board's firmware (version 1) does \
+                                     not support `{}`.", name)));
+            // a job that doesn't need it doesn't care
+            assert_eq!(rejection("", &caps), None);
+        }
+        let caps = Capabilities { serial_in_buffer_size: 0, ..LEGACY };
+        assert_eq!(rejection(everything, &caps),
+                   Some("The board's firmware (version 1) has no room for \
+                         serial input.".to_owned()));
+        assert_eq!(check_capabilities(&with_ranges(8), &LEGACY), Ok(()));
+        assert_eq!(check_capabilities(&with_ranges(9), &LEGACY),
+                   Err("The board's firmware supports at most 8 `rwmap` \
+                        ranges.".to_owned()));
+        let flips = |nmi, irq| {
+            format!(r#", "nmi": {}, "irq": {}"#, toggles(nmi), toggles(irq))
+        };
+        assert_eq!(rejection(&flips(60, 60), &LEGACY), None);
+        assert_eq!(rejection(&flips(60, 61), &LEGACY),
+                   Some("The board's firmware supports at most 120 signal \
+                         toggles in total.".to_owned()));
+        let caps = Capabilities { termination_flags: 0x1F, ..LEGACY };
+        assert_eq!(rejection("", &caps),
+                   Some("The board's firmware does not support some of the \
+                         requested termination conditions (flags 20)."
+                        .to_owned()));
+        assert_eq!(rejection(r#", "terminate_on_bad_write": false"#, &caps),
+                   None);
+    }
+
+    // Builds the device's side of a conversation, and the host's side it
+    // expects, to replay.
+    struct Script(Vec<(Direction, Vec<u8>)>);
+
+    impl Script {
+        fn host(mut self, bytes: &[u8]) -> Script {
+            self.0.push((Direction::HostToDevice, bytes.to_vec()));
+            self
+        }
+        fn device(mut self, bytes: &[u8]) -> Script {
+            self.0.push((Direction::DeviceToHost, bytes.to_vec()));
+            self
+        }
+        fn packet(self, typ: u8, data: &[u8], ack: &[u8]) -> Script {
+            self.host(&comm::frame_packet(typ, data).unwrap()).device(ack)
+        }
+        // Everything after the capabilities (and baud rate) for a job of
+        // `toy::job(&[0xEA], "")`, which runs for 1000 cycles.
+        fn job(self) -> Script {
+            self.packet(0x09, &[0x02, 0x00], &[0, 0, 1])
+                .packet(0x01, &[0xEA], &[0, 0, 1])
+                .packet(0xFE, &[], &[0, 0, 3])
+                .device(&comm::frame_packet(0x04, &[0, 0, 0x03, 0xE8,
+                                                    0, 0, 0, 1, 0x02, 0x01,
+                                                    0]).unwrap())
+                .host(&[0, 0, 1])
+                .host(&SHUTDOWN)
+        }
+    }
+
+    // Runs a job against a replay of `script`, and returns what happened, and
+    // how many times the port was opened.
+    fn replay(script: Script, job: &Job, settings: &mut Option<Settings>)
+              -> (io::Result<JobResult>, u32) {
+        let replay = ReplayPort::from_units(script.0);
+        let opens = Cell::new(0);
+        let result = attempt_job(job, Path::new("replay"), settings, &mut || {
+            opens.set(opens.get() + 1);
+            Comm::with_port(Box::new(replay.clone()))
+        });
+        assert!(!replay.diverged());
+        // and nothing is left over
+        assert!(replay.clone().read(&mut [0]).is_err());
+        (result, opens.get())
+    }
+
+    #[test]
+    fn legacy_fallback() {
+        let job = toy::job(&[0xEA], "");
+        // the query kills old firmware, so we start it over
+        let script = Script(Vec::new()).device(&WAKEUP)
+            .packet(0x0A, &[], &[0, 0, 0, 0, 0])
+            .host(&SHUTDOWN)
+            .device(&WAKEUP)
+            .job();
+        let mut settings = None;
+        let (result, opens) = replay(script, &job, &mut settings);
+        let result = result.unwrap();
+        assert_eq!(opens, 2);
+        assert_eq!((result.num_cycles, result.last_pc), (1000, Some(0x0201)));
+        assert_eq!(result.termination_cause, "limit");
+        assert_eq!(result.baud_rate, Some(comm::DEFAULT_BAUD_RATE));
+        assert_eq!(settings, Some(Settings {
+            caps: LEGACY, baud_rate: comm::DEFAULT_BAUD_RATE,
+        }));
+        // what old firmware can't do is the job's problem
+        let script = Script(Vec::new()).device(&WAKEUP)
+            .packet(0x0A, &[], &[0, 0, 0, 0, 0])
+            .host(&SHUTDOWN)
+            .device(&WAKEUP)
+            .host(&SHUTDOWN);
+        let (result, _) = replay(script, &with_ranges(9), &mut None);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        // next time, we already know
+        let script = Script(Vec::new()).device(&WAKEUP).job();
+        let (result, opens) = replay(script, &job, &mut settings);
+        assert_eq!(result.unwrap().num_cycles, 1000);
+        assert_eq!(opens, 1);
+    }
+
+    #[test]
+    fn cached_settings() {
+        let job = toy::job(&[0xEA], "");
+        let caps = Capabilities { features: LEGACY.features
+                                  | caps::FEATURE_BAUD_RATE, ..LEGACY };
+        let rate = comm::PREFERRED_BAUD_RATE;
+        let rate = [(rate >> 24) as u8, (rate >> 16) as u8, (rate >> 8) as u8,
+                    rate as u8];
+        let negotiated = |script: Script| {
+            script.packet(0x0B, &rate, &[0, 0, 1])
+                .host(&comm::frame_packet(0xFF, &[]).unwrap())
+                .device(&[0, 0, 8])
+        };
+        let script = Script(Vec::new()).device(&WAKEUP)
+            .packet(0x0A, &[], &[0, 0, 3])
+            .device(&comm::frame_packet(0x43, &caps.to_bytes()).unwrap())
+            .host(&[0, 0, 3]);
+        let mut settings = None;
+        let (result, _) = replay(negotiated(script).job(), &job, &mut settings);
+        assert_eq!(result.unwrap().baud_rate, Some(comm::PREFERRED_BAUD_RATE));
+        assert_eq!(settings, Some(Settings {
+            caps: caps.clone(), baud_rate: comm::PREFERRED_BAUD_RATE,
+        }));
+        // the board starts over at the default rate every time, so we still
+        // have to ask for the faster one...
+        let script = Script(Vec::new()).device(&WAKEUP);
+        let (result, _) = replay(negotiated(script).job(), &job, &mut settings);
+        assert_eq!(result.unwrap().baud_rate, Some(comm::PREFERRED_BAUD_RATE));
+        // ...unless it didn't work last time
+        let mut settings = Some(Settings {
+            caps: caps.clone(), baud_rate: comm::DEFAULT_BAUD_RATE,
+        });
+        let script = Script(Vec::new()).device(&WAKEUP).job();
+        let (result, _) = replay(script, &job, &mut settings);
+        assert_eq!(result.unwrap().baud_rate, Some(comm::DEFAULT_BAUD_RATE));
+        assert_eq!(settings.unwrap().baud_rate, comm::DEFAULT_BAUD_RATE);
+    }
+}
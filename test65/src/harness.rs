@@ -0,0 +1,940 @@
+// Runs jobs on an emulated CPU, the same way the hardware would run them.
+// Everything but the CPU itself lives here: memory, the rwmap, the serial
+// ports, signal flips, termination causes, and cycle reporting. An emulator
+// only has to implement `Cpu`, and make each of its bus cycles by calling
+// the matching method on `System`.
+
+use std::clone::Clone;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::io;
+use super::trace;
+use std::io::Write;
+use super::{Blob, DataType, Job, JobResult, Profile, Range, SERIAL_OUT_LIMIT};
+use super::symbols::Symbols;
+
+const BRK: u8 = 0x00;
+// Cycles streamed to a `trace_out` are formatted and written this many at a
+// time.
+const TRACE_CHUNK: usize = 65536;
+
+// The pins `System` drives, and the little bit of CPU state it needs to
+// model them.
+pub trait Pins {
+    fn set_irq(&mut self, state: bool);
+    fn set_nmi(&mut self, state: bool);
+    // SOB went low; set V.
+    fn set_overflow(&mut self);
+    // RES was released; run the reset sequence on the next step.
+    fn reset(&mut self);
+    // The stack pointer, for the dummy stack reads made while RES is held.
+    fn get_s(&self) -> u8;
+}
+
+pub trait Cpu: Pins {
+    // Runs one instruction (or one interrupt sequence), making every bus
+    // cycle through `system`, and passing `self` along as the `Pins`.
+    fn step(&mut self, system: &mut System);
+    // The registers, for `show_regs`. A CPU that can't tell just doesn't
+    // report them.
+    fn registers(&self) -> Option<Registers> { None }
+    // For debuggers. `cycle` is ignored. Returns false if the CPU can't.
+    fn set_registers(&mut self, _registers: &Registers) -> bool { false }
+    // For snapshots: everything about the CPU, at an instruction boundary,
+    // and a way to put it all back. A CPU that can't do both can't be
+    // snapshotted (or resumed).
+    fn save(&self) -> Option<CpuState> { None }
+    fn restore(&mut self, _state: &CpuState) -> bool { false }
+}
+
+// The registers at an instruction boundary, just before the cycle numbered
+// `cycle`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub cycle: u32,
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub p: u8,
+}
+
+// Whether the CPU is executing instructions, waiting for an interrupt (WAI),
+// stopped (STP), or going to run the reset sequence on its next step.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RunState { Running, Waiting, Stopped, Reset }
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuState {
+    pub registers: Registers,
+    pub state: RunState,
+}
+
+// The input pins a job can toggle.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlipType {
+    #[serde(rename = "so")] Overflow,
+    #[serde(rename = "nmi")] Nmi,
+    #[serde(rename = "irq")] Irq,
+    #[serde(rename = "rdy")] Ready,
+    #[serde(rename = "res")] Reset,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+struct Flip {
+    typ: FlipType,
+    cycle: u32,
+    state: bool,
+}
+
+impl PartialOrd for Flip {
+    fn partial_cmp(&self, other: &Flip) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Flip {
+    fn cmp(&self, other: &Flip) -> Ordering {
+        self.cycle.cmp(&other.cycle)
+    }
+}
+
+// Addresses a debugger wants to hear about accesses to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+}
+
+// An access that hit a watchpoint, made during the cycle numbered `cycle`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hit {
+    pub cycle: u32,
+    pub addr: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+// What a debugger has asked `System` to keep an eye on. A job run without a
+// debugger has none of this to check.
+#[derive(Debug, Default)]
+pub struct Probe {
+    // If set, a `terminate_on_*` condition is noted in `trapped` instead of
+    // ending the job, and the fetch or write goes ahead as if the condition
+    // were off.
+    pub trap: bool,
+    pub trapped: Option<&'static str>,
+    pub watchpoints: Vec<Watchpoint>,
+    pub hits: Vec<Hit>,
+    // if present, every bus cycle made, packed
+    pub log: Option<Vec<u32>>,
+}
+
+// Everything on the board except the CPU.
+pub struct System {
+    sram: [u8; 65536],
+    writable: [bool; 65536],
+    serial_in_addr: Option<u16>,
+    serial_out_addr: Option<u16>,
+    serial_in_data: VecDeque<u8>,
+    serial_out_data: Vec<u8>,
+    serial_out_rem: u32,
+    vector_has_been_pulled: bool,
+    cycles_to_report: u32,
+    // packed, as in report packet 0x01, and formatted once the job is done
+    cycles: Vec<u32>,
+    // if present, reported cycles go here (one per line) instead
+    trace_out: Option<Box<dyn Write>>,
+    trace_error: Option<io::Error>,
+    terminate_on_brk: bool,
+    terminate_on_infinite_loop: bool,
+    terminate_on_zero_fetch: bool,
+    terminate_on_stack_fetch: bool,
+    terminate_on_vector_fetch: bool,
+    terminate_on_bad_write: bool,
+    last_pc: Option<u16>,
+    termination_cause: Option<&'static str>,
+    cycles_to_run: u32,
+    num_cycles: u32,
+    // sorted by cycle; those before `next_flip` have been applied
+    flips: Vec<Flip>,
+    next_flip: usize,
+    // the cycle of flips[next_flip], or never
+    next_flip_cycle: u32,
+    // false while RDY is held low
+    rdy: bool,
+    // true while RESB is held low
+    res: bool,
+    // set when RESB goes low, cleared once the CPU has been reset
+    reset_pending: bool,
+    // true while SOB is held low
+    so: bool,
+    // set when a serial port pulses SO
+    clear_so_next_cycle: bool,
+    // true while IRQB or NMIB is held low
+    irq: bool,
+    nmi: bool,
+    probe: Option<Box<Probe>>,
+}
+
+impl System {
+    fn add_flips(flips: &mut Vec<Flip>, vec: &Option<Vec<u32>>, typ: FlipType) {
+        if let Some(ref vec) = vec {
+            let mut next_state = true;
+            for cycle in vec.iter() {
+                flips.push(Flip { cycle: *cycle, typ, state: next_state });
+                next_state = !next_state;
+            }
+        }
+    }
+    fn new(job: &Job, profile: Profile, trace_out: Option<Box<dyn Write>>)
+           -> System {
+        let mut sram = [0; 65536];
+        sram[0xFFFD] = 0x02;
+        for rec in job.init.iter() {
+            let base = rec.base as usize;
+            let size = rec.size();
+            (&mut sram[base .. base+size]).copy_from_slice(&rec.data.data[..]);
+        }
+        let mut writable = [false; 65536];
+        let rwmap = &job.rwmap;
+        let rwmap = match rwmap {
+            Some(rwmap) => &rwmap[..],
+            None => &[Range { start: 0, end: 511 }],
+        };
+        for range in rwmap.iter() {
+            for cell in (&mut writable[range.start as usize .. range.end as usize + 1]).iter_mut() {
+                *cell = true;
+            }
+        }
+        let serial_in_data = match &job.serial_in_data {
+            Some(data) => data.data.clone().into(),
+            None => VecDeque::new(),
+        };
+        let cycles_to_run = job.max_cycles
+            .unwrap_or(profile.max_cycle_count());
+        let cycles_to_report = profile.cycles_to_report()
+            .min(u32::max_value() as usize) as u32;
+        let mut flips = Vec::new();
+        Self::add_flips(&mut flips, &job.so, FlipType::Overflow);
+        Self::add_flips(&mut flips, &job.nmi, FlipType::Nmi);
+        Self::add_flips(&mut flips, &job.irq, FlipType::Irq);
+        Self::add_flips(&mut flips, &job.rdy, FlipType::Ready);
+        Self::add_flips(&mut flips, &job.res, FlipType::Reset);
+        flips.sort_unstable();
+        System {
+            sram, writable,
+            serial_in_addr: job.serial_in_addr,
+            serial_out_addr: job.serial_out_addr,
+            serial_in_data,
+            serial_out_data: Vec::new(),
+            serial_out_rem: SERIAL_OUT_LIMIT,
+            vector_has_been_pulled: false,
+            cycles_to_run,
+            cycles_to_report: if job.show_cycles.unwrap_or(false) { cycles_to_report.min(cycles_to_run) } else { 0 },
+            cycles: Vec::new(),
+            trace_out, trace_error: None,
+            terminate_on_brk: job.terminate_on_brk.unwrap_or(true),
+            terminate_on_infinite_loop: job.terminate_on_infinite_loop.unwrap_or(true),
+            terminate_on_zero_fetch: job.terminate_on_zero_fetch.unwrap_or(true),
+            terminate_on_stack_fetch: job.terminate_on_stack_fetch.unwrap_or(true),
+            terminate_on_vector_fetch: job.terminate_on_vector_fetch.unwrap_or(true),
+            terminate_on_bad_write: job.terminate_on_bad_write.unwrap_or(true),
+            last_pc: None,
+            termination_cause: None,
+            num_cycles: 5,
+            next_flip: 0,
+            next_flip_cycle: flips.get(0).map(|x| x.cycle)
+                .unwrap_or(u32::max_value()),
+            flips,
+            rdy: true,
+            res: false,
+            reset_pending: false,
+            so: false,
+            clear_so_next_cycle: false,
+            irq: false,
+            nmi: false,
+            probe: None,
+        }
+    }
+    fn report_cycle(&mut self, cpu: &mut dyn Pins, typ: u32, addr: u16, data: u8) {
+        if self.num_cycles >= self.cycles_to_run || self.termination_cause.is_some() { return }
+        if let Some(log) = self.probe.as_mut().and_then(|x| x.log.as_mut()) {
+            log.push(trace::pack(typ as u8, addr, data));
+        }
+        if self.cycles_to_report > 0 && self.termination_cause.is_none() {
+            self.cycles_to_report -= 1;
+            self.cycles.push(trace::pack(typ as u8, addr, data));
+            if self.trace_out.is_some() && self.cycles.len() >= TRACE_CHUNK {
+                self.flush_trace();
+            }
+        }
+        self.num_cycles += 1;
+        while self.num_cycles >= self.next_flip_cycle {
+            let flip = &self.flips[self.next_flip];
+            let (typ, state) = (flip.typ, flip.state);
+            self.next_flip += 1;
+            self.next_flip_cycle = self.flips.get(self.next_flip)
+                .map(|x| x.cycle).unwrap_or(u32::max_value());
+            match typ {
+                FlipType::Overflow => self.set_so(cpu, state),
+                FlipType::Irq => {
+                    self.irq = state;
+                    cpu.set_irq(state)
+                },
+                FlipType::Nmi => {
+                    self.nmi = state;
+                    cpu.set_nmi(state)
+                },
+                // the first toggle pulls RDY low
+                FlipType::Ready => self.rdy = !state,
+                FlipType::Reset => {
+                    self.res = state;
+                    if state { self.reset_pending = true }
+                },
+            }
+        }
+        if self.clear_so_next_cycle {
+            self.clear_so_next_cycle = false;
+            self.so = false;
+        }
+    }
+    // Writes out the cycles recorded so far, if there's a `trace_out`. After
+    // an error, they're thrown away instead.
+    fn flush_trace(&mut self) {
+        let out = match self.trace_out.as_mut() {
+            Some(x) => x,
+            None => return,
+        };
+        if self.trace_error.is_none() {
+            let mut buf = Vec::with_capacity(self.cycles.len() * 8);
+            for &cycle in self.cycles.iter() {
+                trace::push_cycle_string(&mut buf, cycle);
+                buf.push(b'\n');
+            }
+            if let Err(e) = out.write_all(&buf) {
+                self.trace_error = Some(e);
+            }
+        }
+        self.cycles.clear();
+    }
+    // V is set on the falling edge of SOB, not while it's low. A serial port
+    // pulsing SO while the job is already holding it low sets nothing (and
+    // lets go of it a cycle later regardless), just like the real thing.
+    fn set_so(&mut self, cpu: &mut dyn Pins, state: bool) {
+        if state && !self.so {
+            cpu.set_overflow();
+        }
+        self.so = state;
+    }
+    fn pulse_so(&mut self, cpu: &mut dyn Pins) {
+        self.set_so(cpu, true);
+        self.clear_so_next_cycle = true;
+    }
+    fn handle_read(&mut self, cpu: &mut dyn Pins, addr: u16) -> u8 {
+        if let Some(serial_in_addr) = self.serial_in_addr {
+            if addr == serial_in_addr {
+                match self.serial_in_data.pop_front() {
+                    Some(x) => return x,
+                    None => {
+                        self.pulse_so(cpu);
+                        return 0;
+                    }
+                }
+            }
+        }
+        self.sram[addr as usize]
+    }
+    fn handle_write(&mut self, cpu: &mut dyn Pins, addr: u16, value: u8) {
+        if let Some(serial_out_addr) = self.serial_out_addr {
+            if addr == serial_out_addr {
+                if self.serial_out_rem == 0 {
+                    return self.pulse_so(cpu);
+                }
+                self.serial_out_rem -= 1;
+                return self.serial_out_data.push(value);
+            }
+        }
+        if self.writable[addr as usize] {
+            self.sram[addr as usize] = value;
+        }
+        else if self.terminate_on_bad_write && self.termination_cause.is_none() {
+            self.terminate("bad_write");
+        }
+    }
+    // While RESB is low, the CPU abandons whatever it was doing and reads the
+    // stack every cycle. Nothing gets written.
+    fn reset_cycle(&mut self, cpu: &mut dyn Pins) -> u8 {
+        let addr = 0x0100 | cpu.get_s() as u16;
+        let ret = self.sram[addr as usize];
+        self.report_cycle(cpu, NORMAL_READ, addr, ret);
+        ret
+    }
+    // Runs dummy cycles until RESB goes high again (if the instruction it
+    // cut short didn't already), then puts the CPU back in its reset state. Its next step will run the reset sequence and pull
+    // the vector again. (`vector_has_been_pulled` stays set, just as in the
+    // firmware, so the dummy cycles of the sequence are reported.)
+    fn finish_reset(&mut self, cpu: &mut dyn Pins) {
+        while self.res && self.is_running() {
+            self.reset_cycle(cpu);
+        }
+        self.reset_pending = false;
+        cpu.reset();
+    }
+    fn is_running(&self) -> bool {
+        self.termination_cause.is_none() && self.num_cycles < self.cycles_to_run
+    }
+    // Once RESB has gone low, the rest of the instruction is dummy stack
+    // reads for as long as it's held there. When it goes high again, the
+    // reset sequence starts right away, and whatever is left of the
+    // instruction never reaches the bus.
+    //
+    // While RDY is low, the W65C02S holds whatever cycle it's on, read or
+    // write, and the hardware sees (and reports, and acts on) the same bus
+    // state again every clock. A stalled serial read consumes a byte each
+    // time, and a stalled opcode fetch looks like an infinite loop. The CPU
+    // only gets the data from the last repeat.
+    fn perform_read(&mut self, cpu: &mut dyn Pins, typ: u32, addr: u16) -> u8 {
+        loop {
+            if self.reset_pending {
+                if !self.res { return 0 }
+                return self.reset_cycle(cpu)
+            }
+            let stalled = !self.rdy;
+            let ret = self.handle_read(cpu, addr);
+            if self.probe.is_some() { self.watch(addr, ret, false) }
+            if self.vector_has_been_pulled {
+                self.report_cycle(cpu, typ, addr, ret)
+            }
+            let ret = if typ == OPCODE_READ {
+                self.check_opcode_fetch(addr, ret)
+            } else { ret };
+            if !stalled || !self.is_running() { return ret }
+        }
+    }
+    fn perform_write(&mut self, cpu: &mut dyn Pins, typ: u32, addr: u16, value: u8) {
+        loop {
+            if self.reset_pending {
+                if self.res { self.reset_cycle(cpu); }
+                return
+            }
+            let stalled = !self.rdy;
+            if self.probe.is_some() { self.watch(addr, value, true) }
+            if self.vector_has_been_pulled {
+                self.report_cycle(cpu, typ, addr, value)
+            }
+            self.handle_write(cpu, addr, value);
+            if !stalled || !self.is_running() { return }
+        }
+    }
+    // Ends the job, unless a debugger is trapping terminations. Returns true
+    // if it did.
+    fn terminate(&mut self, cause: &'static str) -> bool {
+        if let Some(probe) = self.probe.as_mut() {
+            if probe.trap {
+                probe.trapped.get_or_insert(cause);
+                return false
+            }
+        }
+        self.termination_cause = Some(cause);
+        true
+    }
+    fn watch(&mut self, addr: u16, value: u8, write: bool) {
+        let cycle = self.num_cycles;
+        let probe = self.probe.as_mut().unwrap();
+        let hit = probe.watchpoints.iter().any(|x| {
+            addr >= x.start && addr <= x.end
+                && if write { x.write } else { x.read }
+        });
+        if hit { probe.hits.push(Hit { cycle, addr, value, write }) }
+    }
+    // 0x03 = fast NOP
+    fn check_opcode_fetch(&mut self, addr: u16, ret: u8) -> u8 {
+        if self.vector_has_been_pulled {
+            if let Some(last_pc) = self.last_pc {
+                if self.terminate_on_infinite_loop && addr == last_pc
+                && self.terminate("infinite_loop") {
+                    return 0x03;
+                }
+            }
+            self.last_pc = Some(addr);
+            if self.terminate_on_zero_fetch && addr < 0x0100
+            && self.terminate("zero_fetch") {
+                return 0x03;
+            }
+            if self.terminate_on_stack_fetch && addr >= 0x0100 && addr < 0x0200
+            && self.terminate("stack_fetch") {
+                return 0x03;
+            }
+            if self.terminate_on_vector_fetch && addr >= 0xFFFA
+            && self.terminate("vector_fetch") {
+                return 0x03;
+            }
+            if self.terminate_on_brk && ret == BRK && self.terminate("brk") {
+                return 0x03;
+            }
+        }
+        ret
+    }
+}
+
+const LOCKED_WRITE: u32 = 2;
+const LOCKED_READ: u32 = 3;
+const VECTOR_READ: u32 = 5;
+const NORMAL_WRITE: u32 = 6;
+const NORMAL_READ: u32 = 7;
+const OPCODE_READ: u32 = 15;
+
+// The bus, as the CPU sees it. A `Cpu` makes every bus cycle by calling
+// exactly one of these.
+impl System {
+    pub fn read(&mut self, cpu: &mut dyn Pins, addr: u16) -> u8 {
+        self.perform_read(cpu, NORMAL_READ, addr)
+    }
+    pub fn read_locked(&mut self, cpu: &mut dyn Pins, addr: u16) -> u8 {
+        self.perform_read(cpu, LOCKED_READ, addr)
+    }
+    pub fn read_opcode(&mut self, cpu: &mut dyn Pins, addr: u16) -> u8 {
+        self.perform_read(cpu, OPCODE_READ, addr)
+    }
+    pub fn read_vector(&mut self, cpu: &mut dyn Pins, addr: u16) -> u8 {
+        self.vector_has_been_pulled = true;
+        self.perform_read(cpu, VECTOR_READ, addr)
+    }
+    pub fn write(&mut self, cpu: &mut dyn Pins, addr: u16, value: u8) {
+        self.perform_write(cpu, NORMAL_WRITE, addr, value)
+    }
+    pub fn write_locked(&mut self, cpu: &mut dyn Pins, addr: u16, value: u8) {
+        self.perform_write(cpu, LOCKED_WRITE, addr, value)
+    }
+}
+
+
+// A job stopped between two instructions, with everything it takes to pick
+// it back up: the CPU, memory, the rwmap, the serial ports, the signal flips
+// still to come, the cycles reported so far, and what the response should
+// look like. It serializes to JSON. With a `trace_out`, the cycles already
+// written out aren't in it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    cpu: CpuState,
+    profile: Profile,
+    num_cycles: u32,
+    cycles_to_run: u32,
+    last_pc: Option<u16>,
+    // as in control packet 0x07
+    termination_flags: u8,
+    sram: Blob,
+    rwmap: Vec<[u16; 2]>,
+    serial_in_addr: Option<u16>,
+    serial_out_addr: Option<u16>,
+    serial_in_data: Blob,
+    serial_out_data: Blob,
+    serial_out_rem: u32,
+    serial_out_fmt: Option<DataType>,
+    vector_has_been_pulled: bool,
+    cycles_to_report: u32,
+    cycles: Vec<String>,
+    show_disasm: bool,
+    regs: Option<Vec<Registers>>,
+    flips: Vec<Flip>,
+    rdy: bool,
+    res: bool,
+    reset_pending: bool,
+    so: bool,
+    clear_so_next_cycle: bool,
+    irq: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    symbols: Option<Symbols>,
+}
+
+fn snapshot_error(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("snapshot: {}", what))
+}
+
+impl System {
+    // The parts of a snapshot that aren't just copied out.
+    fn save(&self) -> (Vec<[u16; 2]>, u8) {
+        let mut rwmap: Vec<[u16; 2]> = Vec::new();
+        for addr in 0 .. 65536 {
+            if !self.writable[addr] { continue }
+            let addr = addr as u16;
+            match rwmap.last_mut() {
+                Some(range) if range[1] as u32 + 1 == addr as u32 =>
+                    range[1] = addr,
+                _ => rwmap.push([addr, addr]),
+            }
+        }
+        let flags = [self.terminate_on_brk, self.terminate_on_infinite_loop,
+                     self.terminate_on_zero_fetch,
+                     self.terminate_on_stack_fetch,
+                     self.terminate_on_vector_fetch,
+                     self.terminate_on_bad_write];
+        let termination_flags = flags.iter().enumerate()
+            .fold(0, |a, (n, &x)| if x { a | 1 << n } else { a });
+        (rwmap, termination_flags)
+    }
+    fn restore(snapshot: &Snapshot, trace_out: Option<Box<dyn Write>>)
+               -> io::Result<System> {
+        if snapshot.sram.len() != 65536 {
+            return Err(snapshot_error("SRAM isn't 64KiB"))
+        }
+        let mut sram = [0; 65536];
+        sram.copy_from_slice(&snapshot.sram);
+        let mut writable = [false; 65536];
+        for range in snapshot.rwmap.iter() {
+            if range[1] < range[0] {
+                return Err(snapshot_error("backwards rwmap range"))
+            }
+            for cell in (&mut writable[range[0] as usize
+                                       .. range[1] as usize + 1]).iter_mut() {
+                *cell = true;
+            }
+        }
+        let mut cycles = Vec::with_capacity(snapshot.cycles.len());
+        for cycle in snapshot.cycles.iter() {
+            let cycle = trace::Cycle::parse(cycle)
+                .ok_or_else(|| snapshot_error("bad cycle string"))?;
+            cycles.push(trace::pack(cycle.typ, cycle.addr, cycle.data));
+        }
+        let mut flips = snapshot.flips.clone();
+        flips.sort();
+        let flag = |n: u8| snapshot.termination_flags & (1 << n) != 0;
+        Ok(System {
+            sram, writable,
+            serial_in_addr: snapshot.serial_in_addr,
+            serial_out_addr: snapshot.serial_out_addr,
+            serial_in_data: snapshot.serial_in_data.data.clone().into(),
+            serial_out_data: snapshot.serial_out_data.data.clone(),
+            serial_out_rem: snapshot.serial_out_rem,
+            vector_has_been_pulled: snapshot.vector_has_been_pulled,
+            cycles_to_report: snapshot.cycles_to_report,
+            cycles,
+            trace_out, trace_error: None,
+            terminate_on_brk: flag(0),
+            terminate_on_infinite_loop: flag(1),
+            terminate_on_zero_fetch: flag(2),
+            terminate_on_stack_fetch: flag(3),
+            terminate_on_vector_fetch: flag(4),
+            terminate_on_bad_write: flag(5),
+            last_pc: snapshot.last_pc,
+            termination_cause: None,
+            cycles_to_run: snapshot.cycles_to_run,
+            num_cycles: snapshot.num_cycles,
+            next_flip: 0,
+            next_flip_cycle: flips.get(0).map(|x| x.cycle)
+                .unwrap_or(u32::max_value()),
+            flips,
+            rdy: snapshot.rdy,
+            res: snapshot.res,
+            reset_pending: snapshot.reset_pending,
+            so: snapshot.so,
+            clear_so_next_cycle: snapshot.clear_so_next_cycle,
+            irq: snapshot.irq,
+            nmi: false,
+            probe: None,
+        })
+    }
+}
+
+// A job in progress on `cpu`. `run_on_emulator_with` is one of these, stepped
+// until it stops; stepping one by hand lets you stop partway through, and
+// snapshot it or look around.
+pub struct Emulator<'a, C: Cpu> {
+    cpu: &'a mut C,
+    system: System,
+    profile: Profile,
+    serial_out_fmt: Option<DataType>,
+    show_disasm: bool,
+    regs: Option<Vec<Registers>>,
+    // true until the CPU has been stepped since it was last reset
+    fresh: bool,
+    symbols: Option<Symbols>,
+}
+
+impl<'a, C: Cpu> Emulator<'a, C> {
+    // Starts `job` on `cpu`, which should be fresh out of reset. Arguments
+    // are as for `run_on_emulator_with`.
+    pub fn new(job: &Job, cpu: &'a mut C, profile: Profile,
+               trace_out: Option<Box<dyn Write>>) -> Emulator<'a, C> {
+        Emulator {
+            cpu,
+            system: System::new(job, profile, trace_out),
+            profile,
+            serial_out_fmt: job.serial_out_fmt,
+            show_disasm: job.show_disasm == Some(true),
+            regs: if job.show_regs == Some(true) { Some(Vec::new()) }
+                  else { None },
+            fresh: true,
+            symbols: job.symbols.clone(),
+        }
+    }
+    // Picks up a job where `snapshot` left it, putting `cpu` in the state it
+    // was in. Cycles go to `trace_out`, if given, from here on.
+    pub fn resume(snapshot: &Snapshot, cpu: &'a mut C,
+                  trace_out: Option<Box<dyn Write>>)
+                  -> io::Result<Emulator<'a, C>> {
+        let system = System::restore(snapshot, trace_out)?;
+        if !cpu.restore(&snapshot.cpu) {
+            return Err(snapshot_error("this CPU can't be restored"))
+        }
+        cpu.set_irq(snapshot.irq);
+        Ok(Emulator {
+            cpu, system,
+            profile: snapshot.profile,
+            serial_out_fmt: snapshot.serial_out_fmt,
+            show_disasm: snapshot.show_disasm,
+            regs: snapshot.regs.clone(),
+            fresh: snapshot.cpu.state == RunState::Reset,
+            symbols: snapshot.symbols.clone(),
+        })
+    }
+    pub fn is_running(&self) -> bool { self.system.is_running() }
+    pub fn num_cycles(&self) -> u32 { self.system.num_cycles }
+    // Why the job ended, if it has.
+    pub fn termination_cause(&self) -> Option<&'static str> {
+        let system = &self.system;
+        if system.num_cycles >= system.cycles_to_run {
+            system.termination_cause.or(Some("limit"))
+        }
+        else { system.termination_cause }
+    }
+    pub fn cpu(&mut self) -> &mut C { self.cpu }
+    // The job's symbols, which label `last_pc` and the disassembly in the
+    // response.
+    pub fn symbols(&self) -> Option<&Symbols> { self.symbols.as_ref() }
+    pub fn set_symbols(&mut self, symbols: Option<Symbols>) {
+        self.symbols = symbols
+    }
+    // Memory, as it would read if there were no serial ports. Pokes ignore
+    // the rwmap.
+    pub fn peek(&self, addr: u16) -> u8 { self.system.sram[addr as usize] }
+    pub fn poke(&mut self, addr: u16, value: u8) {
+        self.system.sram[addr as usize] = value
+    }
+    // Asserts `typ` from cycle `start` for `length` cycles, on top of the
+    // toggles the job scheduled.
+    pub fn pulse(&mut self, typ: FlipType, start: u32, length: u32) {
+        let system = &mut self.system;
+        system.flips.push(Flip { typ, cycle: start, state: true });
+        system.flips.push(Flip { typ, cycle: start.saturating_add(length),
+                                 state: false });
+        system.flips[system.next_flip ..].sort();
+        system.next_flip_cycle = system.flips[system.next_flip].cycle;
+    }
+    // Debugging hooks; see `Probe`.
+    pub fn probe(&mut self) -> &mut Probe {
+        self.system.probe.get_or_insert_with(Default::default)
+    }
+    // Runs one instruction, or interrupt sequence, or the rest of a reset.
+    pub fn step(&mut self) {
+        let system = &mut self.system;
+        if system.reset_pending {
+            system.finish_reset(self.cpu);
+            self.fresh = true;
+            return
+        }
+        if let Some(regs) = self.regs.as_mut() {
+            if regs.len() < self.profile.cycles_to_report() {
+                if let Some(x) = self.cpu.registers() {
+                    regs.push(Registers { cycle: system.num_cycles, ..x });
+                }
+            }
+        }
+        self.cpu.step(system);
+        self.fresh = false;
+    }
+    // Everything needed to `resume` from here, except the CPU's interrupt
+    // latches (see API.md). A job that's over, or that's holding NMIB low (a
+    // restored CPU would see a fresh edge), can't be snapshotted. With a
+    // `trace_out`, the cycles so far are written out first.
+    pub fn snapshot(&mut self) -> io::Result<Snapshot> {
+        if !self.is_running() {
+            return Err(snapshot_error("the job is already over"))
+        }
+        if self.system.nmi {
+            return Err(snapshot_error("NMIB is being held low"))
+        }
+        let mut cpu = self.cpu.save()
+            .ok_or_else(|| snapshot_error("this CPU can't be saved"))?;
+        if self.fresh { cpu.state = RunState::Reset }
+        self.system.flush_trace();
+        let system = &self.system;
+        let (rwmap, termination_flags) = system.save();
+        Ok(Snapshot {
+            cpu: CpuState {
+                registers: Registers { cycle: system.num_cycles,
+                                       ..cpu.registers },
+                ..cpu
+            },
+            profile: self.profile,
+            num_cycles: system.num_cycles,
+            cycles_to_run: system.cycles_to_run,
+            last_pc: system.last_pc,
+            termination_flags,
+            sram: Blob { data: system.sram.to_vec() },
+            rwmap,
+            serial_in_addr: system.serial_in_addr,
+            serial_out_addr: system.serial_out_addr,
+            serial_in_data: Blob {
+                data: system.serial_in_data.iter().cloned().collect()
+            },
+            serial_out_data: Blob { data: system.serial_out_data.clone() },
+            serial_out_rem: system.serial_out_rem,
+            serial_out_fmt: self.serial_out_fmt,
+            vector_has_been_pulled: system.vector_has_been_pulled,
+            cycles_to_report: system.cycles_to_report,
+            cycles: system.cycles.iter()
+                .map(|&x| trace::cycle_string(x)).collect(),
+            show_disasm: self.show_disasm,
+            regs: self.regs.clone(),
+            flips: system.flips[system.next_flip ..].to_vec(),
+            rdy: system.rdy,
+            res: system.res,
+            reset_pending: system.reset_pending,
+            so: system.so,
+            clear_so_next_cycle: system.clear_so_next_cycle,
+            irq: system.irq,
+            symbols: self.symbols.clone(),
+        })
+    }
+    // Runs the job to the end, and returns its response. An error writing to
+    // the `trace_out` is the only error this returns.
+    pub fn finish(mut self) -> io::Result<JobResult> {
+        while self.is_running() { self.step() }
+        let termination_cause = self.termination_cause();
+        let mut system = self.system;
+        system.flush_trace();
+        if let Some(e) = system.trace_error.take() { return Err(e) }
+        if let Some(mut out) = system.trace_out.take() { out.flush()? }
+        let cycles: Vec<String> = system.cycles.iter()
+            .map(|&x| trace::cycle_string(x)).collect();
+        let mut result = JobResult {
+            num_cycles: system.num_cycles,
+            last_pc: system.last_pc,
+            last_pc_symbol: None,
+            termination_cause: termination_cause.unwrap().to_owned(),
+            serial_out_data: self.serial_out_fmt
+                .map(|fmt| fmt.encode(&system.serial_out_data[..])),
+            disasm: if self.show_disasm { Some(trace::decode(&cycles)) }
+                    else { None },
+            cycles,
+            emulator_regs: self.regs,
+            board: None,
+            baud_rate: None,
+        };
+        if let Some(symbols) = self.symbols.as_ref() {
+            symbols.annotate_result(&mut result);
+        }
+        Ok(result)
+    }
+}
+
+// Runs a job to completion on `cpu`, holding it to the same limits as the
+// server.
+pub fn run_on_emulator<C: Cpu>(job: &Job, cpu: &mut C) -> JobResult {
+    run_on_emulator_with(job, cpu, Profile::Strict, None)
+        .expect("nowhere for an error to come from")
+}
+
+// As `run_on_emulator`, but with `profile`'s limits. If `trace_out` is
+// given, the cycles go there, one cycle string per line, instead of into the
+// response; an error writing them is the only error this returns.
+pub fn run_on_emulator_with<C: Cpu>(job: &Job, cpu: &mut C, profile: Profile,
+                                    trace_out: Option<Box<dyn Write>>)
+                                    -> io::Result<JobResult> {
+    Emulator::new(job, cpu, profile, trace_out).finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::get_job_for;
+    use super::super::toy::{self, Toy, LOOP};
+
+    fn run(extra: &str) -> JobResult {
+        let job = toy::job(LOOP, extra);
+        run_on_emulator_with(&job, &mut Toy::new(), Profile::Local, None)
+            .unwrap()
+    }
+
+    #[test]
+    fn reset_pulses() {
+        // the reset sequence after a pulse: two dummy reads at PC, three
+        // stack reads, and the vector, and then LDA #$05 again
+        let sequence = ["702051A", "702051A", "701FC00", "701FB00",
+                        "701FA00", "5FFFC00", "5FFFD02", "F0200A9",
+                        "7020105"];
+        // RESB goes low on cycle 10, in the middle of STA $0010, and the
+        // rest of the STA never happens
+        let x = run(r#", "show_cycles": true, "max_cycles": 20,
+                       "res": [10, 11]"#);
+        let mut expected = vec!["5FFFC00", "5FFFD02", "F0200A9", "7020105",
+                                "F02028D", "701FC00"];
+        expected.extend(&sequence[..]);
+        assert_eq!(x.cycles, expected);
+        // held low for three cycles, it reads the stack three times
+        let x = run(r#", "show_cycles": true, "max_cycles": 22,
+                       "res": [10, 13]"#);
+        let mut expected = vec!["5FFFC00", "5FFFD02", "F0200A9", "7020105",
+                                "F02028D", "701FC00", "701FC00", "701FC00"];
+        expected.extend(&sequence[..]);
+        assert_eq!(x.cycles, expected);
+        assert_eq!(x.termination_cause, "limit");
+    }
+
+    #[test]
+    fn snapshot_and_resume() {
+        // $0200: CLI; LDA #$05; STA $3001; INC A; JMP $0203, with RTI at
+        // $0300 for both interrupts, and the serial ports at $3000-$3001
+        let job = r#"{"init": [{"base": 512, "data": "base64:WKkFjQEwGkwDAg=="},
+                               {"base": 768, "data": "base64:QA=="},
+                               {"base": 65530, "data": "base64:AAMAAgAD"}],
+                      "serial_in_addr": 12288, "serial_out_addr": 12289,
+                      "serial_out_fmt": "base64", "max_cycles": 300,
+                      "show_cycles": true, "show_regs": true,
+                      "show_disasm": true,
+                      "irq": [40, 60], "nmi": [100, 120], "rdy": [80, 83],
+                      "res": [170, 173], "so": [200, 201]}"#;
+        let job = get_job_for(job.as_bytes(), Profile::Local).unwrap();
+        let full = run_on_emulator_with(&job, &mut Toy::new(), Profile::Local,
+                                        None).unwrap();
+        assert_eq!(full.termination_cause, "limit");
+        let texts: Vec<&str> = full.disasm.as_ref().unwrap().iter()
+            .map(|x| x.text.as_str()).collect();
+        for &x in ["(IRQ)", "(NMI)", "(reset)", "RTI"].iter() {
+            assert!(texts.contains(&x), "no {}", x);
+        }
+        let mut snapshots = 0;
+        for at in 5 .. 300 {
+            let mut cpu = Toy::new();
+            let mut emulator = Emulator::new(&job, &mut cpu, Profile::Local,
+                                             None);
+            while emulator.is_running() && emulator.num_cycles() < at {
+                emulator.step();
+            }
+            if !emulator.is_running() { break }
+            let snapshot = match emulator.snapshot() {
+                Ok(x) => x,
+                Err(e) => {
+                    assert_eq!(e.to_string(),
+                               "snapshot: NMIB is being held low");
+                    continue
+                },
+            };
+            let text = serde_json::to_string(&snapshot).unwrap();
+            let snapshot: Snapshot = serde_json::from_str(&text).unwrap();
+            let mut cpu = Toy::new();
+            cpu.pc = 0x1234;
+            let resumed = Emulator::resume(&snapshot, &mut cpu, None).unwrap()
+                .finish().unwrap();
+            assert_eq!(resumed, full, "snapshot at cycle {}", at);
+            snapshots += 1;
+        }
+        assert!(snapshots > 200);
+    }
+}
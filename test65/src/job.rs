@@ -0,0 +1,329 @@
+use std::fmt;
+use std::io;
+use std::ops::Deref;
+use serde::de;
+use serde::de::Error as SerdeDeError;
+use super::symbols::Symbols;
+
+pub const MIN_CYCLE_COUNT: u32 = 9;
+pub const MAX_CYCLE_COUNT: u32 = 10000000;
+pub const MAX_SPECIAL_CYCLES: usize = 20;
+pub const CYCLES_TO_REPORT: usize = 1000;
+// Bytes of serial output a job can produce before SO starts getting pulsed.
+pub const SERIAL_OUT_LIMIT: u32 = 131072;
+
+// Which limits a job is held to. `Strict` is what the server enforces (and
+// what API.md documents); `Local` is for emulators running jobs on your own
+// machine, where the limits only get in the way. Either way, a job runs for
+// at least MIN_CYCLE_COUNT cycles, and no more than 2^32-1.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    Strict, Local
+}
+impl Profile {
+    // Also the default `max_cycles`.
+    pub fn max_cycle_count(&self) -> u32 {
+        match self {
+            Profile::Strict => MAX_CYCLE_COUNT,
+            Profile::Local => u32::max_value(),
+        }
+    }
+    pub fn max_special_cycles(&self) -> usize {
+        match self {
+            Profile::Strict => MAX_SPECIAL_CYCLES,
+            Profile::Local => usize::max_value(),
+        }
+    }
+    pub fn cycles_to_report(&self) -> usize {
+        match self {
+            Profile::Strict => CYCLES_TO_REPORT,
+            Profile::Local => usize::max_value(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataType {
+    Utf8, Base64
+}
+impl DataType {
+    // Formats serial output the way the API returns it.
+    pub fn encode(&self, data: &[u8]) -> String {
+        match self {
+            DataType::Utf8 =>
+                "utf8:".to_owned() + &String::from_utf8_lossy(data),
+            DataType::Base64 =>
+                "base64:".to_owned() + &base64::encode(data),
+        }
+    }
+}
+impl serde::Serialize for DataType {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: serde::Serializer {
+        serializer.serialize_str(match self {
+            DataType::Utf8 => "utf8",
+            DataType::Base64 => "base64",
+        })
+    }
+}
+impl<'de> serde::Deserialize<'de> for DataType {
+    fn deserialize<D>(deserializer: D) -> Result<DataType, D::Error>
+    where D: serde::Deserializer<'de> {
+        deserializer.deserialize_str(DataTypeVisitor)
+    }
+}
+struct DataTypeVisitor;
+impl<'de> de::Visitor<'de> for DataTypeVisitor {
+    type Value = DataType;
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("either utf8 or base64")
+    }
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<DataType, E> {
+        if s == "base64" { Ok(DataType::Base64) }
+        else if s == "utf8" { Ok(DataType::Utf8) }
+        else {
+            Err(E::custom("expected either utf8 or base64"))
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct InitRec {
+    pub base: u16,
+    pub data: Blob,
+    pub size: Option<u32>,
+}
+
+impl InitRec {
+    // How many bytes this record fills. If `size` is larger than the data,
+    // the data repeats.
+    pub fn size(&self) -> usize {
+        self.size.map(|x| x as usize).unwrap_or(self.data.len())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Range {
+    pub start: u16,
+    pub end: u16
+}
+impl<'de> serde::Deserialize<'de> for Range {
+    fn deserialize<D>(deserializer: D) -> Result<Range, D::Error>
+    where D: serde::Deserializer<'de> {
+        deserializer.deserialize_seq(RangeVisitor)
+    }
+}
+struct RangeVisitor;
+impl<'de> de::Visitor<'de> for RangeVisitor {
+    type Value = Range;
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an array [a,b] where a are valid 16-bit addresses and b is >= a")
+    }
+    fn visit_seq<A>(self, mut seq: A) -> Result<Range, A::Error>
+    where A: de::SeqAccess<'de> {
+        let len = seq.size_hint().expect("array lengths should be known");
+        if len != 2 {
+            return Err(de::Error::custom("range must be 2-element array"));
+        }
+        let start = seq.next_element()?.unwrap();
+        let end = seq.next_element()?.unwrap();
+        if end < start {
+            return Err(de::Error::custom("end of range cannot be less than \
+                                          start of range"));
+        }
+        Ok(Range { start, end })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Blob {
+    pub data: Vec<u8>
+}
+impl Deref for Blob {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] { &self.data[..] }
+}
+// Always as base64, which holds anything.
+impl serde::Serialize for Blob {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where S: serde::Serializer {
+        serializer.serialize_str(&DataType::Base64.encode(&self.data[..]))
+    }
+}
+impl<'de> serde::Deserialize<'de> for Blob {
+    fn deserialize<D>(deserializer: D) -> Result<Blob, D::Error>
+    where D: serde::Deserializer<'de> {
+        deserializer.deserialize_str(BlobVisitor)
+    }
+}
+struct BlobVisitor;
+impl<'de> de::Visitor<'de> for BlobVisitor {
+    type Value = Blob;
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string starting with utf8: or base64:")
+    }
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Blob, E> {
+        if s.starts_with("base64:") {
+            match base64::decode(&s[7..]) {
+                Ok(data) => Ok(Blob{data}),
+                Err(_) => Err(E::custom("invalid base64 data")),
+            }
+        }
+        else if s.starts_with("utf8:") {
+            Ok(Blob{data: s[5..].as_bytes().to_vec()})
+        }
+        else {
+            Err(E::custom("binary data must start with utf8: or base64:"))
+        }
+    }
+    fn visit_byte_buf<E: de::Error>(self, data: Vec<u8>) -> Result<Blob, E> {
+        Ok(Blob{data})
+    }
+}
+
+// A job, as described in API.md.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Job {
+    pub init: Vec<InitRec>,
+    pub rwmap: Option<Vec<Range>>,
+    pub serial_in_addr: Option<u16>,
+    pub serial_out_addr: Option<u16>,
+    pub serial_in_data: Option<Blob>,
+    pub serial_out_fmt: Option<DataType>,
+    pub show_cycles: Option<bool>,
+    pub show_disasm: Option<bool>,
+    pub show_regs: Option<bool>,
+    pub max_cycles: Option<u32>,
+    pub terminate_on_brk: Option<bool>,
+    pub terminate_on_infinite_loop: Option<bool>,
+    pub terminate_on_zero_fetch: Option<bool>,
+    pub terminate_on_stack_fetch: Option<bool>,
+    pub terminate_on_vector_fetch: Option<bool>,
+    pub terminate_on_bad_write: Option<bool>,
+    pub nmi: Option<Vec<u32>>,
+    pub irq: Option<Vec<u32>>,
+    pub rdy: Option<Vec<u32>>,
+    pub so: Option<Vec<u32>>,
+    pub res: Option<Vec<u32>>,
+    pub symbols: Option<Symbols>,
+}
+
+impl Job {
+    // The `terminate_on_*` options, as the bitmask in control packet 0x07.
+    pub fn termination_flags(&self) -> u8 {
+        let mut termination_flag = 0x3F;
+        if let Some(false) = self.terminate_on_brk {
+            termination_flag &= !0x01;
+        }
+        if let Some(false) = self.terminate_on_infinite_loop {
+            termination_flag &= !0x02;
+        }
+        if let Some(false) = self.terminate_on_zero_fetch {
+            termination_flag &= !0x04;
+        }
+        if let Some(false) = self.terminate_on_stack_fetch {
+            termination_flag &= !0x08;
+        }
+        if let Some(false) = self.terminate_on_vector_fetch {
+            termination_flag &= !0x10;
+        }
+        if let Some(false) = self.terminate_on_bad_write {
+            termination_flag &= !0x20;
+        }
+        termination_flag
+    }
+    // The `disasm` of the response, if the job asked for it.
+    pub fn disassemble(&self, cycles: &[String])
+                       -> Option<Vec<super::trace::Instruction>> {
+        if self.show_disasm == Some(true) { Some(super::trace::decode(cycles)) }
+        else { None }
+    }
+}
+
+// Parses a job and checks it against the limits in API.md. Signal toggle
+// lists come back sorted, and `show_disasm` turns on `show_cycles`.
+pub fn get_job<R: io::Read>(input: R) -> Result<Job, serde_json::Error> {
+    get_job_for(input, Profile::Strict)
+}
+
+// As `get_job`, but only holding the job to `profile`'s limits.
+pub fn get_job_for<R: io::Read>(input: R, profile: Profile)
+                                -> Result<Job, serde_json::Error> {
+    let mut job: Job = serde_json::from_reader(input)?;
+    for rec in &job.init {
+        let endut = (rec.base as u32).saturating_add(rec.size() as u32);
+        if endut > 65536 {
+            Err(serde_json::Error::custom("Initialization record exceeds \
+                                           size of address space"))?
+        }
+    }
+    if let Some(max_cycles) = job.max_cycles {
+        if max_cycles > profile.max_cycle_count()
+        || max_cycles < MIN_CYCLE_COUNT {
+            Err(serde_json::Error::custom(match profile {
+                Profile::Strict => "Maximum cycle count cannot exceed \
+                                    10,000,000 or be less than 9",
+                Profile::Local => "Maximum cycle count cannot be less than 9",
+            }))?
+        }
+    }
+    for spec in [&mut job.nmi, &mut job.irq, &mut job.rdy,
+                 &mut job.so, &mut job.res].iter_mut() {
+        if let Some(ref mut spec) = spec {
+            if spec.len() > profile.max_special_cycles() {
+                Err(serde_json::Error::custom(format!(
+                    "A given special signal may not toggle more than {} \
+                     times", profile.max_special_cycles())))?
+            }
+            spec.sort_unstable();
+        }
+    }
+    if job.show_disasm == Some(true) {
+        job.show_cycles = Some(true);
+    }
+    Ok(job)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job_with(profile: Profile, extra: &str) -> Result<Job, String> {
+        let json = format!(r#"{{"init": [{{"base": 512, "data": "utf8:x"}}]
+                               {}}}"#, extra);
+        get_job_for(json.as_bytes(), profile).map_err(|e| e.to_string())
+    }
+
+    fn toggles(n: u32) -> String {
+        let cycles: Vec<String> = (0 .. n).rev()
+            .map(|x| (100 + x * 10).to_string()).collect();
+        format!(r#", "irq": [{}]"#, cycles.join(","))
+    }
+
+    #[test]
+    fn strict_limits() {
+        let job = job_with(Profile::Strict, &toggles(20)).unwrap();
+        let irq = job.irq.unwrap();
+        assert_eq!(irq.len(), 20);
+        assert!(irq.windows(2).all(|x| x[0] <= x[1]), "{:?}", irq);
+        let e = job_with(Profile::Strict, &toggles(21)).unwrap_err();
+        assert!(e.contains("more than 20 times"), "{}", e);
+        job_with(Profile::Strict, r#", "max_cycles": 10000000"#).unwrap();
+        let e = job_with(Profile::Strict, r#", "max_cycles": 10000001"#)
+            .unwrap_err();
+        assert!(e.contains("cannot exceed 10,000,000"), "{}", e);
+        assert!(job_with(Profile::Strict, r#", "max_cycles": 8"#).is_err());
+    }
+
+    #[test]
+    fn local_limits() {
+        let job = job_with(Profile::Local, &toggles(1000)).unwrap();
+        let irq = job.irq.unwrap();
+        assert_eq!(irq.len(), 1000);
+        assert!(irq.windows(2).all(|x| x[0] <= x[1]));
+        job_with(Profile::Local, r#", "max_cycles": 4294967295"#).unwrap();
+        let e = job_with(Profile::Local, r#", "max_cycles": 8"#).unwrap_err();
+        assert!(e.contains("cannot be less than 9"), "{}", e);
+    }
+}
@@ -0,0 +1,48 @@
+// The 65test job model, the driver that runs jobs on real hardware, and the
+// harness that runs them on an emulator. The CGI script, the emulator core,
+// and anything else that wants to run jobs without going through HTTP build
+// on this.
+
+extern crate base64;
+extern crate serde;
+extern crate serde_json;
+#[macro_use]
+extern crate serde_derive;
+extern crate crc;
+#[cfg(feature = "hardware")]
+extern crate serial;
+#[cfg(feature = "hardware")]
+extern crate fs2;
+
+pub mod cobs;
+pub mod coverage;
+pub mod debugger;
+pub mod disasm;
+pub mod gdb;
+pub mod harness;
+mod job;
+pub mod profiler;
+mod result;
+pub mod symbols;
+#[cfg(test)]
+mod toy;
+pub mod trace;
+pub mod vcd;
+#[cfg(feature = "hardware")]
+pub mod caps;
+#[cfg(feature = "hardware")]
+pub mod capture;
+#[cfg(feature = "hardware")]
+pub mod comm;
+#[cfg(feature = "hardware")]
+pub mod discover;
+#[cfg(feature = "hardware")]
+mod hardware;
+#[cfg(feature = "hardware")]
+pub mod pool;
+
+pub use job::*;
+pub use result::JobResult;
+pub use harness::{run_on_emulator, run_on_emulator_with};
+#[cfg(feature = "hardware")]
+pub use hardware::{attempt_job, open_comm, run_on_hardware};
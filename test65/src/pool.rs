@@ -0,0 +1,330 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use fs2::FileExt;
+use super::caps::Capabilities;
+
+// One serial port path per line. Blank lines and lines starting with `#` are
+// ignored.
+pub const POOL_PATH: &str = ".65test_serial_path.txt";
+// A board that fails this many jobs in a row is quarantined...
+const QUARANTINE_THRESHOLD: u32 = 3;
+// ...for this many seconds after its most recent failure. After that, it
+// starts over with a clean slate.
+const QUARANTINE_SECS: u64 = 600;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// If no board frees up in this long, give up.
+const ACQUIRE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// What the last successful job on a board found out about it, so the next
+// job can skip asking.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub caps: Capabilities,
+    pub baud_rate: u32,
+}
+
+// A board from the pool, locked for our exclusive use until dropped.
+// Each board has a lock file next to the pool file. Besides providing the
+// lock, it records how many jobs in a row have failed on that board, when the
+// last failure was, and (on a second line) the board's settings. It's only
+// ever read or written while locked.
+pub struct Board {
+    path: PathBuf,
+    lock: File,
+    failures: u32,
+    last_failure: u64,
+    settings: Option<Settings>,
+    // the time, in seconds since the epoch
+    clock: fn() -> u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs()).unwrap_or(0)
+}
+
+fn lock_path(port_path: &Path) -> PathBuf {
+    let name: String = port_path.to_string_lossy().chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    format!(".65test_lock{}", name).into()
+}
+
+impl Board {
+    pub fn try_lock(path: &Path) -> io::Result<Option<Board>> {
+        Board::try_lock_with(path, now)
+    }
+    fn try_lock_with(path: &Path, clock: fn() -> u64)
+                     -> io::Result<Option<Board>> {
+        let mut lock = OpenOptions::new().read(true).write(true).create(true)
+            .truncate(false).open(lock_path(path))?;
+        match lock.try_lock_exclusive() {
+            Ok(_) => (),
+            Err(ref e) if e.kind() == fs2::lock_contended_error().kind()
+                => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mut contents = String::new();
+        lock.read_to_string(&mut contents)?;
+        let mut lines = contents.lines();
+        let mut health = lines.next().unwrap_or("").split_whitespace()
+            .map(|x| x.parse().unwrap_or(0));
+        let failures = health.next().unwrap_or(0) as u32;
+        let last_failure = health.next().unwrap_or(0);
+        let settings = lines.next().and_then(parse_settings);
+        let mut board = Board {
+            path: path.to_owned(), lock, failures, last_failure, settings,
+            clock,
+        };
+        if board.failures >= QUARANTINE_THRESHOLD && !board.is_quarantined() {
+            board.failures = 0;
+            board.save_health()?;
+        }
+        Ok(Some(board))
+    }
+    pub fn path(&self) -> &Path { &self.path }
+    pub fn settings(&self) -> Option<&Settings> { self.settings.as_ref() }
+    pub fn is_quarantined(&self) -> bool {
+        self.failures >= QUARANTINE_THRESHOLD
+            && (self.clock)().saturating_sub(self.last_failure)
+               < QUARANTINE_SECS
+    }
+    fn save_health(&mut self) -> io::Result<()> {
+        self.lock.seek(SeekFrom::Start(0))?;
+        self.lock.set_len(0)?;
+        writeln!(self.lock, "{} {}", self.failures, self.last_failure)?;
+        if let Some(settings) = &self.settings {
+            let hex: String = settings.caps.to_bytes().iter()
+                .map(|x| format!("{:02X}", x)).collect();
+            writeln!(self.lock, "{} {}", settings.baud_rate, hex)?;
+        }
+        Ok(())
+    }
+    // Remembers the settings the job used for the next job.
+    pub fn report_success(&mut self, settings: Option<Settings>)
+                          -> io::Result<()> {
+        if self.failures == 0 && settings == self.settings { return Ok(()) }
+        self.failures = 0;
+        self.settings = settings;
+        self.save_health()
+    }
+    // An error of kind `InvalidInput` is the job's fault, not the board's,
+    // and doesn't count. Anything else makes us forget the board's settings,
+    // in case they're why it failed.
+    pub fn report_failure(&mut self, error: &io::Error) -> io::Result<()> {
+        if error.kind() == io::ErrorKind::InvalidInput { return Ok(()) }
+        self.settings = None;
+        self.failures = self.failures.saturating_add(1);
+        self.last_failure = (self.clock)();
+        if self.failures == QUARANTINE_THRESHOLD {
+            eprintln!("board {:?} failed {} jobs in a row, quarantining it",
+                      self.path, self.failures);
+        }
+        self.save_health()
+    }
+}
+
+// A baud rate and the capabilities payload, in hex. Anything else is
+// forgotten.
+fn parse_settings(line: &str) -> Option<Settings> {
+    let mut fields = line.split_whitespace();
+    let baud_rate = fields.next()?.parse().ok()?;
+    let hex = fields.next()?;
+    if !hex.is_ascii() || hex.len() % 2 != 0 { return None }
+    let bytes: Option<Vec<u8>> = (0 .. hex.len()).step_by(2)
+        .map(|n| u8::from_str_radix(&hex[n .. n+2], 16).ok())
+        .collect();
+    let caps = Capabilities::parse(&bytes?).ok()?;
+    Some(Settings { caps, baud_rate })
+}
+
+pub fn read_pool() -> io::Result<Vec<PathBuf>> {
+    let file = File::open(POOL_PATH).map_err(|e| {
+        io::Error::new(e.kind(), format!("{}: {}", POOL_PATH, e))
+    })?;
+    let mut ret = Vec::new();
+    for line in io::BufReader::new(file).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("#") { continue }
+        ret.push(line.into());
+    }
+    Ok(ret)
+}
+
+// Quarantined boards are only used if every board in the pool is quarantined;
+// otherwise, we'd rather wait for a healthy one. An error of kind `TimedOut`
+// means every board was busy the whole time.
+pub fn acquire() -> io::Result<Board> {
+    acquire_from(&read_pool()?, ACQUIRE_TIMEOUT)
+}
+
+fn acquire_from(paths: &[PathBuf], timeout: Duration) -> io::Result<Board> {
+    if paths.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::Other,
+                                  "No boards are listed in the pool"))
+    }
+    let deadline = Instant::now() + timeout;
+    loop {
+        let mut any_busy = false;
+        let mut fallback = None;
+        for path in paths.iter() {
+            match Board::try_lock(path)? {
+                None => any_busy = true,
+                Some(board) => {
+                    if !board.is_quarantined() { return Ok(board) }
+                    else if fallback.is_none() { fallback = Some(board) }
+                },
+            }
+        }
+        if !any_busy {
+            if let Some(board) = fallback {
+                eprintln!("every board is quarantined, trying {:?} anyway",
+                          board.path);
+                return Ok(board)
+            }
+        }
+        std::mem::drop(fallback);
+        if Instant::now() >= deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                      format!("No board was free for {} \
+                                               seconds", timeout.as_secs())))
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::fs;
+    use super::super::caps::LEGACY;
+
+    const T: u64 = 1_000_000;
+
+    thread_local! {
+        static NOW: Cell<u64> = Cell::new(T);
+    }
+
+    fn fake_now() -> u64 { NOW.with(|x| x.get()) }
+    fn set_now(now: u64) { NOW.with(|x| x.set(now)) }
+
+    // A port path for a test, whose lock file goes away with it.
+    struct Port(PathBuf);
+
+    impl Drop for Port {
+        fn drop(&mut self) { let _ = fs::remove_file(lock_path(&self.0)); }
+    }
+
+    fn port(name: &str) -> Port {
+        let port = Port(format!("/dev/65test-pool-{}-{}", std::process::id(),
+                                name).into());
+        let _ = fs::remove_file(lock_path(&port.0));
+        port
+    }
+
+    fn lock(port: &Port) -> Board {
+        Board::try_lock_with(&port.0, fake_now).unwrap().unwrap()
+    }
+
+    fn health(port: &Port) -> String {
+        fs::read_to_string(lock_path(&port.0)).unwrap()
+    }
+
+    fn hardware_error() -> io::Error {
+        io::Error::new(io::ErrorKind::Other, "no response")
+    }
+
+    #[test]
+    fn health_round_trip() {
+        let port = port("round-trip");
+        let mut board = lock(&port);
+        assert_eq!((board.failures, board.last_failure), (0, 0));
+        assert!(Board::try_lock(&port.0).unwrap().is_none());
+        board.report_failure(&hardware_error()).unwrap();
+        board.report_failure(&hardware_error()).unwrap();
+        std::mem::drop(board);
+        assert_eq!(health(&port), format!("2 {}\n", T));
+        let mut board = lock(&port);
+        assert_eq!((board.failures, board.last_failure), (2, T));
+        board.report_success(None).unwrap();
+        std::mem::drop(board);
+        assert_eq!(health(&port), format!("0 {}\n", T));
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        let port = port("settings");
+        let settings = Settings { caps: LEGACY, baud_rate: 250000 };
+        let mut board = lock(&port);
+        assert_eq!(board.settings(), None);
+        board.report_success(Some(settings.clone())).unwrap();
+        std::mem::drop(board);
+        assert_eq!(health(&port), "0 0\n250000 00010008002000780\
+                                   4B00078000200003F000000FF\n");
+        let mut board = lock(&port);
+        assert_eq!(board.settings(), Some(&settings));
+        // a failure might have been their fault, so they're forgotten
+        board.report_failure(&hardware_error()).unwrap();
+        assert_eq!(board.settings(), None);
+        std::mem::drop(board);
+        assert_eq!(health(&port), format!("1 {}\n", T));
+        // as is anything that doesn't make sense
+        fs::write(lock_path(&port.0), "0 0\n250000 0001\n").unwrap();
+        assert_eq!(lock(&port).settings(), None);
+    }
+
+    #[test]
+    fn quarantine() {
+        let port = port("quarantine");
+        set_now(T);
+        let mut board = lock(&port);
+        board.report_failure(&hardware_error()).unwrap();
+        board.report_failure(&hardware_error()).unwrap();
+        // a job the board can't run isn't the board's fault
+        let bad_job = io::Error::new(io::ErrorKind::InvalidInput, "no NMI");
+        board.report_failure(&bad_job).unwrap();
+        assert!(!board.is_quarantined());
+        board.report_failure(&hardware_error()).unwrap();
+        assert!(board.is_quarantined());
+        std::mem::drop(board);
+        set_now(T + QUARANTINE_SECS - 1);
+        let board = lock(&port);
+        assert!(board.is_quarantined());
+        std::mem::drop(board);
+        // once the quarantine runs out, the board starts over
+        set_now(T + QUARANTINE_SECS);
+        let mut board = lock(&port);
+        assert!(!board.is_quarantined());
+        assert_eq!(board.failures, 0);
+        assert_eq!(health(&port), format!("0 {}\n", T));
+        board.report_failure(&hardware_error()).unwrap();
+        assert!(!board.is_quarantined());
+    }
+
+    #[test]
+    fn acquire_deadline() {
+        let busy = port("busy");
+        let _held = Board::try_lock(&busy.0).unwrap().unwrap();
+        let start = Instant::now();
+        let e = acquire_from(&[busy.0.clone()], Duration::from_millis(300))
+            .err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        // a quarantined board is only used if every board is quarantined
+        let sick = port("sick");
+        fs::write(lock_path(&sick.0), format!("3 {}\n", now())).unwrap();
+        let healthy = port("healthy");
+        let paths = [sick.0.clone(), healthy.0.clone()];
+        let board = acquire_from(&paths, Duration::from_secs(0)).unwrap();
+        assert_eq!(board.path(), healthy.0.as_path());
+        std::mem::drop(board);
+        let board = acquire_from(&paths[..1], Duration::from_secs(0)).unwrap();
+        assert_eq!(board.path(), sick.0.as_path());
+        assert!(acquire_from(&[], Duration::from_secs(0)).is_err());
+    }
+}
@@ -0,0 +1,371 @@
+// Where a run spends its cycles: per PC, per opcode, and per address range,
+// plus how often each page of memory is read and written. Like coverage, it
+// is worked out from the bus cycles alone. Each cycle belongs to the
+// instruction whose opcode fetch came last before it, including any cycles
+// RDY stalled it for. Interrupts and resets are counted apart from the
+// instructions they cut in on: a sequence that pulls the reset vector is a
+// reset, and one that pulls another vector without being a BRK is an
+// interrupt.
+
+use std::io;
+use super::disasm;
+use super::symbols::Symbols;
+use super::trace::{Cycle, OPCODE_READ, VECTOR_READ};
+
+// An address range to total up, e.g. a subroutine. `start` and `end` are
+// both inclusive.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProfileRange {
+    pub name: String,
+    pub start: u16,
+    pub end: u16,
+    #[serde(default)]
+    pub executed: u64,
+    #[serde(default)]
+    pub cycles: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PcProfile {
+    pub pc: u16,
+    // the opcode last executed there
+    pub opcode: u8,
+    pub mnemonic: String,
+    pub executed: u64,
+    pub cycles: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symbol: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OpcodeProfile {
+    pub opcode: u8,
+    pub mnemonic: String,
+    pub executed: u64,
+    pub cycles: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PageProfile {
+    pub page: u8,
+    pub reads: u64,
+    pub writes: u64,
+}
+
+// What `Profiler::report` makes, and what rustcore --profile writes. Only
+// PCs, opcodes, and pages that saw any use are listed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProfileReport {
+    pub cycles: u64,
+    pub instructions: u64,
+    pub interrupts: u64,
+    pub interrupt_cycles: u64,
+    pub resets: u64,
+    pub reset_cycles: u64,
+    pub ranges: Vec<ProfileRange>,
+    pub pcs: Vec<PcProfile>,
+    pub opcodes: Vec<OpcodeProfile>,
+    pub pages: Vec<PageProfile>,
+}
+
+// Feed it a run a cycle at a time, packed the way report packets carry them,
+// then call `finish`.
+pub struct Profiler {
+    pc_executed: Vec<u64>,
+    pc_cycles: Vec<u64>,
+    pc_opcode: Vec<u8>,
+    opcode_executed: Vec<u64>,
+    opcode_cycles: Vec<u64>,
+    page_reads: Vec<u64>,
+    page_writes: Vec<u64>,
+    interrupts: u64,
+    interrupt_cycles: u64,
+    resets: u64,
+    reset_cycles: u64,
+    // the instruction in progress: its opcode fetch (None before the first
+    // one), how many cycles it has taken so far, and the last vector it
+    // pulled
+    fetch: Option<Cycle>,
+    cycles: u64,
+    vector: Option<u16>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            pc_executed: vec![0; 65536],
+            pc_cycles: vec![0; 65536],
+            pc_opcode: vec![0; 65536],
+            opcode_executed: vec![0; 256],
+            opcode_cycles: vec![0; 256],
+            page_reads: vec![0; 256],
+            page_writes: vec![0; 256],
+            interrupts: 0,
+            interrupt_cycles: 0,
+            resets: 0,
+            reset_cycles: 0,
+            fetch: None,
+            cycles: 0,
+            vector: None,
+        }
+    }
+    pub fn add_cycle(&mut self, cycle: u32) {
+        let cycle = Cycle { typ: (cycle >> 24) as u8 & 15,
+                            addr: (cycle >> 8) as u16, data: cycle as u8 };
+        if cycle.typ == OPCODE_READ {
+            self.end_instruction();
+            self.fetch = Some(cycle);
+        }
+        else if cycle.typ == VECTOR_READ && cycle.addr & 1 == 0 {
+            self.vector = Some(cycle.addr);
+        }
+        // bit 0 of the type is set for reads
+        let page = (cycle.addr >> 8) as usize;
+        if cycle.typ & 1 != 0 { self.page_reads[page] += 1 }
+        else { self.page_writes[page] += 1 }
+        self.cycles += 1;
+    }
+    // Ends a run fed in with `add_cycle`.
+    pub fn finish(&mut self) {
+        self.end_instruction();
+        self.fetch = None;
+    }
+    fn end_instruction(&mut self) {
+        let cycles = std::mem::replace(&mut self.cycles, 0);
+        let vector = self.vector.take();
+        if cycles == 0 { return }
+        match (self.fetch, vector) {
+            // the cycles before the first opcode fetch are the tail of the
+            // initial reset
+            (None, _) | (_, Some(0xFFFC)) => {
+                self.resets += 1;
+                self.reset_cycles += cycles;
+            },
+            (Some(fetch), Some(_)) if fetch.data != disasm::BRK => {
+                self.interrupts += 1;
+                self.interrupt_cycles += cycles;
+            },
+            (Some(fetch), _) => {
+                let (pc, opcode) = (fetch.addr as usize, fetch.data);
+                self.pc_executed[pc] += 1;
+                self.pc_cycles[pc] += cycles;
+                self.pc_opcode[pc] = opcode;
+                self.opcode_executed[opcode as usize] += 1;
+                self.opcode_cycles[opcode as usize] += cycles;
+            },
+        }
+    }
+    // Totals everything up, including the given ranges. With symbols, each
+    // label that any code ran under gets a range too, running up to the next
+    // label, and PCs are labeled.
+    pub fn report(&self, ranges: &[ProfileRange], symbols: Option<&Symbols>)
+                  -> ProfileReport {
+        let total = |range: &ProfileRange| {
+            let span = range.start as usize ..= range.end as usize;
+            ProfileRange {
+                executed: self.pc_executed[span.clone()].iter().sum(),
+                cycles: self.pc_cycles[span].iter().sum(),
+                .. range.clone()
+            }
+        };
+        let mut ranges: Vec<ProfileRange> = ranges.iter().map(total)
+            .collect();
+        if let Some(symbols) = symbols {
+            ranges.extend(symbols.ranges().iter().map(total)
+                          .filter(|x| x.executed > 0));
+        }
+        let pcs = (0 .. 65536).filter(|&pc| self.pc_executed[pc] > 0)
+            .map(|pc| PcProfile {
+                pc: pc as u16,
+                opcode: self.pc_opcode[pc],
+                mnemonic: disasm::mnemonic(self.pc_opcode[pc]).to_owned(),
+                executed: self.pc_executed[pc],
+                cycles: self.pc_cycles[pc],
+                symbol: symbols.and_then(|x| x.lookup(pc as u16)),
+            }).collect();
+        let opcodes = (0 .. 256).filter(|&x| self.opcode_executed[x] > 0)
+            .map(|x| OpcodeProfile {
+                opcode: x as u8,
+                mnemonic: disasm::mnemonic(x as u8).to_owned(),
+                executed: self.opcode_executed[x],
+                cycles: self.opcode_cycles[x],
+            }).collect();
+        let pages = (0 .. 256)
+            .filter(|&x| self.page_reads[x] > 0 || self.page_writes[x] > 0)
+            .map(|x| PageProfile {
+                page: x as u8,
+                reads: self.page_reads[x],
+                writes: self.page_writes[x],
+            }).collect();
+        let instructions = self.pc_executed.iter().sum();
+        let cycles = self.pc_cycles.iter().sum::<u64>()
+            + self.interrupt_cycles + self.reset_cycles;
+        ProfileReport {
+            cycles, instructions,
+            interrupts: self.interrupts,
+            interrupt_cycles: self.interrupt_cycles,
+            resets: self.resets,
+            reset_cycles: self.reset_cycles,
+            ranges, pcs, opcodes, pages,
+        }
+    }
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    if whole == 0 { 0.0 } else { part as f64 * 100.0 / whole as f64 }
+}
+
+fn write_heatmap(out: &mut dyn io::Write, title: &str, counts: &[u64])
+                 -> io::Result<()> {
+    writeln!(out, "{} per page:", title)?;
+    write!(out, "   ")?;
+    for n in 0 .. 16 { write!(out, "{:>6}", format!("_{:X}", n))? }
+    writeln!(out)?;
+    for (row, chunk) in counts.chunks(16).enumerate() {
+        write!(out, "{:X}_ ", row)?;
+        for &x in chunk {
+            let count = match x {
+                0 => "-".to_owned(),
+                n if n < 100000 => n.to_string(),
+                n if n < 100000000 => format!("{}k", n / 1000),
+                n => format!("{}M", n / 1000000),
+            };
+            write!(out, "{:>6}", count)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+impl ProfileReport {
+    // A flat report: totals, then ranges, PCs, and opcodes, each from the
+    // most cycles to the fewest, then the read and write heatmaps.
+    pub fn write_report(&self, out: &mut dyn io::Write) -> io::Result<()> {
+        let total = self.cycles;
+        writeln!(out, "{} cycles: {} instructions, {} interrupts ({} cycles), \
+                       {} resets ({} cycles)", total, self.instructions,
+                 self.interrupts, self.interrupt_cycles, self.resets,
+                 self.reset_cycles)?;
+        if !self.ranges.is_empty() {
+            let mut ranges: Vec<&ProfileRange> = self.ranges.iter().collect();
+            ranges.sort_by(|a, b| b.cycles.cmp(&a.cycles)
+                           .then(a.start.cmp(&b.start)));
+            writeln!(out)?;
+            writeln!(out, " %cycles      cycles    executed  range")?;
+            for x in ranges {
+                writeln!(out, "{:7.2}% {:>11} {:>11}  {} (${:04X}-${:04X})",
+                         percent(x.cycles, total), x.cycles, x.executed,
+                         x.name, x.start, x.end)?;
+            }
+        }
+        let mut pcs: Vec<&PcProfile> = self.pcs.iter().collect();
+        pcs.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.pc.cmp(&b.pc)));
+        writeln!(out)?;
+        writeln!(out, " %cycles   cumulative      cycles    executed  \
+                       avg  pc")?;
+        let mut cumulative = 0;
+        for x in pcs {
+            cumulative += x.cycles;
+            let symbol = x.symbol.as_ref().map(|x| format!(" ({})", x))
+                .unwrap_or_default();
+            writeln!(out, "{:7.2}% {:11.2}% {:>11} {:>11} {:4.1}  ${:04X}\
+                           {} {}", percent(x.cycles, total),
+                     percent(cumulative, total), x.cycles, x.executed,
+                     x.cycles as f64 / x.executed as f64, x.pc, symbol,
+                     x.mnemonic)?;
+        }
+        let mut opcodes: Vec<&OpcodeProfile> = self.opcodes.iter().collect();
+        opcodes.sort_by(|a, b| b.cycles.cmp(&a.cycles)
+                        .then(a.opcode.cmp(&b.opcode)));
+        writeln!(out)?;
+        writeln!(out, " %cycles      cycles    executed   avg  opcode")?;
+        for x in opcodes {
+            writeln!(out, "{:7.2}% {:>11} {:>11}  {:4.1}  ${:02X} {} {:?}",
+                     percent(x.cycles, total), x.cycles, x.executed,
+                     x.cycles as f64 / x.executed as f64, x.opcode,
+                     x.mnemonic, disasm::mode(x.opcode))?;
+        }
+        let mut reads = vec![0; 256];
+        let mut writes = vec![0; 256];
+        for x in self.pages.iter() {
+            reads[x.page as usize] = x.reads;
+            writes[x.page as usize] = x.writes;
+        }
+        writeln!(out)?;
+        write_heatmap(out, "Reads", &reads)?;
+        writeln!(out)?;
+        write_heatmap(out, "Writes", &writes)
+    }
+}
+
+// Parses a range for the command line: NAME=START-END, in hex.
+pub fn parse_range(s: &str) -> Option<ProfileRange> {
+    let eq = s.find('=')?;
+    let (name, span) = (&s[.. eq], &s[eq + 1 ..]);
+    let dash = span.find('-')?;
+    let start = u16::from_str_radix(span[.. dash].trim_start_matches('$'),
+                                    16).ok()?;
+    let end = u16::from_str_radix(span[dash + 1 ..].trim_start_matches('$'),
+                                  16).ok()?;
+    if name.is_empty() || end < start { return None }
+    Some(ProfileRange { name: name.to_owned(), start, end, executed: 0,
+                        cycles: 0 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_trace() {
+        let mut profiler = Profiler::new();
+        for cycle in [
+            // the end of the reset sequence
+            "5FFFC00", "5FFFD02",
+            // $0200: LDA $10, STA $3000, LDA $10
+            "F0200A5", "7020110", "7001042",
+            "F02028D", "7020300", "7020430", "6300042",
+            "F0200A5", "7020110", "7001042",
+            // LDA $10 again, stalled by RDY for a cycle
+            "F0200A5", "7020110", "7020110", "7001042",
+            // an IRQ cuts in at $0202
+            "F0202EA", "70202EA", "601FF02", "601FE02", "601FD20",
+            "5FFFE00", "5FFFF03",
+            // $0300: BRK, which is an instruction of its own
+            "F030000", "7030100", "601FF03", "601FE02", "601FD30",
+            "5FFFE00", "5FFFF03",
+        ].iter() {
+            profiler.add_cycle(u32::from_str_radix(cycle, 16).unwrap());
+        }
+        profiler.finish();
+        let report = profiler.report(&[parse_range("loop=0200-$0202")
+                                        .unwrap()], None);
+        assert_eq!((report.cycles, report.instructions), (30, 5));
+        assert_eq!((report.resets, report.reset_cycles), (1, 2));
+        assert_eq!((report.interrupts, report.interrupt_cycles), (1, 7));
+        let opcodes: Vec<(u8, u64, u64)> = report.opcodes.iter()
+            .map(|x| (x.opcode, x.executed, x.cycles)).collect();
+        assert_eq!(opcodes, [(0x00, 1, 7), (0x8D, 1, 4), (0xA5, 3, 10)]);
+        let pcs: Vec<(u16, &str, u64, u64)> = report.pcs.iter()
+            .map(|x| (x.pc, x.mnemonic.as_str(), x.executed, x.cycles))
+            .collect();
+        assert_eq!(pcs, [(0x0200, "LDA", 3, 10), (0x0202, "STA", 1, 4),
+                         (0x0300, "BRK", 1, 7)]);
+        let pages: Vec<(u8, u64, u64)> = report.pages.iter()
+            .map(|x| (x.page, x.reads, x.writes)).collect();
+        assert_eq!(pages, [(0x00, 3, 0), (0x01, 0, 6), (0x02, 12, 0),
+                           (0x03, 2, 0), (0x30, 0, 1), (0xFF, 6, 0)]);
+        assert_eq!((report.ranges[0].executed, report.ranges[0].cycles),
+                   (4, 14));
+    }
+
+    #[test]
+    fn ranges() {
+        let range = parse_range("main=$0200-02FF").unwrap();
+        assert_eq!((range.name.as_str(), range.start, range.end),
+                   ("main", 0x0200, 0x02FF));
+        assert!(parse_range("main=0300-0200").is_none());
+        assert!(parse_range("=0200-0300").is_none());
+        assert!(parse_range("main").is_none());
+    }
+}
@@ -0,0 +1,29 @@
+use super::harness::Registers;
+use super::trace::Instruction;
+
+// The outcome of a job, as described under "Response" in API.md. Whatever ran
+// the job fills in as much as it knows; `board` and `baud_rate` only make
+// sense for real hardware.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JobResult {
+    pub num_cycles: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_pc: Option<u16>,
+    // `last_pc` as label+offset, if the job had symbols
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_pc_symbol: Option<String>,
+    pub termination_cause: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial_out_data: Option<String>,
+    #[serde(default)]
+    pub cycles: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disasm: Option<Vec<Instruction>>,
+    // Only an emulator can see these; see `show_regs` in API.md.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub emulator_regs: Option<Vec<Registers>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub board: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub baud_rate: Option<u32>,
+}
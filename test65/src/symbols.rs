@@ -0,0 +1,245 @@
+// Labels for addresses, from an assembler's symbol file, so that addresses
+// can be shown as `label+offset`. Three formats are understood, and told
+// apart line by line:
+// - WLA-DX `.sym` files, from `wlalink -S`: `00:0234 main` under `[labels]`
+// - VICE label files, like ld65 -Ln writes: `al 000234 .main`
+// - ca65 debug files, from ld65 --dbgfile: `sym` lines of `type=lab`
+// Only labels are used; constants (`[definitions]`, `type=equ`) aren't
+// addresses.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use super::{Blob, JobResult};
+use super::profiler::ProfileRange;
+use super::trace::Instruction;
+
+// An address farther than this past the nearest label below it is left
+// bare; it's more likely unlabeled data than part of that label's routine.
+const MAX_OFFSET: u16 = 0x1000;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Symbols {
+    // sorted by address, one label per address
+    labels: Vec<(u16, String)>,
+}
+
+fn parse_hex(x: &str) -> Option<u16> {
+    let x = x.trim_start_matches("0x").trim_start_matches('$');
+    u32::from_str_radix(x, 16).ok().filter(|&x| x <= 0xFFFF)
+        .map(|x| x as u16)
+}
+
+// `name="main",val=0x234,type=lab` and so on, from a ca65 `sym` line.
+fn parse_dbg_sym(fields: &str) -> Option<(u16, String)> {
+    let (mut name, mut val, mut typ) = (None, None, None);
+    for field in fields.split(',') {
+        let mut it = field.splitn(2, '=');
+        match (it.next()?, it.next()?) {
+            ("name", x) => name = Some(x.trim_matches('"').to_owned()),
+            ("val", x) => val = parse_hex(x),
+            ("type", x) => typ = Some(x),
+            _ => (),
+        }
+    }
+    if typ != Some("lab") { return None }
+    Some((val?, name?))
+}
+
+impl Symbols {
+    // Lines that aren't labels are skipped, so this can't fail; a test with
+    // no labels has a symbol file with none in it.
+    pub fn parse(text: &str) -> Symbols {
+        let mut labels = Vec::new();
+        // WLA-DX labels come before any section, or under [labels]
+        let mut in_labels = true;
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with(';') { continue }
+            if line.starts_with('[') {
+                in_labels = line == "[labels]";
+                continue
+            }
+            let mut words = line.split_whitespace();
+            let first = words.next().unwrap_or("");
+            let label = match first {
+                "al" => {
+                    // VICE puts a memory space in front, e.g. `C:0234`
+                    let addr = words.next().map(|x| {
+                        x.rsplit(':').next().unwrap_or(x)
+                    }).and_then(parse_hex);
+                    let name = words.next()
+                        .map(|x| x.trim_start_matches('.').to_owned());
+                    addr.zip(name)
+                },
+                "sym" => words.next().and_then(parse_dbg_sym),
+                _ if in_labels && first.contains(':') => {
+                    let addr = first.rsplit(':').next().and_then(parse_hex);
+                    let name = words.next().map(str::to_owned);
+                    addr.zip(name)
+                },
+                _ => None,
+            };
+            labels.extend(label);
+        }
+        // Where several labels share an address, the first one that isn't
+        // local (WLA-DX's start with _, ca65's with @) wins.
+        let local = |x: &str| x.starts_with('_') || x.starts_with('@');
+        labels.sort_by_key(|(addr, name)| (*addr, local(name)));
+        labels.dedup_by_key(|(addr, _)| *addr);
+        Symbols { labels }
+    }
+    pub fn load(path: &str) -> io::Result<Symbols> {
+        let mut text = String::new();
+        File::open(path).and_then(|mut x| x.read_to_string(&mut text))
+            .map_err(|e| {
+                io::Error::new(io::ErrorKind::Other,
+                               format!("{}: {}", path, e))
+            })?;
+        Ok(Symbols::parse(&text))
+    }
+    // `main` or `main+$4`, or None if no label is close enough below.
+    pub fn lookup(&self, addr: u16) -> Option<String> {
+        let n = match self.labels.binary_search_by_key(&addr, |x| x.0) {
+            Ok(n) => n,
+            Err(0) => return None,
+            Err(n) => n - 1,
+        };
+        let (base, ref name) = self.labels[n];
+        match addr - base {
+            0 => Some(name.clone()),
+            offset if offset <= MAX_OFFSET =>
+                Some(format!("{}+${:X}", name, offset)),
+            _ => None,
+        }
+    }
+    // `$0234 (main+$4)`, or just `$0234`.
+    pub fn show(&self, addr: u16) -> String {
+        match self.lookup(addr) {
+            Some(x) => format!("${:04X} ({})", addr, x),
+            None => format!("${:04X}", addr),
+        }
+    }
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        self.labels.iter().find(|x| x.1 == name).map(|x| x.0)
+    }
+    // Each label, running up to the next one.
+    pub fn ranges(&self) -> Vec<ProfileRange> {
+        self.labels.iter().enumerate().map(|(n, (start, name))| {
+            let end = self.labels.get(n + 1).map(|x| x.0 - 1)
+                .unwrap_or(0xFFFF);
+            ProfileRange { name: name.clone(), start: *start, end,
+                           executed: 0, cycles: 0 }
+        }).collect()
+    }
+    // Fills in the `_symbol` fields of disassembled instructions.
+    pub fn annotate(&self, instructions: &mut [Instruction]) {
+        for x in instructions.iter_mut() {
+            x.symbol = x.pc.and_then(|x| self.lookup(x));
+            x.effective_symbol = x.effective.and_then(|x| self.lookup(x));
+        }
+    }
+    // ...and those of a response.
+    pub fn annotate_result(&self, result: &mut JobResult) {
+        result.last_pc_symbol = result.last_pc.and_then(|x| self.lookup(x));
+        if let Some(disasm) = result.disasm.as_mut() { self.annotate(disasm) }
+    }
+}
+
+// In a job, `symbols` holds the text of a symbol file, as binary data.
+impl<'de> serde::Deserialize<'de> for Symbols {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D)
+                                                -> Result<Symbols, D::Error> {
+        let blob: Blob = serde::Deserialize::deserialize(deserializer)?;
+        Ok(Symbols::parse(&String::from_utf8_lossy(&blob.data)))
+    }
+}
+
+// As a VICE label file.
+impl fmt::Display for Symbols {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (addr, name) in self.labels.iter() {
+            writeln!(f, "al {:06X} .{}", addr, name)?;
+        }
+        Ok(())
+    }
+}
+
+impl serde::Serialize for Symbols {
+    fn serialize<S: serde::Serializer>(&self, serializer: S)
+                                       -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("utf8:{}", self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WLA: &str = "\
+; this file was created with wlalink
+[labels]
+00:0200 main
+00:0200 _local
+00:0210 loop
+[definitions]
+00001234 SOME_CONSTANT
+";
+
+    const VICE: &str = "\
+al C:0200 .main
+al C:0210 .loop
+al C:0210 .@inner
+al 003000 .data
+";
+
+    const DBG: &str = "\
+version\tmajor=2,minor=0
+sym\tid=0,name=\"main\",addrsize=absolute,scope=0,def=1,ref=2,val=0x200,\
+type=lab
+sym\tid=1,name=\"WIDTH\",addrsize=zeropage,scope=0,def=3,val=0x28,type=equ
+sym\tid=2,name=\"loop\",addrsize=absolute,scope=0,def=4,val=0x210,type=lab
+";
+
+    fn labels(symbols: &Symbols) -> Vec<(u16, &str)> {
+        symbols.labels.iter().map(|(a, n)| (*a, n.as_str())).collect()
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(labels(&Symbols::parse(WLA)),
+                   [(0x0200, "main"), (0x0210, "loop")]);
+        assert_eq!(labels(&Symbols::parse(VICE)),
+                   [(0x0200, "main"), (0x0210, "loop"), (0x3000, "data")]);
+        assert_eq!(labels(&Symbols::parse(DBG)),
+                   [(0x0200, "main"), (0x0210, "loop")]);
+        assert_eq!(labels(&Symbols::parse("nothing to see here\n")), []);
+    }
+
+    #[test]
+    fn local_labels_lose() {
+        let symbols = Symbols::parse("[labels]\n00:0200 _here\n00:0200 \
+                                      there\n00:0300 _alone\n");
+        assert_eq!(labels(&symbols), [(0x0200, "there"), (0x0300, "_alone")]);
+    }
+
+    #[test]
+    fn lookup() {
+        let symbols = Symbols::parse(VICE);
+        assert_eq!(symbols.lookup(0x0200).as_deref(), Some("main"));
+        assert_eq!(symbols.lookup(0x020F).as_deref(), Some("main+$F"));
+        assert_eq!(symbols.lookup(0x01FF), None);
+        assert_eq!(symbols.lookup(0x3000 + MAX_OFFSET).as_deref(),
+                   Some("data+$1000"));
+        assert_eq!(symbols.lookup(0x3001 + MAX_OFFSET), None);
+        assert_eq!(symbols.show(0x0212), "$0212 (loop+$2)");
+        assert_eq!(symbols.show(0x0100), "$0100");
+        assert_eq!(symbols.address_of("loop"), Some(0x0210));
+    }
+
+    #[test]
+    fn round_trip() {
+        let symbols = Symbols::parse(WLA);
+        assert_eq!(Symbols::parse(&symbols.to_string()), symbols);
+    }
+}
@@ -0,0 +1,166 @@
+// A toy CPU for the tests: just enough of a W65C02S (the reset and interrupt
+// sequences, NOP, INC A, LDA #, STA abs, JMP abs, CLI and RTI, each with the
+// same bus cycles as the real thing) to run jobs through the harness.
+// Anything else is a one-cycle NOP, like the 65C02's undefined opcodes.
+
+use super::{get_job_for, Job, Profile};
+use super::harness::{Cpu, CpuState, Pins, Registers, RunState, System};
+
+const P_I: u8 = 0x04;
+const P_D: u8 = 0x08;
+const P_V: u8 = 0x40;
+
+#[derive(Debug, Clone)]
+pub struct Toy {
+    pub pc: u16,
+    pub a: u8,
+    pub s: u8,
+    pub p: u8,
+    reset: bool,
+    irq: bool,
+    nmi: bool,
+    nmi_edge: bool,
+}
+
+// $0200: LDA #$05; STA $0010; INC A; JMP $0200
+pub const LOOP: &[u8] = &[0xA9, 0x05, 0x8D, 0x10, 0x00, 0x1A, 0x4C, 0x00,
+                          0x02];
+
+// A job (with the local profile's limits) that runs `program` from $0200.
+// `extra` is more of the job's JSON, starting with a comma.
+pub fn job(program: &[u8], extra: &str) -> Job {
+    let json = format!(r#"{{"init": [{{"base": 512, "data": "base64:{}"}}]
+                           {}}}"#, base64::encode(program), extra);
+    get_job_for(json.as_bytes(), Profile::Local).unwrap()
+}
+
+impl Toy {
+    pub fn new() -> Toy {
+        Toy { pc: 0, a: 0, s: 0xFF, p: 0x34, reset: true,
+              irq: false, nmi: false, nmi_edge: false }
+    }
+    fn operand(&mut self, system: &mut System) -> u8 {
+        let pc = self.pc;
+        self.pc = pc.wrapping_add(1);
+        system.read(self, pc)
+    }
+    fn push(&mut self, system: &mut System, value: u8) {
+        let s = self.s;
+        self.s = s.wrapping_sub(1);
+        system.write(self, 0x0100 | s as u16, value);
+    }
+    fn pull(&mut self, system: &mut System) -> u8 {
+        self.s = self.s.wrapping_add(1);
+        let s = self.s;
+        system.read(self, 0x0100 | s as u16)
+    }
+    fn vector(&mut self, system: &mut System, addr: u16) {
+        let lo = system.read_vector(self, addr);
+        let hi = system.read_vector(self, addr + 1);
+        self.pc = (hi as u16) << 8 | lo as u16;
+        self.p = (self.p | P_I) & !P_D;
+    }
+    fn interrupt(&mut self, system: &mut System, addr: u16) {
+        let pc = self.pc;
+        system.read_opcode(self, pc);
+        system.read(self, pc);
+        self.push(system, (pc >> 8) as u8);
+        self.push(system, pc as u8);
+        let p = self.p & !0x10;
+        self.push(system, p);
+        self.vector(system, addr);
+    }
+}
+
+impl Pins for Toy {
+    fn set_irq(&mut self, state: bool) { self.irq = state }
+    fn set_nmi(&mut self, state: bool) {
+        if state && !self.nmi { self.nmi_edge = true }
+        self.nmi = state;
+    }
+    fn set_overflow(&mut self) { self.p |= P_V }
+    fn reset(&mut self) { self.reset = true }
+    fn get_s(&self) -> u8 { self.s }
+}
+
+impl Cpu for Toy {
+    fn step(&mut self, system: &mut System) {
+        if self.reset {
+            self.reset = false;
+            let pc = self.pc;
+            system.read(self, pc);
+            system.read(self, pc);
+            // the pushes of an interrupt, as reads
+            for _ in 0 .. 3 {
+                let s = self.s;
+                self.s = s.wrapping_sub(1);
+                system.read(self, 0x0100 | s as u16);
+            }
+            return self.vector(system, 0xFFFC)
+        }
+        if self.nmi_edge {
+            self.nmi_edge = false;
+            return self.interrupt(system, 0xFFFA)
+        }
+        if self.irq && self.p & P_I == 0 {
+            return self.interrupt(system, 0xFFFE)
+        }
+        let pc = self.pc;
+        self.pc = pc.wrapping_add(1);
+        match system.read_opcode(self, pc) {
+            0xEA => { system.read(self, pc.wrapping_add(1)); },
+            0x1A => {
+                system.read(self, pc.wrapping_add(1));
+                self.a = self.a.wrapping_add(1);
+            },
+            0xA9 => self.a = self.operand(system),
+            0x8D => {
+                let lo = self.operand(system);
+                let hi = self.operand(system);
+                let a = self.a;
+                system.write(self, (hi as u16) << 8 | lo as u16, a);
+            },
+            0x4C => {
+                let lo = self.operand(system);
+                let hi = system.read(self, pc.wrapping_add(2));
+                self.pc = (hi as u16) << 8 | lo as u16;
+            },
+            0x58 => {
+                system.read(self, pc.wrapping_add(1));
+                self.p &= !P_I;
+            },
+            0x40 => {
+                system.read(self, pc.wrapping_add(1));
+                let s = self.s;
+                system.read(self, 0x0100 | s as u16);
+                self.p = self.pull(system) | 0x30;
+                let lo = self.pull(system);
+                let hi = self.pull(system);
+                self.pc = (hi as u16) << 8 | lo as u16;
+            },
+            _ => (),
+        }
+    }
+    fn registers(&self) -> Option<Registers> {
+        Some(Registers { cycle: 0, pc: self.pc, a: self.a, x: 0, y: 0,
+                         s: self.s, p: self.p })
+    }
+    fn set_registers(&mut self, registers: &Registers) -> bool {
+        self.pc = registers.pc;
+        self.a = registers.a;
+        self.s = registers.s;
+        self.p = registers.p;
+        true
+    }
+    fn save(&self) -> Option<CpuState> {
+        Some(CpuState {
+            registers: self.registers()?,
+            state: if self.reset { RunState::Reset } else { RunState::Running },
+        })
+    }
+    fn restore(&mut self, state: &CpuState) -> bool {
+        *self = Toy::new();
+        self.reset = state.state == RunState::Reset;
+        self.set_registers(&state.registers)
+    }
+}
@@ -0,0 +1,501 @@
+// Reading cycle strings (see "Cycle strings" in API.md) back into bus cycles,
+// and carving a trace up into instructions.
+
+use std::io;
+use std::ops::Range;
+use super::disasm;
+use super::symbols::Symbols;
+
+// Cycle reports start with the reset vector pull, which is cycle 5.
+pub const FIRST_REPORTED_CYCLE: usize = 5;
+
+pub const LOCKED_WRITE: u8 = 0x2;
+pub const LOCKED_READ: u8 = 0x3;
+pub const VECTOR_READ: u8 = 0x5;
+pub const NORMAL_WRITE: u8 = 0x6;
+pub const NORMAL_READ: u8 = 0x7;
+pub const OPCODE_READ: u8 = 0xF;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+// A cycle as report packet 0x01 carries it: the type in bits 24-27, then the
+// address, then the data.
+pub fn pack(typ: u8, addr: u16, data: u8) -> u32 {
+    (typ as u32) << 24 | (addr as u32) << 8 | data as u32
+}
+
+// Appends the cycle string of a packed cycle.
+pub fn push_cycle_string(out: &mut Vec<u8>, cycle: u32) {
+    for shift in (0 .. 7).rev() {
+        out.push(HEX_DIGITS[(cycle >> (shift * 4)) as usize & 15]);
+    }
+}
+
+pub fn cycle_string(cycle: u32) -> String {
+    let mut ret = Vec::with_capacity(7);
+    push_cycle_string(&mut ret, cycle);
+    String::from_utf8(ret).expect("hex digits are ASCII")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cycle {
+    pub typ: u8,
+    pub addr: u16,
+    pub data: u8,
+}
+
+impl Cycle {
+    // None if the string is garbled.
+    pub fn parse(cycle: &str) -> Option<Cycle> {
+        if cycle.len() != 7 { return None }
+        let x = u32::from_str_radix(cycle, 16).ok()?;
+        Some(Cycle { typ: (x >> 24) as u8, addr: (x >> 8) as u16,
+                     data: x as u8 })
+    }
+    pub fn is_write(&self) -> bool {
+        self.typ == LOCKED_WRITE || self.typ == NORMAL_WRITE
+    }
+    pub fn describe_type(&self) -> &'static str {
+        match self.typ {
+            LOCKED_WRITE => "locked write",
+            LOCKED_READ => "locked read",
+            VECTOR_READ => "vector fetch",
+            NORMAL_WRITE => "write",
+            NORMAL_READ => "read",
+            OPCODE_READ => "opcode fetch",
+            _ => "(impossible)",
+        }
+    }
+}
+
+fn is_opcode_fetch(cycle: &str) -> bool {
+    Cycle::parse(cycle).map(|x| x.typ == OPCODE_READ).unwrap_or(false)
+}
+
+// Splits a trace into instructions, each starting with its opcode fetch.
+// Whatever comes before the first opcode fetch (usually the tail end of the
+// reset sequence) is an "instruction" of its own.
+pub fn split_instructions(cycles: &[String]) -> Vec<Range<usize>> {
+    let mut ret = Vec::new();
+    let mut start = 0;
+    for n in 1 .. cycles.len() {
+        if is_opcode_fetch(&cycles[n]) {
+            ret.push(start .. n);
+            start = n;
+        }
+    }
+    if start < cycles.len() { ret.push(start .. cycles.len()) }
+    ret
+}
+
+// Reads that make up the stack side of these instructions.
+const STACK_MNEMONICS: &[&str] = &["BRK", "JSR", "RTI", "RTS", "PHA", "PHP",
+                                   "PHX", "PHY", "PLA", "PLP", "PLX", "PLY"];
+
+// One instruction of a trace, decoded: what it was, where it went, and what
+// each of its bus cycles was for. Each cycle's label is one of:
+//
+// - `opcode`: the opcode fetch
+// - `operand`: a fetch of one of the instruction's operand bytes
+// - `pointer`: a read of the address an indirect mode goes through
+// - `data`: the access the instruction exists to make
+// - `stack`, `vector`: pushes, pulls, and vector fetches
+// - `dummy`: a read of an address the instruction has already read (or of
+//   the byte after it), whose data goes unused
+// - `spurious`: any other read whose data goes unused, including the extra
+//   read of read-modify-write instructions
+// - `ignored opcode`: an opcode fetch cut short by an interrupt
+// - `garbled`: a cycle string that didn't parse
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Instruction {
+    // the number of its first cycle
+    pub cycle: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pc: Option<u16>,
+    pub text: String,
+    // the address the instruction accessed, or jumped or branched to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effective: Option<u16>,
+    pub labels: Vec<String>,
+    // `pc` and `effective` as label+offset, if the job had symbols
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symbol: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub effective_symbol: Option<String>,
+}
+
+impl Instruction {
+    // The cycles this covers, as indices into the trace.
+    pub fn range(&self) -> Range<usize> {
+        let start = self.cycle - FIRST_REPORTED_CYCLE;
+        start .. start + self.labels.len()
+    }
+}
+
+fn word(lo: Option<&Cycle>, hi: Option<&Cycle>) -> Option<u16> {
+    Some((hi?.data as u16) << 8 | lo?.data as u16)
+}
+
+// Labels the stack and vector cycles of something that isn't a normal
+// instruction (an interrupt or the reset sequence), and returns where its
+// vector pointed.
+fn label_interrupt(parsed: &[Option<Cycle>], labels: &mut [&'static str])
+                   -> Option<u16> {
+    let mut vector = Vec::new();
+    for (n, cycle) in parsed.iter().enumerate() {
+        let cycle = match cycle { Some(x) => x, None => continue };
+        if !labels[n].is_empty() { continue }
+        labels[n] = if cycle.typ == VECTOR_READ {
+            vector.push(cycle);
+            "vector"
+        }
+        else if cycle.addr & 0xFF00 == 0x0100 { "stack" }
+        else { "dummy" };
+    }
+    word(vector.get(0).cloned(), vector.get(1).cloned())
+}
+
+// The interrupt, if any, that these cycles are handling.
+fn interrupt_name(parsed: &[Option<Cycle>]) -> Option<&'static str> {
+    parsed.iter().filter_map(|x| x.as_ref())
+        .find(|x| x.typ == VECTOR_READ).map(|x| match x.addr & 0xFFFE {
+            0xFFFA => "(NMI)",
+            0xFFFC => "(reset)",
+            _ => "(IRQ)",
+        })
+}
+
+fn decode_instruction(cycles: &[String], range: Range<usize>) -> Instruction {
+    let parsed: Vec<Option<Cycle>>
+        = cycles[range.clone()].iter().map(|x| Cycle::parse(x)).collect();
+    let mut labels: Vec<&'static str> = parsed.iter()
+        .map(|x| if x.is_some() { "" } else { "garbled" }).collect();
+    let mut ret = Instruction {
+        cycle: range.start + FIRST_REPORTED_CYCLE, pc: None,
+        text: String::new(), effective: None, labels: Vec::new(),
+        symbol: None, effective_symbol: None,
+    };
+    let fetch = match parsed[0] {
+        Some(x) if x.typ == OPCODE_READ => x,
+        _ => {
+            // the tail end of the reset sequence, or the start of a trace
+            // that began mid-instruction
+            ret.text = interrupt_name(&parsed).unwrap_or("(unknown)")
+                .to_owned();
+            ret.effective = label_interrupt(&parsed, &mut labels);
+            ret.labels = labels.iter().map(|x| x.to_string()).collect();
+            return ret
+        },
+    };
+    ret.pc = Some(fetch.addr);
+    let opcode = fetch.data;
+    // an interrupt steals the fetch of the next instruction
+    if opcode != super::disasm::BRK {
+        if let Some(name) = interrupt_name(&parsed) {
+            labels[0] = "ignored opcode";
+            ret.text = name.to_owned();
+            ret.effective = label_interrupt(&parsed, &mut labels);
+            ret.labels = labels.iter().map(|x| x.to_string()).collect();
+            return ret
+        }
+    }
+    labels[0] = "opcode";
+    let mnemonic = disasm::mnemonic(opcode);
+    let mode = disasm::mode(opcode);
+    let len = mode.operand_len();
+    // bytes of the instruction itself, and the byte after it
+    let own = |addr: u16| addr.wrapping_sub(fetch.addr) as usize <= len + 1;
+    let mut operand = Vec::with_capacity(len);
+    for (n, cycle) in parsed.iter().enumerate().skip(1) {
+        if operand.len() >= len { break }
+        match cycle {
+            Some(cycle) if !cycle.is_write() && cycle.addr
+                == fetch.addr.wrapping_add(1 + operand.len() as u16) => {
+                operand.push(cycle.data);
+                labels[n] = "operand";
+            },
+            _ => (),
+        }
+    }
+    ret.text = disasm::disassemble(fetch.addr, opcode, &operand);
+    let operand_word = if operand.len() == 2 {
+        Some((operand[1] as u16) << 8 | operand[0] as u16)
+    } else { None };
+    use super::disasm::Mode::*;
+    if STACK_MNEMONICS.contains(&mnemonic) {
+        ret.effective = label_interrupt(&parsed, &mut labels);
+        if mnemonic == "JSR" { ret.effective = operand_word }
+    }
+    else {
+        match mode {
+            Implied | Accumulator | Immediate => (),
+            Relative => {
+                ret.effective = operand.get(0).map(|&x| {
+                    fetch.addr.wrapping_add(2)
+                        .wrapping_add(x as i8 as u16)
+                });
+            },
+            Absolute if mnemonic == "JMP" => ret.effective = operand_word,
+            _ => {
+                // The pointer of an indirect mode is the first two
+                // consecutive reads that aren't of the instruction itself.
+                let indirect = match mode {
+                    ZeroPageIndirect | ZeroPageXIndirect | ZeroPageIndirectY
+                        | AbsoluteIndirect | AbsoluteXIndirect => true,
+                    _ => false,
+                };
+                let mut pointer = None;
+                if indirect {
+                    let wrap = if mode == AbsoluteIndirect
+                        || mode == AbsoluteXIndirect { 0xFFFF } else { 0xFF };
+                    for n in 1 .. parsed.len().saturating_sub(1) {
+                        if let (Some(lo), Some(hi)) = (parsed[n], parsed[n+1]) {
+                            if labels[n].is_empty() && labels[n+1].is_empty()
+                            && !lo.is_write() && !hi.is_write()
+                            && !own(lo.addr) && lo.addr <= wrap
+                            && hi.addr == lo.addr.wrapping_add(1) & wrap {
+                                labels[n] = "pointer";
+                                labels[n+1] = "pointer";
+                                pointer = Some((lo, hi));
+                                break
+                            }
+                        }
+                    }
+                }
+                if mnemonic == "JMP" {
+                    ret.effective = pointer.and_then(|(lo, hi)| {
+                        word(Some(&lo), Some(&hi))
+                    });
+                }
+                else {
+                    ret.effective = parsed.iter().enumerate().skip(1)
+                        .filter(|(n, _)| labels[*n].is_empty())
+                        .filter_map(|(_, x)| x.as_ref())
+                        .find(|x| !own(x.addr)).map(|x| x.addr);
+                }
+                let mut data_read = false;
+                for (n, cycle) in parsed.iter().enumerate() {
+                    let cycle = match cycle { Some(x) => x, None => continue };
+                    if labels[n].is_empty()
+                    && Some(cycle.addr) == ret.effective
+                    && mnemonic != "JMP" {
+                        if cycle.is_write() { labels[n] = "data" }
+                        else if !data_read {
+                            labels[n] = "data";
+                            data_read = true;
+                        }
+                        else { labels[n] = "spurious" }
+                    }
+                }
+            },
+        }
+    }
+    let pointer_addrs: Vec<u16> = parsed.iter().zip(labels.iter())
+        .filter(|(_, &l)| l == "pointer")
+        .filter_map(|(x, _)| x.map(|x| x.addr)).collect();
+    for (n, cycle) in parsed.iter().enumerate() {
+        if let Some(cycle) = cycle {
+            if labels[n].is_empty() {
+                labels[n] = if own(cycle.addr)
+                    || pointer_addrs.contains(&cycle.addr) { "dummy" }
+                    else { "spurious" };
+            }
+        }
+    }
+    ret.labels = labels.iter().map(|x| x.to_string()).collect();
+    ret
+}
+
+// Decodes a whole trace, an instruction at a time.
+pub fn decode(cycles: &[String]) -> Vec<Instruction> {
+    split_instructions(cycles).into_iter()
+        .map(|range| decode_instruction(cycles, range)).collect()
+}
+
+// Writes a trace out as a listing: each instruction, disassembled, followed
+// by its bus cycles and what each was for.
+pub fn write_listing(out: &mut dyn io::Write, cycles: &[String])
+                     -> io::Result<()> {
+    write_listing_from(out, cycles, FIRST_REPORTED_CYCLE, None)
+}
+
+// As `write_listing`, for a piece of a trace that starts at cycle `first`,
+// and with addresses labeled from `symbols`, if given.
+pub fn write_listing_from(out: &mut dyn io::Write, cycles: &[String],
+                          first: usize, symbols: Option<&Symbols>)
+                          -> io::Result<()> {
+    let show = |addr: u16| match symbols {
+        Some(symbols) => symbols.show(addr),
+        None => format!("${:04X}", addr),
+    };
+    for instruction in decode(cycles) {
+        let pc = instruction.pc.map(show).unwrap_or_default();
+        match instruction.effective {
+            Some(x) => writeln!(out, "{:<5}  {:<16}  -> {}", pc,
+                                instruction.text, show(x))?,
+            None => writeln!(out, "{:<5}  {}", pc, instruction.text)?,
+        }
+        for (n, label) in instruction.range().zip(instruction.labels.iter()) {
+            match Cycle::parse(&cycles[n]) {
+                Some(x) => writeln!(out, "{:>7}  ${:04X}  ${:02X}  {:<12}  {}",
+                                    n + first, x.addr, x.data,
+                                    x.describe_type(), label)?,
+                None => writeln!(out, "{:>7}  {:?}  {}",
+                                 n + first, cycles[n], label)?,
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(cycles: &[&str]) -> Vec<String> {
+        cycles.iter().map(|x| x.to_string()).collect()
+    }
+
+    // Decodes cycles that make up exactly one instruction.
+    fn one(cycles: &[&str]) -> Instruction {
+        let mut ret = decode(&trace(cycles));
+        assert_eq!(ret.len(), 1, "{:?}", ret);
+        ret.remove(0)
+    }
+
+    fn check(cycles: &[&str], text: &str, effective: Option<u16>,
+             labels: &[&str]) {
+        let x = one(cycles);
+        assert_eq!(x.text, text);
+        assert_eq!(x.effective, effective, "{}", text);
+        assert_eq!(x.labels, labels, "{}", text);
+    }
+
+    #[test]
+    fn api_example() {
+        let x = one(&["F025CEE", "7025D48", "7025E12", "3124805", "3124805",
+                      "2124806"]);
+        assert_eq!(x.cycle, FIRST_REPORTED_CYCLE);
+        assert_eq!(x.pc, Some(0x025C));
+        assert_eq!(x.text, "INC $1248");
+        assert_eq!(x.effective, Some(0x1248));
+        assert_eq!(x.labels, ["opcode", "operand", "operand", "data",
+                              "spurious", "data"]);
+    }
+
+    #[test]
+    fn simple_modes() {
+        check(&["F0200EA", "70201EA"], "NOP", None, &["opcode", "dummy"]);
+        check(&["F02001A", "702011A"], "INC A", None, &["opcode", "dummy"]);
+        check(&["F0200A9", "7020112"], "LDA #$12", None,
+              &["opcode", "operand"]);
+        check(&["F0200A5", "7020110", "7001042"], "LDA $10", Some(0x10),
+              &["opcode", "operand", "data"]);
+        check(&["F020085", "7020110", "6001042"], "STA $10", Some(0x10),
+              &["opcode", "operand", "data"]);
+        check(&["F0200B5", "7020110", "7020110", "7001542"], "LDA $10,X",
+              Some(0x15), &["opcode", "operand", "dummy", "data"]);
+        check(&["F0200B6", "7020110", "7020110", "7001542"], "LDX $10,Y",
+              Some(0x15), &["opcode", "operand", "dummy", "data"]);
+        check(&["F0200AD", "7020134", "7020212", "7123442"], "LDA $1234",
+              Some(0x1234), &["opcode", "operand", "operand", "data"]);
+        check(&["F0200B9", "7020134", "7020212", "7123542"], "LDA $1234,Y",
+              Some(0x1235), &["opcode", "operand", "operand", "data"]);
+    }
+
+    #[test]
+    fn page_crossing_indexed_read() {
+        check(&["F0200BD", "70201F0", "7020212", "7020212", "7131042"],
+              "LDA $12F0,X", Some(0x1310),
+              &["opcode", "operand", "operand", "dummy", "data"]);
+    }
+
+    #[test]
+    fn indirect_modes() {
+        check(&["F0200B2", "7020110", "7001000", "7001113", "7130042"],
+              "LDA ($10)", Some(0x1300),
+              &["opcode", "operand", "pointer", "pointer", "data"]);
+        check(&["F0200A1", "7020110", "7020110", "7001200", "7001313",
+                "7130042"],
+              "LDA ($10,X)", Some(0x1300),
+              &["opcode", "operand", "dummy", "pointer", "pointer", "data"]);
+        check(&["F0200B1", "7020110", "7001000", "7001113", "7130142"],
+              "LDA ($10),Y", Some(0x1301),
+              &["opcode", "operand", "pointer", "pointer", "data"]);
+        // the pointer wraps around the zero page
+        check(&["F0200B2", "70201FF", "700FF00", "7000013", "7130042"],
+              "LDA ($FF)", Some(0x1300),
+              &["opcode", "operand", "pointer", "pointer", "data"]);
+    }
+
+    #[test]
+    fn jumps() {
+        check(&["F02004C", "7020100", "7020204"], "JMP $0400", Some(0x0400),
+              &["opcode", "operand", "operand"]);
+        check(&["F02006C", "7020100", "7020230", "7020230", "7300000",
+                "7300104"],
+              "JMP ($3000)", Some(0x0400),
+              &["opcode", "operand", "operand", "dummy", "pointer",
+                "pointer"]);
+        check(&["F02007C", "7020100", "7020230", "7020230", "7300200",
+                "7300304"],
+              "JMP ($3000,X)", Some(0x0400),
+              &["opcode", "operand", "operand", "dummy", "pointer",
+                "pointer"]);
+        check(&["F020020", "7020100", "701FF00", "601FF02", "601FE02",
+                "7020230"],
+              "JSR $3000", Some(0x3000),
+              &["opcode", "operand", "stack", "stack", "stack", "operand"]);
+        check(&["F020048", "7020148", "601FF42"], "PHA", None,
+              &["opcode", "dummy", "stack"]);
+    }
+
+    #[test]
+    fn branches() {
+        // taken
+        check(&["F0200D0", "7020105", "7020202"], "BNE $0207", Some(0x0207),
+              &["opcode", "operand", "dummy"]);
+        // not taken
+        check(&["F0200D0", "7020105"], "BNE $0207", Some(0x0207),
+              &["opcode", "operand"]);
+    }
+
+    #[test]
+    fn interrupts() {
+        let labels = ["ignored opcode", "dummy", "stack", "stack", "stack",
+                      "vector", "vector"];
+        let x = one(&["F0200EA", "70200EA", "601FF02", "601FE00", "601FD20",
+                      "5FFFE00", "5FFFF03"]);
+        assert_eq!((x.pc, x.text.as_str(), x.effective),
+                   (Some(0x0200), "(IRQ)", Some(0x0300)));
+        assert_eq!(x.labels, labels);
+        let x = one(&["F0200EA", "70200EA", "601FF02", "601FE00", "601FD20",
+                      "5FFFA00", "5FFFB04"]);
+        assert_eq!((x.pc, x.text.as_str(), x.effective),
+                   (Some(0x0200), "(NMI)", Some(0x0400)));
+        assert_eq!(x.labels, labels);
+        // BRK uses the IRQ vector, but is an instruction of its own
+        let x = one(&["F020000", "7020100", "601FF02", "601FE02", "601FD30",
+                      "5FFFE00", "5FFFF03"]);
+        assert_eq!((x.text.as_str(), x.effective), ("BRK #$00", Some(0x0300)));
+        assert_eq!(x.labels, ["opcode", "operand", "stack", "stack", "stack",
+                              "vector", "vector"]);
+    }
+
+    #[test]
+    fn trace_edges() {
+        // the reset sequence's vector pull comes first
+        let all = decode(&trace(&["5FFFC00", "5FFFD02", "F0200EA",
+                                  "70201EA"]));
+        assert_eq!(all.len(), 2);
+        assert_eq!((all[0].pc, all[0].text.as_str(), all[0].effective),
+                   (None, "(reset)", Some(0x0200)));
+        assert_eq!(all[0].labels, ["vector", "vector"]);
+        assert_eq!(all[1].cycle, FIRST_REPORTED_CYCLE + 2);
+        assert_eq!(all[1].range(), 2 .. 4);
+        // cut short by the cycle limit
+        check(&["F0200AD", "7020110"], "LDA $??10", None,
+              &["opcode", "operand"]);
+        check(&["F0200A5", "what"], "LDA $??", None, &["opcode", "garbled"]);
+    }
+}
@@ -0,0 +1,175 @@
+// Writes a job's trace out as a VCD (Value Change Dump), for GTKWave and the
+// like. Besides the bus, this carries the edges the job scheduled on the
+// input pins. Timing is nominal (one cycle per microsecond); only the order
+// of events means anything.
+
+use std::io;
+use std::io::Write;
+use super::{Job, JobResult};
+use super::trace::{Cycle, FIRST_REPORTED_CYCLE};
+
+// in nanoseconds
+const CYCLE_LENGTH: u64 = 1000;
+
+// (identifier, width, name)
+const SIGNALS: &[(&str, u32, &str)] = &[
+    ("!", 1, "PHI2"),
+    ("\"", 16, "A"),
+    ("#", 8, "D"),
+    ("$", 1, "RWB"),
+    ("%", 1, "VPB"),
+    ("&", 1, "MLB"),
+    ("'", 1, "SYNC"),
+    ("(", 1, "NMIB"),
+    (")", 1, "IRQB"),
+    ("*", 1, "RDY"),
+    ("+", 1, "SOB"),
+    (",", 1, "RESB"),
+];
+const PHI2: usize = 0;
+const A: usize = 1;
+const D: usize = 2;
+const RWB: usize = 3;
+const NMIB: usize = 7;
+
+struct Dumper<'a> {
+    out: &'a mut dyn Write,
+    values: Vec<String>,
+    pending: Vec<String>,
+}
+
+impl<'a> Dumper<'a> {
+    fn set(&mut self, signal: usize, value: Option<u32>) {
+        let (id, width, _) = SIGNALS[signal];
+        let value = match (value, width) {
+            (Some(x), 1) => format!("{}", x & 1),
+            (None, 1) => "x".to_owned(),
+            (Some(x), _) => format!("b{:0width$b} ", x, width = width as usize),
+            (None, _) => "bx ".to_owned(),
+        };
+        if self.values[signal] != value {
+            self.pending.push(format!("{}{}", value, id));
+            self.values[signal] = value;
+        }
+    }
+    fn flush(&mut self, time: u64) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            writeln!(self.out, "#{}", time)?;
+            for change in self.pending.drain(..) {
+                writeln!(self.out, "{}", change)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Cycle numbers at which each input pin changes, and what it changes to.
+// Each list's first toggle asserts the (active low) pin.
+fn edges(job: &Job) -> Vec<(u32, usize, u32)> {
+    let mut ret = Vec::new();
+    let lists = [&job.nmi, &job.irq, &job.rdy, &job.so, &job.res];
+    for (n, list) in lists.iter().enumerate() {
+        if let Some(list) = list {
+            for (i, &cycle) in list.iter().enumerate() {
+                ret.push((cycle, NMIB + n, i as u32 & 1));
+            }
+        }
+    }
+    ret.sort_unstable();
+    ret
+}
+
+pub fn write_vcd(out: &mut dyn Write, job: &Job, result: &JobResult)
+                 -> io::Result<()> {
+    writeln!(out, "$version 65test $end")?;
+    writeln!(out, "$comment one cycle per microsecond, nominally; cycle N \
+                   starts at N us $end")?;
+    writeln!(out, "$timescale 1ns $end")?;
+    writeln!(out, "$scope module w65c02s $end")?;
+    for &(id, width, name) in SIGNALS.iter() {
+        if width == 1 {
+            writeln!(out, "$var wire 1 {} {} $end", id, name)?;
+        }
+        else {
+            writeln!(out, "$var wire {} {} {} [{}:0] $end", width, id, name,
+                     width - 1)?;
+        }
+    }
+    writeln!(out, "$upscope $end")?;
+    writeln!(out, "$enddefinitions $end")?;
+    let edges = edges(job);
+    // Go as far as the trace, or the last scheduled edge, whichever is
+    // later, but no further than the job went.
+    let end = (result.cycles.len() + FIRST_REPORTED_CYCLE)
+        .max(edges.last().map(|x| x.0 as usize + 1).unwrap_or(0))
+        .min(result.num_cycles as usize);
+    let mut dumper = Dumper {
+        out, values: vec![String::new(); SIGNALS.len()], pending: Vec::new(),
+    };
+    for n in NMIB .. SIGNALS.len() { dumper.set(n, Some(1)) }
+    let mut edges = edges.into_iter().peekable();
+    for n in 0 .. end {
+        let time = n as u64 * CYCLE_LENGTH;
+        while let Some(&(cycle, signal, value)) = edges.peek() {
+            if cycle as usize > n { break }
+            dumper.set(signal, Some(value));
+            edges.next();
+        }
+        let cycle = n.checked_sub(FIRST_REPORTED_CYCLE)
+            .and_then(|x| result.cycles.get(x))
+            .and_then(|x| Cycle::parse(x));
+        dumper.set(PHI2, Some(0));
+        dumper.set(A, cycle.map(|x| x.addr as u32));
+        dumper.set(D, None);
+        for bit in 0 .. 4 {
+            dumper.set(RWB + bit, cycle.map(|x| (x.typ >> bit) as u32));
+        }
+        dumper.flush(time)?;
+        dumper.set(PHI2, Some(1));
+        dumper.set(D, cycle.map(|x| x.data as u32));
+        dumper.flush(time + CYCLE_LENGTH / 2)?;
+    }
+    dumper.set(PHI2, Some(0));
+    dumper.flush(end as u64 * CYCLE_LENGTH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{get_job_for, Profile};
+
+    // (time, value) of each change to the signal with identifier `id`
+    fn changes(vcd: &str, id: &str) -> Vec<(u64, String)> {
+        let mut time = 0;
+        let mut ret = Vec::new();
+        for line in vcd.lines() {
+            if line.starts_with('#') {
+                time = line[1..].parse().unwrap();
+            }
+            else if line.len() == 1 + id.len() && line.ends_with(id)
+                && !line.starts_with('$') {
+                ret.push((time, line[..1].to_owned()));
+            }
+        }
+        ret
+    }
+
+    #[test]
+    fn toggles_assert_then_release() {
+        let job = get_job_for(&br#"{"init": [{"base": 512, "data": "utf8:x"}],
+                                    "nmi": [10, 20], "irq": [12, 22]}"#[..],
+                              Profile::Local).unwrap();
+        let result: JobResult = serde_json::from_str(
+            r#"{"num_cycles": 30, "termination_cause": "limit"}"#).unwrap();
+        let mut out = Vec::new();
+        write_vcd(&mut out, &job, &result).unwrap();
+        let vcd = String::from_utf8(out).unwrap();
+        let expect = |a: u64, b: u64| vec![
+            (0, "1".to_owned()),
+            (a * CYCLE_LENGTH, "0".to_owned()),
+            (b * CYCLE_LENGTH, "1".to_owned()),
+        ];
+        assert_eq!(changes(&vcd, "("), expect(10, 20));
+        assert_eq!(changes(&vcd, ")"), expect(12, 22));
+    }
+}